@@ -0,0 +1,103 @@
+//! Bandwidth limits of containers in bridged network
+//!
+//! Ingress and egress are from the point of view of the container: ingress
+//! is shaped on the host side of the veth pair and egress is redirected to
+//! an ifb device and shaped there.
+use quire::validate::{Structure, Numeric};
+
+
+/// Burst is at least this many bytes, so that (segmentation-offloaded)
+/// packets fit into the bucket
+pub const MIN_BURST: u64 = 32768;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Bandwidth {
+    /// Rate in bits per second
+    pub ingress_rate: Option<u64>,
+    /// Burst in bytes
+    pub ingress_burst: Option<u64>,
+    pub egress_rate: Option<u64>,
+    pub egress_burst: Option<u64>,
+}
+
+/// Effective limit of a single direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limit {
+    /// Rate in bytes per second
+    pub rate: u64,
+    pub burst: u32,
+}
+
+fn min(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn limit(rate: Option<u64>, burst: Option<u64>) -> Option<Limit> {
+    rate.map(|rate| {
+        let rate = rate / 8;
+        // by default ~10ms of traffic
+        let burst = burst.unwrap_or(rate / 100).max(MIN_BURST);
+        Limit {
+            rate,
+            burst: burst.min(u32::max_value() as u64) as u32,
+        }
+    })
+}
+
+impl Bandwidth {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("ingress_rate", Numeric::new().min(8).optional())
+        .member("ingress_burst", Numeric::new().min(1).optional())
+        .member("egress_rate", Numeric::new().min(8).optional())
+        .member("egress_burst", Numeric::new().min(1).optional())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ingress_rate.is_none() && self.egress_rate.is_none()
+    }
+    /// Combines limits of the container with limits of the sandbox
+    ///
+    /// Sandbox values are used as defaults and as maximum values.
+    pub fn capped(&self, sandbox: &Bandwidth) -> Bandwidth {
+        Bandwidth {
+            ingress_rate: min(self.ingress_rate, sandbox.ingress_rate),
+            ingress_burst: min(self.ingress_burst, sandbox.ingress_burst),
+            egress_rate: min(self.egress_rate, sandbox.egress_rate),
+            egress_burst: min(self.egress_burst, sandbox.egress_burst),
+        }
+    }
+    pub fn ingress(&self) -> Option<Limit> {
+        limit(self.ingress_rate, self.ingress_burst)
+    }
+    pub fn egress(&self) -> Option<Limit> {
+        limit(self.egress_rate, self.egress_burst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quire::{parse_string, Options};
+    use super::{Bandwidth, Limit};
+
+    #[test]
+    fn capped() {
+        let sandbox: Bandwidth = parse_string("<test>",
+            "ingress-rate: 100M\negress-rate: 10M\negress-burst: 1M",
+            &Bandwidth::validator(), &Options::default()).unwrap();
+        let container: Bandwidth = parse_string("<test>",
+            "ingress-rate: 1G\negress-rate: 1M\negress-burst: 64k",
+            &Bandwidth::validator(), &Options::default()).unwrap();
+        let bw = container.capped(&sandbox);
+        assert_eq!(bw.ingress(),
+            Some(Limit { rate: 12_500_000, burst: 125_000 }));
+        assert_eq!(bw.egress(), Some(Limit { rate: 125_000, burst: 64_000 }));
+        let bw = Bandwidth::default().capped(&sandbox);
+        assert_eq!(bw.egress(),
+            Some(Limit { rate: 1_250_000, burst: 1_000_000 }));
+        assert_eq!(Bandwidth::default().ingress(), None);
+        assert!(Bandwidth::default().is_empty());
+    }
+}
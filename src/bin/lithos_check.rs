@@ -8,10 +8,11 @@ extern crate scan_dir;
 #[macro_use] extern crate log;
 
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fs::{metadata};
 use std::net::IpAddr;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
@@ -20,24 +21,36 @@ use argparse::{ArgumentParser, Parse, ParseOption, StoreTrue, Print, Collect};
 use ipnetwork::IpNetwork;
 use quire::{parse_config, Options};
 
-use lithos::utils::{in_mapping, check_mapping, relative};
+use lithos::utils::{in_mapping, check_mapping, relative, map_dir};
 use lithos::range::in_range;
 use lithos::master_config::MasterConfig;
 use lithos::sandbox_config::SandboxConfig;
 use lithos::container_config::{ContainerConfig, Variables, replace_vars};
-use lithos::container_config::{Variable::TcpPort, Activation::Systemd};
-use lithos::container_config::TcpPortSettings;
+use lithos::container_config::{Variable, Activation::Systemd};
+use lithos::container_config::{Volume, OverlayInfo, OverlayStorage};
+use lithos::container_config::{FileInfo, SecretFilesInfo};
 use lithos::child_config::{ChildConfig, ChildKind};
-use lithos::network::{get_host_name, get_host_ip};
+use lithos::network::{public_hostname, public_addresses};
 use lithos::id_map::{IdMapExt};
+use lithos::limits::Resource;
+use lithos::cpuset::CpuList;
+use lithos::scheduling;
+use lithos::devices;
+use lithos::quota::{QuotaMethod, MIN_IMAGE_SIZE};
+use lithos::mount_plan::MountPlan;
+use lithos::image_manifest;
+use lithos::resolv_conf;
+use lithos::port_forward::{self, PortForward};
 
 static EXIT_STATUS: AtomicUsize = ATOMIC_USIZE_INIT;
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
 
 macro_rules! err {
     ( $( $x:expr ),* ) => {
         {
             error!($($x),*);
             EXIT_STATUS.store(1,  Ordering::SeqCst);
+            ERRORS.fetch_add(1,  Ordering::SeqCst);
         }
     }
 }
@@ -45,7 +58,7 @@ macro_rules! err {
 
 fn check_master_config(master: &MasterConfig, verbose: bool) {
     // TODO(tailhook) maybe check host only if we need it for hosts file
-    match get_host_name() {
+    match public_hostname(master) {
         Ok(hostname) => {
             if verbose {
                 println!("Hostname is {}", hostname);
@@ -55,10 +68,12 @@ fn check_master_config(master: &MasterConfig, verbose: bool) {
             err!("Can't get hostname: {}", e);
         }
     }
-    match get_host_ip() {
-        Ok(ipaddr) => {
+    match public_addresses(master) {
+        Ok(addrs) => {
             if verbose {
-                println!("IPAddr is {}", ipaddr);
+                for ipaddr in addrs {
+                    println!("IPAddr is {}", ipaddr);
+                }
             }
         }
         Err(e) => {
@@ -79,6 +94,66 @@ fn check_sandbox_config(sandbox: &SandboxConfig) {
     if sandbox.allow_groups.len() == 0 {
         err!("No allowed groups range. Please add `allow-groups: [1-1000]`");
     }
+    if sandbox.collect_core_dumps && sandbox.core_dump_dir.is_none() {
+        err!("`collect-core-dumps` requires `core-dump-dir` to be set");
+    }
+    if let Err(e) = devices::read_devices(&sandbox.allow_devices) {
+        err!("Bad entry in `allow-devices`: {}", e);
+    }
+    if let Some(ref bridge) = sandbox.bridged_network {
+        if let Some(pool) = bridge.ip_pool {
+            if !network_contains(&bridge.network, pool.start) ||
+                !network_contains(&bridge.network, pool.end)
+            {
+                err!("`ip-pool` {} is not in {}", pool, bridge.network);
+            }
+            if let Some(gw) = bridge.default_gateway {
+                if pool.contains(gw) {
+                    err!("`ip-pool` {} contains `default-gateway`", pool);
+                }
+            }
+        }
+        if let Some(ref net) = bridge.ipv6_network {
+            if !net.is_ipv6() {
+                err!("`ipv6-network` must be an IPv6 network");
+            }
+            if let Some(gw) = bridge.ipv6_default_gateway {
+                if !network_contains(net, gw) {
+                    err!("`ipv6-default-gateway` {} is not in {}", gw, net);
+                }
+            }
+        } else if bridge.ipv6_default_gateway.is_some() {
+            err!("`ipv6-default-gateway` requires `ipv6-network`");
+        }
+    }
+    if let Some(ref policy) = sandbox.network_policy {
+        if sandbox.bridged_network.is_none() {
+            err!("`network-policy` requires `bridged-network`");
+        }
+        for rule in policy.allow.iter().chain(&policy.deny) {
+            let network = match rule.network {
+                IpNetwork::V4(net) => IpAddr::V4(net.network()),
+                IpNetwork::V6(net) => IpAddr::V6(net.network()),
+            };
+            if network != rule.network.ip() {
+                err!("Network {} in `network-policy` has host bits set, \
+                    did you mean {}/{}?",
+                    rule.network, network, rule.network.prefix());
+            }
+            for range in &rule.ports {
+                if range.start > range.end || range.end > 65535 {
+                    err!("Bad port range {}-{} in `network-policy`",
+                        range.start, range.end);
+                }
+            }
+        }
+    }
+    for path in sandbox.mask_paths.iter().chain(&sandbox.unmask_paths) {
+        if !path.starts_with("/proc") && !path.starts_with("/sys") {
+            err!("Path {:?} in `mask-paths`/`unmask-paths` must be \
+                inside /proc or /sys", path);
+        }
+    }
     // TODO(tailhook) check allow_users/allow_groups against uid_map/gid_map
 }
 
@@ -98,7 +173,17 @@ fn check_container(config_file: &Path,
     };
     validate_activation(&config);
     validate_substitutions(&config);
+    validate_rlimits(&config, sandbox);
+    validate_volumes(&config, sandbox);
     if let Some(sandbox) = sandbox {
+        if let Err(e) = scheduling::check(config.nice,
+            config.ioprio.as_ref(), sandbox)
+        {
+            err!("{}", e);
+        }
+        if let Err(e) = devices::check(&config.devices, sandbox) {
+            err!("{}", e);
+        }
         if config.uid_map.len() > 0 {
             let user_id = config.user_id.or(sandbox.default_user);
             if let Some(user_id) = user_id {
@@ -156,6 +241,134 @@ fn validate_substitutions(config: &ContainerConfig) {
     }
 }
 
+fn validate_overlay(mp: &str, opt: &OverlayInfo,
+    sandbox: Option<&SandboxConfig>)
+{
+    match (opt.storage, &opt.path) {
+        (OverlayStorage::Persistent, &None) => {
+            err!("Overlay {:?} with persistent storage requires `path`", mp);
+        }
+        (OverlayStorage::Persistent, &Some(ref path)) => {
+            if !path.is_absolute() {
+                err!("Overlay {:?}: path {:?} must be absolute", mp, path);
+            } else if let Some(sandbox) = sandbox {
+                if !sandbox.writable_paths.keys()
+                    .any(|p| path.starts_with(p))
+                {
+                    err!("Overlay {:?}: path {:?} is not in writable-paths \
+                        of the sandbox", mp, path);
+                }
+            }
+        }
+        (_, &Some(_)) => {
+            err!("Overlay {:?}: `path` is only allowed for \
+                persistent storage", mp);
+        }
+        (_, &None) => {}
+    }
+}
+
+fn validate_file(mp: &str, opt: &FileInfo, sandbox: Option<&SandboxConfig>) {
+    if !opt.path.is_absolute() {
+        err!("Volume {:?}: path {:?} must be absolute", mp, opt.path);
+        return;
+    }
+    let sandbox = match sandbox {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    let path = if opt.writable {
+        map_dir(&opt.path, &sandbox.writable_paths)
+    } else {
+        map_dir(&opt.path, &sandbox.readonly_paths)
+            .or_else(|| map_dir(&opt.path, &sandbox.writable_paths))
+    };
+    match path {
+        Some(path) => match metadata(&path) {
+            Ok(ref m) if m.is_dir() => {
+                err!("Volume {:?}: source {:?} is a directory", mp, path);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                err!("Volume {:?}: source {:?} can't be accessed: {}",
+                    mp, path, e);
+            }
+        },
+        None => {
+            err!("Volume {:?}: path {:?} is not in {} of the sandbox",
+                mp, opt.path,
+                if opt.writable { "writable-paths" }
+                else { "readonly-paths" });
+        }
+    }
+}
+
+fn validate_volumes(config: &ContainerConfig,
+    sandbox: Option<&SandboxConfig>)
+{
+    for (mp, volume) in &config.volumes {
+        if !Path::new(mp).is_absolute() {
+            err!("Volume mount point {:?} must be absolute", mp);
+        }
+        match *volume {
+            Volume::Overlay(ref opt) => validate_overlay(mp, opt, sandbox),
+            Volume::File(ref opt) => validate_file(mp, opt, sandbox),
+            Volume::Persistent(ref opt) => match (opt.size, opt.quota) {
+                (Some(size), QuotaMethod::Loop) if size < MIN_IMAGE_SIZE => {
+                    err!("Volume {:?}: size {} is too small for an image, \
+                        minimum is {}", mp, size, MIN_IMAGE_SIZE);
+                }
+                _ => {}
+            },
+            Volume::SecretFiles(ref opt) => {
+                for (name, values) in &opt.files {
+                    if let Err(e) = SecretFilesInfo::check_name(name) {
+                        err!("Volume {:?}: {}", mp, e);
+                    }
+                    if !values.iter().any(|x| x.starts_with("v2:")) {
+                        err!("Volume {:?}: secret {:?} has no v2 values \
+                            (only v2 secrets are supported)", mp, name);
+                    }
+                }
+                match sandbox {
+                    Some(s) if s.secrets_private_key.is_none() => {
+                        err!("Volume {:?}: sandbox has no \
+                            secrets-private-key", mp);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(ref opt) = config.overlay_root {
+        validate_overlay("/", opt, sandbox);
+    }
+}
+
+fn validate_rlimits(config: &ContainerConfig,
+    sandbox: Option<&SandboxConfig>)
+{
+    for (&resource, limit) in &config.rlimits {
+        let max = match sandbox {
+            Some(sandbox) => sandbox.max_rlimits.get(&resource).cloned(),
+            // can't check maximum without sandbox, only check soft/hard
+            None => Some(u64::max_value()),
+        };
+        if let Err(e) = limit.check(resource, max) {
+            err!("{}", e);
+        }
+    }
+    if let Some(sandbox) = sandbox {
+        let core = config.rlimits.get(&Resource::Core)
+            .map(|x| x.hard > 0).unwrap_or(false);
+        if core && sandbox.core_dump_dir.is_none() {
+            err!("Core dumps are enabled in rlimits, but there is no \
+                core-dump-dir in sandbox config");
+        }
+    }
+}
+
 fn validate_variable_types(config: &ContainerConfig, child_cfg: &ChildConfig,
     sandbox: &SandboxConfig)
 {
@@ -170,26 +383,45 @@ fn validate_variable_types(config: &ContainerConfig, child_cfg: &ChildConfig,
     }
 }
 
+fn socket_fds(config: &ContainerConfig) -> Vec<(String, RawFd)> {
+    config.tcp_ports.iter()
+        .map(|(port, props)| (format!("Port {}", port), props.fd))
+    .chain(config.udp_ports.iter()
+        .map(|(port, props)| (format!("Udp port {}", port), props.fd)))
+    .chain(config.unix_sockets.iter()
+        .map(|(path, props)| (format!("Socket {:?}", path), props.fd)))
+    .collect()
+}
+
 fn validate_activation(config: &ContainerConfig) {
     let mut nsockets = 0;
+    let fds = socket_fds(config);
+    for (i, &(ref name, fd)) in fds.iter().enumerate() {
+        if fds[..i].iter().any(|&(_, other)| other == fd) {
+            err!("{} uses fd {} which is already used by other socket",
+                name, fd);
+        }
+    }
     for (key, typ) in &config.variables {
-        match typ {
-            TcpPort(TcpPortSettings { activation: Systemd })
-            => {
-                nsockets += 1;
-                let fd = 2+nsockets;
-                for (port, props) in &config.tcp_ports {
-                     if props.fd == fd {
-                        err!("Port {} conflicts with var {:?} \
-                            for fd: {}. \
-                            You may change file descriptor to a \
-                            higher value, or expand 'activation' \
-                            manually.",
-                            port, key, fd);
-                     }
-                }
+        let settings = match *typ {
+            Variable::TcpPort(ref s) => s,
+            Variable::UdpPort(ref s) => s,
+            Variable::UnixSocket(ref s) => s,
+            _ => continue,
+        };
+        if settings.activation == Systemd {
+            nsockets += 1;
+            let fd = 2+nsockets;
+            for &(ref name, sfd) in &fds {
+                 if sfd == fd {
+                    err!("{} conflicts with var {:?} \
+                        for fd: {}. \
+                        You may change file descriptor to a \
+                        higher value, or expand 'activation' \
+                        manually.",
+                        name, key, fd);
+                 }
             }
-            _ => {}
         }
     }
     if nsockets > 0 { // only first time
@@ -209,7 +441,8 @@ fn validate_activation(config: &ContainerConfig) {
 }
 
 fn check(config_file: &Path, verbose: bool,
-    altered_sandbox: Option<String>, alter_config: Option<PathBuf>)
+    altered_sandbox: Option<String>, alter_config: Option<PathBuf>,
+    explain_mounts: bool)
 {
     let mut alter_config = alter_config;
     let master: MasterConfig = match parse_config(&config_file,
@@ -224,6 +457,9 @@ fn check(config_file: &Path, verbose: bool,
     check_master_config(&master, verbose);
 
     let config_dir = config_file.parent().unwrap().join(&master.sandboxes_dir);
+    let mut cpu_allocations = Vec::<(String, CpuList)>::new();
+    let mut loop_images = Vec::<(String, PathBuf)>::new();
+    let mut published = Vec::<(String, PortForward)>::new();
     scan_dir::ScanDir::files().read(&config_dir, |iter| {
         let yamls = iter.filter(|&(_, ref name)| name.ends_with(".yaml"));
         for (entry, current_fn) in yamls {
@@ -238,6 +474,20 @@ fn check(config_file: &Path, verbose: bool,
                 }
             };
             check_sandbox_config(&sandbox);
+            let image_keys = if sandbox.image_public_keys.len() > 0 {
+                match image_manifest::read_public_keys(
+                    &sandbox.image_public_keys)
+                {
+                    Ok(keys) => Some(keys),
+                    Err(e) => {
+                        err!("Bad entry in `image-public-keys`: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let mut verified_images = HashSet::new();
 
             let default_config = config_file.parent().unwrap()
                 .join(&master.processes_dir)
@@ -272,7 +522,20 @@ fn check(config_file: &Path, verbose: bool,
                         child_cfg.image, current_name, child_name);
                     continue;
                 }
+                if let Some(ref keys) = image_keys {
+                    if verified_images.insert(child_cfg.image.clone()) {
+                        let image_path = sandbox.image_dir
+                            .join(&child_cfg.image);
+                        if let Err(e) = image_manifest::verify(&image_path,
+                                                               keys)
+                        {
+                            err!("Image {} in sandbox {}: {}",
+                                child_cfg.image, current_name, e);
+                        }
+                    }
+                }
                 debug!("Opening config for {:?}", child_name);
+                let errors_before = ERRORS.load(Ordering::SeqCst);
                 let config = match check_container(&sandbox.image_dir
                     .join(&child_cfg.image)
                     .join(&relative(cfg_path, &Path::new("/"))),
@@ -359,11 +622,56 @@ fn check(config_file: &Path, verbose: bool,
                             if !network_contains(&bridge.network, ip) {
                                 err!("{}: invalid ip {}", name, ip);
                             }
+                            if bridge.ip_pool.map_or(false,
+                                |pool| pool.contains(ip))
+                            {
+                                err!("{}: ip {} is inside of `ip-pool`, \
+                                    it may be allocated to other process",
+                                    name, ip);
+                            }
                         } else if ichild.kind == ChildKind::Command {
                             // okay to have no IP for commands
+                        } else if ichild.ipv6_address.is_some() {
+                            // ipv6-only instance
+                        } else if bridge.ip_pool.is_some() {
+                            // will be allocated by lithos_tree
                         } else {
                             err!("{}: no IP address specified", name);
                         }
+                        match (ichild.ipv6_address, bridge.ipv6_network) {
+                            (Some(ip), Some(ref net)) => {
+                                if !network_contains(net, ip) {
+                                    err!("{}: invalid ipv6 {}", name, ip);
+                                }
+                            }
+                            (Some(_), None) => {
+                                err!("{}: ipv6 address requires \
+                                    `ipv6-network` in bridged-network", name);
+                            }
+                            (None, _) => {}
+                        }
+                        for &ip in &ichild.extra_addresses {
+                            let net = if ip.is_ipv4() {
+                                Some(&bridge.network)
+                            } else {
+                                bridge.ipv6_network.as_ref()
+                            };
+                            if !net.map_or(false,
+                                |net| network_contains(net, ip))
+                            {
+                                err!("{}: invalid ip {}", name, ip);
+                            }
+                            if bridge.ip_pool.map_or(false,
+                                |pool| pool.contains(ip))
+                            {
+                                err!("{}: ip {} is inside of `ip-pool`, \
+                                    it may be allocated to other process",
+                                    name, ip);
+                            }
+                        }
+                    } else if ichild.ipv6_address.is_some() {
+                        err!("{}: ipv6 address requires bridged network",
+                            name);
                     }
 
                     let icfg = match config.instantiate(&Variables {
@@ -381,6 +689,116 @@ fn check(config_file: &Path, verbose: bool,
                             continue;
                         }
                     };
+                    if let Some(ref cpuset) = icfg.cpuset {
+                        match cpuset.allocate(sandbox.cpu_pool.as_ref(),
+                            sandbox.memory_nodes.as_ref(), i)
+                        {
+                            Ok(alloc) => {
+                                for &(ref other, ref cpus) in &cpu_allocations
+                                {
+                                    if cpus.intersects(&alloc.cpus) {
+                                        err!("{}: cpus {} overlap with \
+                                            cpus {} of {}",
+                                            name, alloc.cpus, cpus, other);
+                                    }
+                                }
+                                cpu_allocations.push((name.clone(),
+                                                      alloc.cpus));
+                            }
+                            Err(e) => err!("{}: {}", name, e),
+                        }
+                        if master.cgroup_name.is_none() {
+                            err!("{}: cpuset requires `cgroup-name` \
+                                in master config", name);
+                        }
+                    }
+                    // mount plan of an invalid container is meaningless
+                    if explain_mounts &&
+                        ERRORS.load(Ordering::SeqCst) == errors_before
+                    {
+                        match MountPlan::new(&master, &sandbox, &name, &icfg) {
+                            Ok(plan) => {
+                                println!("{}:", name);
+                                for step in &plan.steps {
+                                    println!("    {}", step);
+                                }
+                            }
+                            Err(e) => err!("{}: {}", name, e),
+                        }
+                    }
+                    if !icfg.published_ports.is_empty() &&
+                        sandbox.bridged_network.is_none()
+                    {
+                        err!("{}: published-ports require bridged-network",
+                            name);
+                    }
+                    if let Err(e) = resolv_conf::check(&sandbox.dns,
+                        &icfg.resolv_conf,
+                        &resolv_conf::Vars::new(&name, &sandbox))
+                    {
+                        err!("{}: resolv-conf: {}", name, e);
+                    }
+                    if !icfg.bandwidth.is_empty() {
+                        let has_addr = ichild.ip_address.is_some() ||
+                            ichild.ipv6_address.is_some() ||
+                            sandbox.bridged_network.as_ref()
+                                .map_or(false, |b| b.ip_pool.is_some());
+                        if sandbox.bridged_network.is_none() || !has_addr {
+                            err!("{}: bandwidth limits require \
+                                bridged-network and an ip address", name);
+                        }
+                    }
+                    for fwd in &icfg.published_ports {
+                        if !in_range(&sandbox.allow_tcp_ports,
+                                     fwd.host_port as u32)
+                        {
+                            err!("{}: published port {} is not allowed",
+                                name, fwd.host_port);
+                        }
+                        let has_addr = if fwd.host.is_ipv4() {
+                            ichild.ip_address.is_some() ||
+                            sandbox.bridged_network.as_ref()
+                                .map_or(false, |b| b.ip_pool.is_some())
+                        } else {
+                            ichild.ipv6_address.is_some()
+                        };
+                        if !has_addr {
+                            err!("{}: no container address to publish \
+                                port {} to", name, fwd.host_port);
+                        }
+                        for &(ref other, ref ofwd) in &published {
+                            if port_forward::conflicts(fwd, ofwd) {
+                                err!("{}: port {}:{}/{} is also published \
+                                    by {}", name, fwd.host, fwd.host_port,
+                                    fwd.protocol, other);
+                            }
+                        }
+                        published.push((name.clone(), fwd.clone()));
+                    }
+                    for (mp, volume) in &icfg.volumes {
+                        let opt = match *volume {
+                            Volume::Persistent(ref opt)
+                            if opt.size.is_some() &&
+                                opt.quota == QuotaMethod::Loop => opt,
+                            _ => continue,
+                        };
+                        let image = match map_dir(&opt.path,
+                                                  &sandbox.writable_paths)
+                        {
+                            Some(image) => image,
+                            None => continue,  // reported by validate_volumes
+                        };
+                        if let Some(&(ref other, _)) = loop_images.iter()
+                            .find(|&&(_, ref x)| x == &image)
+                        {
+                            err!("{}: image {:?} of volume {:?} is also \
+                                used by {}, loop images can't be shared \
+                                (use a separate path or `quota: project`)",
+                                name, image, mp, other);
+                        } else {
+                            loop_images.push((name.clone(), image));
+                        }
+                    }
                     for (port, pinfo) in icfg.tcp_ports {
                         if sandbox.bridged_network.is_none() ||
                            pinfo.external
@@ -394,6 +812,53 @@ fn check(config_file: &Path, verbose: bool,
                             }
                         }
                     }
+                    for (port, pinfo) in icfg.udp_ports {
+                        if sandbox.bridged_network.is_none() ||
+                           pinfo.external
+                        {
+                            if !in_range(&sandbox.allow_tcp_ports, port as u32)
+                            {
+                                err!("Udp port {} is not allowed for {:?} \
+                                    of sandbox {:?} of image {:?}",
+                                    port, &ichild.config, current_name,
+                                    ichild.image);
+                            }
+                        }
+                    }
+                    if !icfg.unix_sockets.is_empty() {
+                        // the same way as lithos_tree finds user of sockets
+                        let uid = config.user_id.or(sandbox.default_user)
+                            .unwrap_or(0);
+                        let uid = if sandbox.uid_map.len() > 0 {
+                            sandbox.uid_map.map_id(uid).unwrap_or(0)
+                        } else if config.uid_map.len() > 0 {
+                            config.uid_map.map_id(uid).unwrap_or(0)
+                        } else {
+                            uid
+                        };
+                        if uid == 0 {
+                            err!("{}: unix sockets can't be created by root, \
+                                container user must be non-zero and mapped",
+                                name);
+                        }
+                    }
+                    for (path, sinfo) in &icfg.unix_sockets {
+                        if let Err(e) = sandbox.check_socket_path(path) {
+                            err!("{}: {}", name, e);
+                        }
+                        if let Some(uid) = sinfo.user_id {
+                            if !in_range(&sandbox.allow_users, uid) {
+                                err!("{}: user {} of socket {:?} \
+                                    is not allowed", name, uid, path);
+                            }
+                        }
+                        if let Some(gid) = sinfo.group_id {
+                            if !in_range(&sandbox.allow_groups, gid) {
+                                err!("{}: group {} of socket {:?} \
+                                    is not allowed", name, gid, path);
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -432,6 +897,7 @@ fn main() {
 
     let mut config_file = PathBuf::from("/etc/lithos/master.yaml");
     let mut verbose = false;
+    let mut explain_mounts = false;
     let mut alter_config = None;
     let mut sandbox_name = None;
     let mut check_containers = Vec::<String>::new();
@@ -446,6 +912,10 @@ fn main() {
         ap.refer(&mut verbose)
           .add_option(&["-v", "--verbose"], StoreTrue,
             "Verbose output");
+        ap.refer(&mut explain_mounts)
+          .add_option(&["--explain-mounts"], StoreTrue,
+            "Print the list of mounts that would be done for each
+             container instance");
         ap.refer(&mut alter_config)
           .add_option(&["--alternate-config"], ParseOption,
             "Name of the alterate file name with configs.
@@ -488,7 +958,8 @@ fn main() {
         }
     } else {
         check_binaries();
-        check(&config_file, verbose, sandbox_name, alter_config);
+        check(&config_file, verbose, sandbox_name, alter_config,
+              explain_mounts);
     }
     let exit_status = EXIT_STATUS.load(Ordering::SeqCst) as i32;
     if exit_status != 0 {
@@ -27,6 +27,7 @@ use argparse::{Print, StoreOption};
 use lithos::child_config::ChildConfig;
 use lithos::master_config::MasterConfig;
 use lithos::MAX_CONFIG_LOGS;
+use lithos::core_dumps;
 use lithos::sandbox_config::SandboxConfig;
 
 
@@ -52,6 +53,7 @@ struct ScanResult {
     images: HashSet<PathBuf>,
     image_dirs: HashMap<PathBuf, u32>,
     unused_logs: Vec<PathBuf>,
+    old_core_dumps: Vec<PathBuf>,
 }
 
 
@@ -67,6 +69,7 @@ fn main() {
     let mut ver_max = 1000;
     let mut action = Action::Used;
     let mut clean_logs = false;
+    let mut clean_cores = false;
     let mut days = None::<u32>;
     let mut keep_recent = None::<humantime::Duration>;
     {
@@ -116,6 +119,11 @@ fn main() {
           .add_option(&["--clean-logs"], StoreConst(true),
             "In combination with `--unused` shows unused logs, \
              in combination with `--delete-unused` deletes them.");
+        ap.refer(&mut clean_cores)
+          .add_option(&["--clean-cores"], StoreConst(true),
+            "In combination with `--unused` shows core dumps exceeding \
+             `max-core-dumps`, in combination with `--delete-unused` \
+             deletes them.");
         ap.add_option(&["--version"],
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version of the lithos");
@@ -166,6 +174,11 @@ fn main() {
                     }
                 }
             }
+            if clean_cores {
+                for dir in scan_result.old_core_dumps {
+                    println!("{:?}", dir);
+                }
+            }
         }
         Action::DeleteUnused => {
             let unused = find_unused(&scan_result.images,
@@ -198,6 +211,16 @@ fn main() {
                     }
                 }
             }
+            if clean_cores {
+                for dir in scan_result.old_core_dumps {
+                    if verbose {
+                        println!("Deleting core dump {:?}", dir);
+                    }
+                    remove_dir_all(&dir)
+                        .map_err(|e| error!("Error removing {:?}: {}", dir, e))
+                        .ok();
+                }
+            }
         }
     }
 }
@@ -445,6 +468,20 @@ fn find_used_by_log(master: &MasterConfig, sandbox_name: &str,
     }
 }
 
+fn find_old_core_dumps(dir: &Path, keep: usize, old: &mut Vec<PathBuf>) {
+    if !dir.exists() {
+        return;
+    }
+    scan_dir::ScanDir::dirs().skip_symlinks(true).read(dir, |iter| {
+        for (entry, _) in iter {
+            match core_dumps::old_dumps(&entry.path(), keep) {
+                Ok(dumps) => old.extend(dumps),
+                Err(e) => error!("Error scanning {:?}: {}", entry.path(), e),
+            }
+        }
+    }).map_err(|e| error!("Error scanning {:?}: {}", dir, e)).ok();
+}
+
 fn find_used_images(master: &MasterConfig, master_file: &Path,
     min_time: Option<SystemTime>, ver_min: u32, ver_max: u32)
     -> Result<ScanResult, String>
@@ -455,6 +492,7 @@ fn find_used_images(master: &MasterConfig, master_file: &Path,
     let mut image_dirs = HashMap::new();
     let mut no_clean_dirs = HashSet::new();
     let mut unused_logs = Vec::new();
+    let mut old_core_dumps = Vec::new();
     let childval = ChildConfig::mapping_validator();
     scan_dir::ScanDir::files().read(&config_dir, |iter| -> Result<(), String> {
         let yamls = iter.filter(|&(_, ref name)| name.ends_with(".yaml"));
@@ -464,6 +502,11 @@ fn find_used_images(master: &MasterConfig, master_file: &Path,
                 &SandboxConfig::validator(), &Options::default())
                 .map_err(|e| e.to_string())?;
 
+            if let Some(ref dir) = sandbox_config.core_dump_dir {
+                find_old_core_dumps(&dir.join(sandbox_name),
+                    sandbox_config.max_core_dumps, &mut old_core_dumps);
+            }
+
             if sandbox_config.auto_clean == false {
                 no_clean_dirs.insert(sandbox_config.image_dir.clone());
                 if image_dirs.contains_key(&sandbox_config.image_dir) {
@@ -528,5 +571,5 @@ fn find_used_images(master: &MasterConfig, master_file: &Path,
             dir);
         image_dirs.remove(dir);
     }
-    Ok(ScanResult { images, image_dirs, unused_logs })
+    Ok(ScanResult { images, image_dirs, unused_logs, old_core_dumps })
 }
@@ -12,16 +12,17 @@ extern crate crypto;
 
 use std::fs::File;
 use std::io::{Read, BufReader, BufRead, Write, stdout, stderr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 use blake2::{Blake2b, digest::VariableOutput, digest::Input};
-use failure::{Error, ResultExt};
+use failure::{Error, ResultExt, err_msg};
 use regex::Regex;
 use ssh_keys::{PublicKey, PrivateKey, openssh};
 use structopt::StructOpt;
 
 use lithos::nacl;
+use lithos::image_manifest;
 
 
 #[derive(Debug, StructOpt)]
@@ -36,6 +37,8 @@ enum Options {
     Decrypt(DecryptOpt),
     #[structopt(name="check-key")]
     CheckKey(CheckKeyOpt),
+    #[structopt(name="sign-image")]
+    SignImage(SignImageOpt),
 }
 
 #[derive(Debug, StructOpt)]
@@ -77,6 +80,17 @@ pub struct DecryptOpt {
     data: String,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Write signed manifest into the image directory")]
+pub struct SignImageOpt {
+    #[structopt(long="key-file", short="i", help="
+        A openssh-formatted ed25519 private key to sign manifest with
+    ", parse(try_from_str="parse_private_key"))]
+    key: PrivateKey,
+    #[structopt(help="image directory", parse(from_os_str))]
+    dir: PathBuf,
+}
+
 fn validate_namespace(namespace: &str) -> Result<String, Error> {
     if !Regex::new("^[a-zA-Z0-9_.-]*$").expect("valid re").is_match(namespace) {
         bail!("invalid namespace, \
@@ -194,6 +208,15 @@ fn decrypt(e: DecryptOpt) -> Result<(), Error> {
     Ok(())
 }
 
+fn sign_image(o: SignImageOpt) -> Result<(), Error> {
+    let key_bytes = match o.key {
+        PrivateKey::Ed25519(key) => key,
+        _ => bail!("Only ed25519 keys are supported"),
+    };
+    image_manifest::sign(&o.dir, &key_bytes[..]).map_err(err_msg)?;
+    Ok(())
+}
+
 fn main() {
     use Options::*;
     let opt = Options::from_args();
@@ -201,6 +224,7 @@ fn main() {
         Encrypt(e) => encrypt(e),
         Decrypt(d) => decrypt(d),
         CheckKey(c) => check_key(c),
+        SignImage(s) => sign_image(s),
     };
     match res {
         Ok(()) => {
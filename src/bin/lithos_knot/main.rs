@@ -20,36 +20,44 @@ extern crate unshare;
 use std::env;
 use std::str::FromStr;
 use std::io::{stderr, Write};
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::path::{Path};
 use std::time::{SystemTime, Instant, Duration};
 use std::thread::sleep;
 use std::process::exit;
 use std::net::SocketAddr;
+use std::os::unix::io::{RawFd, FromRawFd};
 
 use humantime::format_rfc3339_seconds;
 use libmount::BindMount;
 use quire::{parse_config, Options as COptions};
 use signal::trap::Trap;
-use unshare::{Command, Stdio, Style, reap_zombies, Capability, Namespace};
+use unshare::{Command, Stdio, Fd, Style, reap_zombies, Capability};
+use unshare::{Namespace, ExitStatus};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
 use nix::sys::signal::Signal;
 use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD};
 use nix::sys::socket::{InetAddr, SockAddr};
 
 use lithos::cgroup;
 use lithos::utils::{check_mapping, in_mapping, change_root};
-use lithos::utils::{temporary_change_root};
+use lithos::utils::{temporary_change_root, instance_number, relative};
 use lithos::range::in_range;
 use lithos::master_config::MasterConfig;
 use lithos::sandbox_config::SandboxConfig;
-use lithos::container_config::{ContainerConfig, Variables};
+use lithos::container_config::{ContainerConfig, Variables, Volume};
 use lithos::container_config::ContainerKind::Daemon;
 use lithos::setup::{init_logging};
 use lithos::mount::{unmount, mount_private, mount_ro_recursive, mount_pseudo};
-use lithos::limits::{set_fileno_limit};
-use lithos::knot_options::Options;
+use lithos::limits::{set_fileno_limit, set_rlimit, Resource, Rlimit};
+use lithos::core_dumps;
+use lithos::scheduling;
+use lithos::devices;
+use lithos::image_manifest;
+use lithos::knot_options::{Options, REMAP_FDS_VAR, parse_fd_map};
 
 use setup_filesystem::{setup_filesystem, prepare_state_dir};
+use setup_filesystem::{write_secret_files};
 
 mod setup_network;
 mod setup_filesystem;
@@ -91,6 +99,43 @@ fn duration(inp: f32) -> Duration {
     Duration::from_millis((inp * 1000.) as u64)
 }
 
+fn collect_core_dumps<W: Write>(base: &Path, dir: &File, name: &str,
+    log: &mut W)
+{
+    match core_dumps::collect_at(dir) {
+        Ok(Some(stamp)) => {
+            let path = core_dumps::instance_dir(base, name).join(stamp);
+            error!("Core dump of {:?} is saved into {:?}", name, path);
+            log.write_all(
+                format!("{}: ----- Core dump is saved into {:?} -----\n",
+                    format_rfc3339_seconds(SystemTime::now()), path)
+                .as_bytes()
+            ).ok();
+        }
+        Ok(None) => {}
+        Err(e) => error!("Error collecting core dumps of {:?}: {}", name, e),
+    }
+}
+
+/// Takes ownership of descriptors passed by lithos_tree for remapping
+///
+/// Descriptors are made close-on-exec, so they are only visible in the
+/// container at the target number.
+fn inherited_fds() -> Result<Vec<(RawFd, File)>, String> {
+    let value = match env::var(REMAP_FDS_VAR) {
+        Ok(value) => value,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut result = Vec::new();
+    for (target, source) in parse_fd_map(&value)? {
+        fcntl(source, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| format!("Bad file descriptor {} for fd {}: {}",
+                source, target, e))?;
+        result.push((target, unsafe { File::from_raw_fd(source) }));
+    }
+    Ok(result)
+}
+
 fn run(options: &Options) -> Result<i32, String>
 {
     let master: MasterConfig = try!(parse_config(&options.master_config,
@@ -118,6 +163,8 @@ fn run(options: &Options) -> Result<i32, String>
             .or_else(|| FromStr::from_str(&master.log_level).ok())
             .unwrap_or(log::LogLevel::Warn)));
 
+    let remapped_fds = try!(inherited_fds());
+
     let stderr_path = master.stdio_log_dir
         .join(format!("{}.log", sandbox_name));
     let mut stderr_file = try!(OpenOptions::new()
@@ -131,6 +178,14 @@ fn run(options: &Options) -> Result<i32, String>
     try!(BindMount::new(&image_path, &mount_dir).mount()
         .map_err(|e| e.to_string()));
     try!(mount_ro_recursive(&mount_dir));
+    if sandbox.image_public_keys.len() > 0 {
+        // check what is actually mounted, the image dir might be replaced
+        let keys = image_manifest::read_public_keys(
+            &sandbox.image_public_keys)?;
+        image_manifest::verify(&mount_dir, &keys)
+            .map_err(|e| format!("Image {:?} verification failed: {}",
+                options.config.image, e))?;
+    }
 
     let container: ContainerConfig;
     container = config::container_config(&mount_dir, &options.config)?;
@@ -190,12 +245,46 @@ fn run(options: &Options) -> Result<i32, String>
         return Err("Bad gid mapping (probably doesn't match allow_groups)"
             .to_string());
     }
+    for (&resource, limit) in &local.rlimits {
+        limit.check(resource, sandbox.max_rlimits.get(&resource).cloned())?;
+    }
+    scheduling::check(local.nice, local.ioprio.as_ref(), &sandbox)?;
+    devices::check(&local.devices, &sandbox)?;
+    if sandbox.collect_core_dumps {
+        // default must not exceed `max-rlimits` of the sandbox either
+        let default = match sandbox.max_rlimits.get(&Resource::Core) {
+            Some(&max) => Rlimit { soft: max, hard: max },
+            None => Rlimit::unlimited(),
+        };
+        local.rlimits.entry(Resource::Core).or_insert(default);
+    }
+    let cpuset = match local.cpuset {
+        Some(ref cfg) => {
+            let instance = instance_number(&options.name).unwrap_or(0);
+            Some(cfg.allocate(sandbox.cpu_pool.as_ref(),
+                    sandbox.memory_nodes.as_ref(), instance)
+                .map_err(|e| format!("Error allocating cpuset: {}", e))?)
+        }
+        None => None,
+    };
+    let core_dump_dir = match local.rlimits.get(&Resource::Core) {
+        Some(lim) if lim.hard > 0 => sandbox.core_dump_dir.clone(),
+        _ => None,
+    };
 
     info!("[{}] Starting container", options.name);
     let state_dir = &master.runtime_dir.join(&master.state_dir)
         .join(&options.name);
-    try!(prepare_state_dir(state_dir, &local, &sandbox));
-    try!(setup_filesystem(&master, &sandbox, &local, state_dir));
+    try!(prepare_state_dir(state_dir, &options.name, &master,
+        &local, &sandbox));
+    let dev_nodes = setup_filesystem(&master, &sandbox,
+                                     &options.name, &local)?;
+    // opened before knot is chrooted into the container
+    let core_dir = match core_dump_dir {
+        Some(ref dir) => Some((dir,
+            core_dumps::open_instance_dir(dir, &options.name)?)),
+        None => None,
+    };
     if let Some(cgroup_parent) = master.cgroup_name {
         // Warning setting cgroup relative to it's own cgroup may not work
         // if we ever want to restart lithos_knot in-place
@@ -215,10 +304,37 @@ fn run(options: &Options) -> Result<i32, String>
                 "cpu.shares",
                 &format!("{}", local.cpu_shares))
             .map_err(|e| error!("Error setting cgroup limit: {}", e)).ok();
+        // containers without `devices` share devfs-dir and aren't
+        // restricted, as it was before `devices` were introduced
+        if !local.devices.is_empty() &&
+            cgroups.has_controller(cgroup::Controller::Devices)
+        {
+            cgroups.set_value(cgroup::Controller::Devices,
+                "devices.deny", "a")?;
+            for rule in devices::cgroup_rules(&dev_nodes) {
+                cgroups.set_value(cgroup::Controller::Devices,
+                    "devices.allow", &rule)?;
+            }
+        }
+        if let Some(ref alloc) = cpuset {
+            cgroups.set_value(cgroup::Controller::Cpuset,
+                    "cpuset.cpus", &alloc.cpus.to_string())?;
+            if let Some(ref mems) = alloc.mems {
+                cgroups.set_value(cgroup::Controller::Cpuset,
+                        "cpuset.mems", &mems.to_string())?;
+            }
+        }
+    } else if cpuset.is_some() {
+        return Err(format!("cpuset requires `cgroup-name` in master config"));
     }
 
+    let has_secret_files = local.volumes.values().any(|v| match *v {
+        Volume::SecretFiles(..) => true,
+        _ => false,
+    });
     let has_secrets = container.secret_environ_file.is_some() ||
-                      !container.secret_environ.is_empty();
+                      !container.secret_environ.is_empty() ||
+                      has_secret_files;
     let keys = if has_secrets {
         Some(secrets::read_keys(&sandbox)
             .map_err(|e| format!("Error decoding private keys: {}", e))?)
@@ -251,10 +367,43 @@ fn run(options: &Options) -> Result<i32, String>
             Ok(())
         })?;
     }
+    if let Some(ref keys) = keys {
+        for (mp, volume) in &local.volumes {
+            if let Volume::SecretFiles(ref opt) = *volume {
+                let values = secrets::decode(keys, &sandbox, &options.config,
+                    &opt.files)
+                    .map_err(|e| format!("Error decoding secrets \
+                        for volume {:?}: {}", mp, e))?;
+                let dir = mount_dir.join(relative(Path::new(mp),
+                                                  Path::new("/")));
+                write_secret_files(&dir, opt, &local, &values)
+                    .map_err(|e| format!("Error writing secret files \
+                        for volume {:?}: {}", mp, e))?;
+            }
+        }
+    }
     drop(keys);
 
     try!(set_fileno_limit(local.fileno_limit)
         .map_err(|e| format!("Error setting file limit: {}", e)));
+    for (&resource, limit) in &local.rlimits {
+        set_rlimit(resource, limit)
+            .map_err(|e| format!("Error setting rlimit {:?}: {}",
+                resource, e))?;
+    }
+    // Scheduling settings are inherited by the child process
+    if local.nice != 0 {
+        scheduling::set_nice(local.nice)
+            .map_err(|e| format!("Error setting nice: {}", e))?;
+    }
+    if let Some(ref prio) = local.ioprio {
+        scheduling::set_ioprio(prio)
+            .map_err(|e| format!("Error setting ioprio: {}", e))?;
+    }
+    if let Some(policy) = local.sched_policy {
+        scheduling::set_sched_policy(policy)
+            .map_err(|e| format!("Error setting sched policy: {}", e))?;
+    }
 
     // This is needed for unshare to properly initialize user namespace
     mount_pseudo(&Path::new("/proc"), "proc", "", false)?;
@@ -320,8 +469,9 @@ fn run(options: &Options) -> Result<i32, String>
 
         let net = net.clone();
         let child = options.config.clone();
+        let bandwidth = local.bandwidth.clone();
         cmd.before_unfreeze(move |pid| {
-            setup_network::setup(pid, &net, &child)?;
+            setup_network::setup(pid, &net, &child, &bandwidth)?;
             child_setup(pid)?;
             Ok(())
         });
@@ -330,8 +480,15 @@ fn run(options: &Options) -> Result<i32, String>
             .map(|(port, cfg)| {
                 let addr = SockAddr::new_inet(InetAddr::from_std(
                     &SocketAddr::new(cfg.host.0, *port)));
-                (cfg.clone(), addr)
+                (setup_network::Listen::tcp(cfg), addr)
             })
+            .chain(local.udp_ports.iter()
+                .filter(|(_, v)| !v.external)
+                .map(|(port, cfg)| {
+                    let addr = SockAddr::new_inet(InetAddr::from_std(
+                        &SocketAddr::new(cfg.host.0, *port)));
+                    (setup_network::Listen::udp(cfg), addr)
+                }))
             .collect::<Vec<_>>();
         cmd.before_exec(move || {
             for &(ref cfg, ref addr) in &sockets {
@@ -344,6 +501,14 @@ fn run(options: &Options) -> Result<i32, String>
     } else {
         cmd.before_unfreeze(child_setup);
     }
+    if !local.published_ports.is_empty() {
+        setup_network::publish_ports(&options.name,
+            &local.published_ports, &sandbox, &options.config)?;
+    }
+    setup_network::install_policy(&options.name,
+        &sandbox, &options.config)?;
+    let _shaping = setup_network::Shaping::new(&local.bandwidth,
+        &sandbox, &options.config)?;
     let rtimeo = Duration::from_millis((local.restart_timeout*1000.0) as u64);
 
     let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD]);
@@ -377,6 +542,17 @@ fn run(options: &Options) -> Result<i32, String>
             };
         }
 
+        for &(target, ref file) in &remapped_fds {
+            let dup_err = |e| format!("Duplicating file descriptor: {}", e);
+            match target {
+                0 => cmd.stdin(try!(Stdio::dup_file(file).map_err(dup_err))),
+                1 => cmd.stdout(try!(Stdio::dup_file(file).map_err(dup_err))),
+                2 => cmd.stderr(try!(Stdio::dup_file(file).map_err(dup_err))),
+                _ => cmd.file_descriptor(target,
+                    try!(Fd::dup_file(file).map_err(dup_err))),
+            };
+        }
+
         warn!("Starting {:?}: {}", options.name,
             cmd.display(&Style::short().path(true)));
         stderr_file.write_all(
@@ -437,6 +613,15 @@ fn run(options: &Options) -> Result<i32, String>
                                     options.name, status, uptime.as_secs(),
                                 ).as_bytes()
                             ).ok();
+                            match (&core_dir, status) {
+                                (&Some((base, ref dir)),
+                                 ExitStatus::Signaled(_, true))
+                                => {
+                                    collect_core_dumps(base, dir,
+                                        &options.name, &mut stderr_file);
+                                }
+                                _ => {}
+                            }
                             iter.interrupt();
                         }
                     }
@@ -1,47 +1,65 @@
 use std::io;
-use std::io::{Write, BufWriter};
-use std::fs::{File};
-use std::fs::{create_dir_all, copy, metadata, symlink_metadata};
-use std::path::{Path, PathBuf};
+use std::mem;
+use std::io::{Read, Write, BufWriter};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
 use std::collections::BTreeMap;
+use std::fs::{create_dir_all, copy, metadata, symlink_metadata};
+use std::path::Path;
+use std::iter::once;
 
 use libmount::{self, BindMount};
 use failure::{Error, ResultExt, err_msg};
 
-use lithos::mount::{mount_ro_recursive};
-use lithos::mount::{mount_pseudo, mount_pts};
-use lithos::network::{get_host_ip, get_host_name};
+use lithos::mount::{remount, mount_pseudo, mount_pts, mount_device};
+use lithos::mount_plan::{MountPlan, Step};
+use lithos::network::{public_addresses, public_hostname};
 use lithos::master_config::MasterConfig;
 use lithos::sandbox_config::SandboxConfig;
-use lithos::container_config::{InstantiatedConfig, Volume};
-use lithos::container_config::Volume::{Statedir, Readonly, Persistent, Tmpfs};
-use lithos::utils::{set_file_mode, set_file_owner};
-use lithos::utils::{relative};
-
+use lithos::container_config::{InstantiatedConfig, SecretFilesInfo};
+use lithos::container_config::MountOptions;
+use lithos::utils::{set_file_mode, set_file_owner, relative};
+use lithos::limits::core_pattern_dir;
+use lithos::core_dumps;
+use lithos::devices::{self, Device};
+use lithos::quota;
+use lithos::resolv_conf;
 
-fn map_dir(dir: &Path, dirs: &BTreeMap<PathBuf, PathBuf>) -> Option<PathBuf> {
-    assert!(dir.is_absolute());
-    for (prefix, real_dir) in dirs.iter() {
-        if dir.starts_with(prefix) {
-            return Some(real_dir.join(relative(dir, prefix)));
-        }
-    }
-    return None;
-}
 
-fn prepare_resolv_conf(state_dir: &Path, local: &InstantiatedConfig,
-    tree: &SandboxConfig)
+fn prepare_resolv_conf(state_dir: &Path, name: &str,
+    local: &InstantiatedConfig, tree: &SandboxConfig)
     -> Result<(), Error>
 {
     let path = state_dir.join("resolv.conf");
-    if local.resolv_conf.copy_from_host {
-        copy(&tree.resolv_conf, &path)?;
+    let resolv = &local.resolv_conf;
+    if !resolv_conf::is_configured(&tree.dns, resolv) {
+        if resolv.copy_from_host {
+            copy(&tree.resolv_conf, &path)?;
+        }
+        return Ok(());
     }
+    let host = if resolv.copy_from_host {
+        let mut data = String::new();
+        File::open(&tree.resolv_conf)
+            .and_then(|mut f| f.read_to_string(&mut data))
+            .map_err(|e| format_err!(
+                "error reading {:?}: {}", tree.resolv_conf, e))?;
+        Some(data)
+    } else {
+        None
+    };
+    let vars = resolv_conf::Vars::new(name, tree);
+    let data = resolv_conf::render(
+        host.as_ref().map(|x| &x[..]), &tree.dns, resolv, &vars)
+        .map_err(err_msg)?;
+    let mut file = File::create(&path)?;
+    file.write_all(data.as_bytes())?;
+    set_file_mode(&path, 0o644)?;
     Ok(())
 }
 
-fn prepare_hosts_file(state_dir: &Path, local: &InstantiatedConfig,
-    tree: &SandboxConfig)
+fn prepare_hosts_file(state_dir: &Path, master: &MasterConfig,
+    local: &InstantiatedConfig, tree: &SandboxConfig)
     -> Result<(), Error>
 {
     let copy_hosts = local.hosts_file.copy_from_host;
@@ -68,7 +86,10 @@ fn prepare_hosts_file(state_dir: &Path, local: &InstantiatedConfig,
                 .as_bytes())?;
         }
         if add_hostname {
-            writeln!(&mut file, "{} {}", get_host_ip()?, get_host_name()?)?;
+            let hostname = public_hostname(master)?;
+            for ip in public_addresses(master)? {
+                writeln!(&mut file, "{} {}", ip, hostname)?;
+            }
         }
         for (ref host, ref ip) in tree.additional_hosts.iter() {
             writeln!(&mut file, "{} {}", ip, host)?;
@@ -78,16 +99,16 @@ fn prepare_hosts_file(state_dir: &Path, local: &InstantiatedConfig,
     Ok(())
 }
 
-pub fn prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
-    tree: &SandboxConfig)
+pub fn prepare_state_dir(dir: &Path, name: &str, master: &MasterConfig,
+    local: &InstantiatedConfig, tree: &SandboxConfig)
     -> Result<(), String>
 {
-    _prepare_state_dir(dir, local, tree)
+    _prepare_state_dir(dir, name, master, local, tree)
     .map_err(|e| format!("state dir: {}", e))
 }
 
-fn _prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
-    tree: &SandboxConfig)
+fn _prepare_state_dir(dir: &Path, name: &str, master: &MasterConfig,
+    local: &InstantiatedConfig, tree: &SandboxConfig)
     -> Result<(), Error>
 {
     // TODO(tailhook) chown files
@@ -100,9 +121,9 @@ fn _prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
                 "Couldn't set chmod for state dir: {}", e))?;
     }
 
-    prepare_resolv_conf(dir, local, tree)
+    prepare_resolv_conf(dir, name, local, tree)
         .map_err(|e| format_err!("error preparing resolv.conf: {}", e))?;
-    prepare_hosts_file(dir, local, tree)
+    prepare_hosts_file(dir, master, local, tree)
         .map_err(|e| format_err!("error preparing hosts: {}", e))?;
     return Ok(());
 }
@@ -136,163 +157,272 @@ fn check_file(root: &Path, file: &str)
     Ok(true)
 }
 
-fn mount_hosts_file(root: &Path, local: &InstantiatedConfig,
-    state_dir: &Path)
+/// Bind-mounts a file from the state dir into the `/etc` of the image
+fn mount_etc_file(source: &Path, root: &Path, file: &str, required: bool)
     -> Result<(), Error>
 {
-    if local.hosts_file.mount == Some(false) {
-        return Ok(());
-    }
-    match (check_file(root, "hosts")?, local.hosts_file.mount) {
-        (false, None) => return Ok(()),
-        (true, None) | (true, Some(true)) => {}
-        (false, Some(true)) => {
-            bail!("/etc/hosts is not a valid mount point");
+    if !check_file(root, file)? {
+        if required {
+            bail!("/etc/{} is not a valid mount point", file);
         }
-        (_, Some(false)) => return Ok(()),  // unreachable
+        return Ok(());
     }
-    BindMount::new(&state_dir.join("hosts"), &root.join("etc/hosts"))
+    BindMount::new(source, &root.join("etc").join(file))
     .mount().map_err(|e| format_err!("{}", e))
 }
 
-fn mount_resolv_conf(root: &Path, local: &InstantiatedConfig,
-    state_dir: &Path)
+fn mount_core_dir(host_dir: &Path, name: &str, root: &Path)
     -> Result<(), Error>
 {
-    if local.resolv_conf.mount == Some(false) {
-        return Ok(());
-    }
-    if local.resolv_conf.mount.is_none() && !local.resolv_conf.copy_from_host {
-        return Ok(());
+    let dir = core_pattern_dir().map_err(err_msg)?
+        .ok_or_else(|| format_err!("kernel.core_pattern must be an \
+            absolute path to collect core dumps"))?;
+    let dest = root.join(relative(&dir, Path::new("/")));
+    match symlink_metadata(&dest) {
+        Ok(ref m) if m.is_dir() => {}
+        Ok(_) => bail!("{:?} is not a directory in the image", dir),
+        Err(e) => bail!("can't check core dir {:?}: {}", dir, e),
     }
-    match (check_file(root, "resolv.conf")?, local.resolv_conf.mount) {
-        (false, None) => return Ok(()),
-        (true, None) | (true, Some(true)) => {}
-        (false, Some(true)) => {
-            bail!("/etc/resolv.conf is not a valid mount point");
-        }
-        (_, Some(false)) => return Ok(()),  // unreachable
-    }
-    BindMount::new(&state_dir.join("resolv.conf"),
-                   &root.join("etc/resolv.conf"))
-    .mount().map_err(|e| format_err!("{}", e))
+    // collect leftovers of the previous run (e.g. if knot was killed)
+    core_dumps::collect(host_dir, name).map_err(err_msg)?;
+    let incoming = core_dumps::prepare_incoming(host_dir, name)
+        .map_err(err_msg)?;
+    BindMount::new(&incoming, &dest).mount()
+        .map_err(|e| format_err!("{}", e))
 }
 
-pub fn setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
-    local: &InstantiatedConfig, state_dir: &Path)
-    -> Result<(), String>
+/// Mounts (and creates on first use) a size-limited image file
+fn mount_image(image: &Path, dest: &Path, size: u64, mode: u32,
+    (user, group): (u32, u32))
+    -> Result<(), Error>
 {
-    _setup_filesystem(master, tree, local, state_dir)
-    .map_err(|e| format!("error setting up filesystem: {}", e))
+    let created = quota::prepare_image(image, size)
+        .map_err(|e| format_err!("Error creating image {:?}: {}", image, e))?;
+    let (device, lock) = quota::attach_loop(image)
+        .map_err(|e| format_err!("Error attaching image {:?}: {}",
+            image, e))?;
+    mount_device(&device, dest, "ext4").map_err(err_msg)?;
+    // Keep the lock for the whole life of the knot process. The descriptor
+    // is close-on-exec, so the container doesn't get it.
+    mem::forget(lock);
+    if created {
+        set_file_owner(dest, user, group)
+            .map_err(|e| format_err!("Error chowning \
+                persistent volume: {}", e))?;
+        set_file_mode(dest, mode)
+            .map_err(|e| format_err!("Can't chmod persistent \
+                volume: {}", e))?;
+    }
+    Ok(())
 }
 
-fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
-    local: &InstantiatedConfig, state_dir: &Path)
-    -> Result<(), Error>
-{
-    let root = PathBuf::from("/");
-    let mntdir = master.runtime_dir.join(&master.mount_dir);
-    assert!(mntdir.is_absolute());
+/// Checks that a single file can be bind-mounted from `source` to `target`
+fn check_bind_file(source: &Path, target: &Path) -> Result<(), Error> {
+    match metadata(source) {
+        Ok(ref m) if !m.is_dir() => {}
+        Ok(_) => bail!("Volume source {:?} is a directory", source),
+        Err(e) => bail!("Can't stat volume source {:?}: {}", source, e),
+    }
+    match symlink_metadata(target) {
+        Ok(ref m) if m.is_file() => {}
+        Ok(_) => bail!("{:?} is not a file in the image", target),
+        Err(e) => bail!("Can't check mount point {:?}: {}", target, e),
+    }
+    Ok(())
+}
 
-    let mut volumes: Vec<(&String, &Volume)> = local.volumes.iter().collect();
-    volumes.sort_by(|&(mp1, _), &(mp2, _)| mp1.len().cmp(&mp2.len()));
+/// Returns whether a path of pseudo filesystem is a directory
+///
+/// `None` means there is no such path in this kernel
+fn check_pseudo_path(path: &Path) -> Result<Option<bool>, Error> {
+    match symlink_metadata(path) {
+        Ok(m) => Ok(Some(m.is_dir())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => bail!("Can't check {:?}: {}", path, e),
+    }
+}
 
-    let devdir = mntdir.join("dev");
-    BindMount::new(&master.devfs_dir, &devdir).mount()
-        .map_err(|e| format_err!("{}", e))?;
-    mount_ro_recursive(&devdir).map_err(err_msg)?;
+fn mask_path(path: &Path) -> Result<(), Error> {
+    match check_pseudo_path(path)? {
+        Some(true) => {
+            libmount::Tmpfs::new(path)
+                .size_bytes(4096).mode(0o555)
+                .mount()
+                .map_err(|e| format_err!("{}", e))?;
+            remount(path, true, &MountOptions::default()).map_err(err_msg)?;
+        }
+        Some(false) => {
+            BindMount::new(Path::new("/dev/null"), path).mount()
+                .map_err(|e| format_err!("{}", e))?;
+        }
+        None => debug!("Nothing to mask at {:?}", path),
+    }
+    Ok(())
+}
 
-    mount_pts(&mntdir.join("dev/pts")).map_err(err_msg)?;
-    mount_pseudo(&mntdir.join("sys"), "sysfs", "", true).map_err(err_msg)?;
-    mount_pseudo(&mntdir.join("proc"), "proc", "", false).map_err(err_msg)?;
+fn readonly_path(path: &Path) -> Result<(), Error> {
+    if check_pseudo_path(path)?.is_some() {
+        BindMount::new(path, path).mount()
+            .map_err(|e| format_err!("{}", e))?;
+        remount(path, true, &MountOptions::default()).map_err(err_msg)?;
+    }
+    Ok(())
+}
 
-    for &(mp_str, volume) in volumes.iter() {
-        let tmp_mp = PathBuf::from(&mp_str[..]);
-        assert!(tmp_mp.is_absolute());  // should be checked earlier
+fn create_dir(path: &Path, mode: Option<u32>, owner: Option<(u32, u32)>,
+    only_new: bool)
+    -> Result<(), Error>
+{
+    if only_new && metadata(path).is_ok() {
+        return Ok(());
+    }
+    create_dir_all(path)
+        .map_err(|e| format_err!("Error creating {:?}: {}", path, e))?;
+    if let Some((user, group)) = owner {
+        set_file_owner(path, user, group)
+            .map_err(|e| format_err!("Error chowning {:?}: {}", path, e))?;
+    }
+    if let Some(mode) = mode {
+        set_file_mode(path, mode)
+            .map_err(|e| format_err!("Can't chmod {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
 
-        let dest = mntdir.join(relative(&tmp_mp, &root));
-        match volume {
-            &Readonly(ref dir) => {
-                let path = match map_dir(dir, &tree.readonly_paths).or_else(
-                                 || map_dir(dir, &tree.writable_paths)) {
-                    None => {
-                        bail!(concat!("Can't find volume for {},",
-                            " probably missing entry in readonly-paths"),
-                            dir.display());
-                    }
-                    Some(path) => path,
-                };
-                BindMount::new(&path, &dest).mount()
-                    .map_err(|e| format_err!("{}", e))?;
-                mount_ro_recursive(&dest).map_err(err_msg)?;
-            }
-            &Persistent(ref opt) => {
-                let path = match map_dir(&opt.path, &tree.writable_paths) {
-                    None => {
-                        bail!("Can't find volume for {:?}, \
-                            probably missing entry in writable-paths",
-                            opt.path);
-                    }
-                    Some(path) => path,
-                };
-                if metadata(&path).is_err() {
-                    if opt.mkdir {
-                        create_dir_all(&path)
-                            .map_err(|e| format_err!("Error creating \
-                                persistent volume: {}", e))?;
-                        let user = local.map_uid(opt.user)
-                            .ok_or(format_err!(
-                                "Non-mapped user {} for volume {}",
-                                opt.user, mp_str))?;
-                        let group = local.map_gid(opt.group)
-                            .ok_or(format_err!(
-                                "Non-mapped group {} for volume {}",
-                                opt.group, mp_str))?;
-                        set_file_owner(&path, user, group)
-                            .map_err(|e| format_err!("Error chowning \
-                                persistent volume: {}", e))?;
-                        set_file_mode(&path, opt.mode)
-                            .map_err(|e| format_err!("Can't chmod persistent \
-                                volume: {}", e))?;
-                    }
-                }
-                BindMount::new(&path, &dest).mount()
-                    .map_err(|e| format_err!("{}", e))?;
+fn execute(step: &Step, nodes: &mut Vec<Device>) -> Result<(), Error> {
+    match *step {
+        Step::CreateDir { ref path, mode, owner, only_new } => {
+            create_dir(path, mode, owner, only_new)?;
+        }
+        Step::RequireDir { ref path, ref error } => {
+            if metadata(path).is_err() {
+                bail!("Can't create {:?}: {}", path, error);
             }
-            &Tmpfs(ref opt) => {
-                libmount::Tmpfs::new(&dest)
-                    .size_bytes(opt.size).mode(opt.mode)
-                    .mount()
-                    .map_err(|e| format_err!("{}", e))?;
+        }
+        Step::Bind { ref source, ref target } => {
+            BindMount::new(source, target).mount()
+                .map_err(|e| format_err!("{}", e))?;
+        }
+        Step::BindFile { ref source, ref target } => {
+            check_bind_file(source, target)?;
+            BindMount::new(source, target).mount()
+                .map_err(|e| format_err!("{}", e))?;
+        }
+        Step::BindEtcFile { ref source, ref root, ref file, required } => {
+            mount_etc_file(source, root, file, required)?;
+        }
+        Step::Tmpfs { ref target, size, mode, owner } => {
+            let mut tmpfs = libmount::Tmpfs::new(target);
+            tmpfs = tmpfs.size_bytes(size).mode(mode);
+            if let Some((user, group)) = owner {
+                tmpfs = tmpfs.uid(user).gid(group);
             }
-            &Statedir(ref opt) => {
-                let relative_dir = relative(&opt.path, &root);
-                let dir = state_dir.join(&relative_dir);
-                if Path::new(&relative_dir) != Path::new(".") {
-                    create_dir_all(&dir)
-                        .map_err(|e| format_err!("Error creating \
-                            persistent volume: {}", e))?;
-                    let user = local.map_uid(opt.user)
-                        .ok_or(format_err!("Non-mapped user {} for volume {}",
-                            opt.user, mp_str))?;
-                    let group = local.map_gid(opt.group)
-                        .ok_or(format_err!("Non-mapped group {} for volume {}",
-                            opt.group, mp_str))?;
-                    set_file_owner(&dir, user, group)
-                        .map_err(|e| format_err!("Error chowning \
-                            persistent volume: {}", e))?;
-                    set_file_mode(&dir, opt.mode)
-                        .map_err(|e| format_err!("Can't chmod persistent \
-                            volume: {}", e))?;
-                }
-                BindMount::new(&dir, &dest).mount()
-                    .map_err(|e| format_err!("{}", e))?;
+            tmpfs.mount().map_err(|e| format_err!("{}", e))?;
+        }
+        Step::Overlay { ref lower, ref upper, ref work, ref target } => {
+            libmount::Overlay::writable(once(lower.as_path()), upper, work,
+                target)
+                .mount()
+                .map_err(|e| format_err!("{}", e))?;
+        }
+        Step::Pseudo { ref target, ref fstype, readonly } => {
+            mount_pseudo(target, fstype, "", readonly).map_err(err_msg)?;
+        }
+        Step::Devpts { ref target } => {
+            mount_pts(target).map_err(err_msg)?;
+        }
+        Step::Mask { ref target } => {
+            mask_path(target)?;
+        }
+        Step::ReadonlyPath { ref target } => {
+            readonly_path(target)?;
+        }
+        Step::CopyDevfs { ref source, ref target } => {
+            nodes.extend(devices::copy_devfs(source, target)
+                .map_err(|e| format_err!("Error copying {:?}: {}",
+                    source, e))?);
+        }
+        Step::Device { ref source, ref dev_dir } => {
+            for dev in devices::read_devices(&[source.clone()])
+                .map_err(err_msg)?
+            {
+                dev.create(dev_dir)
+                    .map_err(|e| format_err!("Error creating device {:?}: {}",
+                        dev.path, e))?;
+                nodes.push(dev);
             }
         }
+        Step::Image { ref image, ref target, size, mode, owner } => {
+            mount_image(image, target, size, mode, owner)?;
+        }
+        Step::ProjectQuota { ref path, size } => {
+            quota::set_project_quota(path, size)
+                .map_err(|e| format_err!("Error setting quota \
+                    for {:?}: {}", path, e))?;
+        }
+        Step::Remount { ref target, readonly, ref options } => {
+            remount(target, readonly, options).map_err(err_msg)?;
+        }
+        Step::CoreDumps { ref host_dir, ref name, ref root } => {
+            mount_core_dir(host_dir, name, root)?;
+        }
+        Step::QuotaState { ref state_dir, ref quotas } => {
+            // used by lithos_ps to show usage of the volumes
+            quota::write_state(state_dir, quotas)
+                .map_err(|e| format_err!("Can't write {:?}: {}",
+                    quota::STATE_FILE, e))?;
+        }
     }
+    Ok(())
+}
 
-    mount_resolv_conf(&mntdir, local, state_dir)?;
-    mount_hosts_file(&mntdir, local, state_dir)?;
+/// Writes decrypted secrets into the tmpfs mounted by `setup_filesystem`
+pub fn write_secret_files(dir: &Path, opt: &SecretFilesInfo,
+    local: &InstantiatedConfig, values: &BTreeMap<String, String>)
+    -> Result<(), Error>
+{
+    let user = local.map_uid(opt.user)
+        .ok_or(format_err!("Non-mapped user {}", opt.user))?;
+    let group = local.map_gid(opt.group)
+        .ok_or(format_err!("Non-mapped group {}", opt.group))?;
+    for (name, value) in values {
+        SecretFilesInfo::check_name(name).map_err(err_msg)?;
+        let path = dir.join(name);
+        let mut file = OpenOptions::new()
+            .write(true).create_new(true).mode(0o600)
+            .open(&path)
+            .map_err(|e| format_err!("Can't create {:?}: {}", path, e))?;
+        file.write_all(value.as_bytes())
+            .map_err(|e| format_err!("Can't write {:?}: {}", path, e))?;
+        set_file_owner(&path, user, group)
+            .map_err(|e| format_err!("Error chowning {:?}: {}", path, e))?;
+        set_file_mode(&path, opt.mode)
+            .map_err(|e| format_err!("Can't chmod {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
 
-    return Ok(());
+/// Mounts container filesystem
+///
+/// Returns device nodes created in a private `/dev` of the container (empty
+/// if container has no `devices` and uses the shared one)
+pub fn setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
+    name: &str, local: &InstantiatedConfig)
+    -> Result<Vec<Device>, String>
+{
+    _setup_filesystem(master, tree, name, local)
+    .map_err(|e| format!("error setting up filesystem: {}", e))
+}
+
+fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
+    name: &str, local: &InstantiatedConfig)
+    -> Result<Vec<Device>, Error>
+{
+    let plan = MountPlan::new(master, tree, name, local).map_err(err_msg)?;
+    let mut nodes = Vec::new();
+    for step in &plan.steps {
+        debug!("Mount step: {}", step);
+        execute(step, &mut nodes)?;
+    }
+    return Ok(nodes);
 }
@@ -3,22 +3,25 @@ use std::io;
 use std::net::{IpAddr};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::mem::{self, size_of};
-use std::process;
 
 use blake2::{self, Digest};
 use failure::{Error, ResultExt};
 use ipnetwork::IpNetwork;
-use libc::{close};
+use libc::{close, c_int, SOCK_STREAM, SOCK_DGRAM};
 use nix::sched::{setns};
 use nix::sched::CloneFlags;
-use nix::sys::socket::{SockAddr};
-use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::{SockAddr, InetAddr};
 use serde_json::to_vec;
 use unshare::{self, Style};
 
+use lithos::bandwidth::Bandwidth;
 use lithos::child_config::ChildInstance;
-use lithos::container_config::{TcpPort, replace_vars};
-use lithos::sandbox_config::{BridgedNetwork};
+use lithos::container_config::{TcpPort, UdpPort, replace_vars};
+use lithos::netlink::Netlink;
+use lithos::network_policy;
+use lithos::port_forward::{self, PortForward};
+use lithos::range::in_range;
+use lithos::sandbox_config::{BridgedNetwork, SandboxConfig};
 
 
 struct NsGuard {
@@ -49,11 +52,14 @@ impl Drop for NsGuard {
 }
 
 
-pub fn setup(pid: u32, net: &BridgedNetwork, child: &ChildInstance)
+pub fn setup(pid: u32, net: &BridgedNetwork, child: &ChildInstance,
+    bandwidth: &Bandwidth)
     -> Result<(), String>
 {
-    if let Some(ip) = child.ip_address {
-        _setup_bridged(pid, net, ip)
+    let addresses = child.addresses();
+    if addresses.len() > 0 {
+        _setup_bridged(pid, net, &addresses,
+            &bandwidth.capped(&net.bandwidth))
         .map_err(|e| e.to_string())
     } else {
         _setup_isolated(pid)
@@ -61,7 +67,89 @@ pub fn setup(pid: u32, net: &BridgedNetwork, child: &ChildInstance)
     }
 }
 
+/// Installs DNAT rules of published ports
+///
+/// Rules are removed by `lithos_tree` when knot exits (as knot itself is
+/// chrooted into the container by then).
+pub fn publish_ports(name: &str, ports: &[PortForward],
+    sandbox: &SandboxConfig, child: &ChildInstance)
+    -> Result<(), String>
+{
+    if sandbox.bridged_network.is_none() {
+        return Err(format!("published-ports require bridged-network"));
+    }
+    for fwd in ports {
+        if !in_range(&sandbox.allow_tcp_ports, fwd.host_port as u32) {
+            return Err(format!("Published port {} is not in \
+                allow-tcp-ports", fwd.host_port));
+        }
+    }
+    port_forward::install(name, ports,
+        child.ip_address, child.ipv6_address)
+}
+
+/// Installs network policy rules of the container (if there is a policy)
+///
+/// Rules are removed by `lithos_tree` when knot exits, similarly to
+/// published ports.
+pub fn install_policy(name: &str, sandbox: &SandboxConfig,
+    child: &ChildInstance)
+    -> Result<(), String>
+{
+    match (&sandbox.network_policy, &sandbox.bridged_network,
+           interface_address(child))
+    {
+        (&Some(ref policy), &Some(ref net), Some(ip)) => {
+            network_policy::install(name, &interface_name(net, &ip), policy)
+        }
+        _ => Ok(()),
+    }
+}
 
+/// Removes ifb device used for shaping egress traffic when dropped
+pub struct Shaping {
+    ifb: Option<String>,
+}
+
+impl Shaping {
+    pub fn new(bandwidth: &Bandwidth, sandbox: &SandboxConfig,
+        child: &ChildInstance)
+        -> Result<Shaping, String>
+    {
+        match (&sandbox.bridged_network, interface_address(child)) {
+            (&Some(ref net), Some(ip)) => Ok(Shaping {
+                ifb: bandwidth.capped(&net.bandwidth).egress()
+                    .map(|_| ifb_name(&interface_name(net, &ip))),
+            }),
+            _ if bandwidth.is_empty() => Ok(Shaping { ifb: None }),
+            _ => Err(format!("bandwidth limits require bridged-network \
+                and an ip address")),
+        }
+    }
+}
+
+impl Drop for Shaping {
+    fn drop(&mut self) {
+        if let Some(ref ifb) = self.ifb {
+            Netlink::open()
+                .and_then(|mut nl| match nl.link_index(ifb)? {
+                    Some(_) => nl.delete_link(ifb),
+                    None => Ok(()),
+                })
+                .map_err(|e| error!("Error removing {:?}: {}", ifb, e))
+                .ok();
+        }
+    }
+}
+
+fn ifb_name(interface: &str) -> String {
+    interface.replacen("li_", "lb_", 1)
+}
+
+/// Address that the name of the (host side) interface is derived from
+fn interface_address(child: &ChildInstance) -> Option<IpAddr> {
+    child.ip_address.or(child.ipv6_address)
+}
 
 fn interface_name(network: &BridgedNetwork, ip: &IpAddr) -> String {
     #[derive(Serialize)]
@@ -84,126 +172,75 @@ fn interface_name(network: &BridgedNetwork, ip: &IpAddr) -> String {
     return name;
 }
 
-fn _setup_bridged(pid: u32, net: &BridgedNetwork, ip: IpAddr)
+fn _setup_bridged(pid: u32, net: &BridgedNetwork, addresses: &[IpAddr],
+    bandwidth: &Bandwidth)
     -> Result<(), Error>
 {
-    let interface = interface_name(net, &ip);
+    let ip = addresses.iter().find(|a| a.is_ipv4()).cloned();
+    let ipv6 = addresses.iter().find(|a| a.is_ipv6()).cloned();
+    let interface = interface_name(net, &addresses[0]);
     let iinterface = interface.replace("_", "-");
     assert!(iinterface != interface);
 
-    if getifaddrs()?.any(|x| x.interface_name == interface) {
-        let mut cmd = unshare::Command::new("/bin/ip");
-        cmd.arg("link");
-        cmd.arg("del").arg(&interface);
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link del failed: {}", s),
-            Err(e) => bail!("ip link del failed: {}", e),
-        }
+    let mut nl = Netlink::open()?;
+    if nl.link_index(&interface)?.is_some() {
+        nl.delete_link(&interface)?;
     }
     {
         // Create interface in the child namespace
         // This helps to keep parent namespace clean if this process crashes
         // for some reason.
         let ns = NsGuard::enter(pid)?;
-
-        let mut cmd = unshare::Command::new("/bin/ip");
-        cmd.arg("link").arg("add");
-        cmd.arg(&interface);
-        cmd.arg("type").arg("veth");
-        cmd.arg("peer").arg("name").arg(&iinterface);
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link add failed: {}", s),
-            Err(e) => bail!("ip link add failed: {}", e),
-        }
-
+        let mut nl = Netlink::open()?;
+        nl.add_veth(&interface, &iinterface)?;
         // The move just external part of the interface to the parent namespace
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("link").arg("set");
-        cmd.arg("dev").arg(&interface);
-        cmd.arg("netns")
-            .arg(&format!("/proc/{}/fd/{}",
-                          process::id(), ns.parent_raw_fd()));
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link set failed: {}", s),
-            Err(e) => bail!("ip link set failed: {}", e),
-        }
+        nl.set_netns(&interface, ns.parent_raw_fd())?;
     }  // return into parent namespace to add to bridge and up the interface
 
-    let mut cmd = unshare::Command::new("/sbin/brctl");
-    cmd.arg("addif").arg(&net.bridge).arg(&interface);
-    debug!("Running {}", cmd.display(&Style::short()));
-    match cmd.status() {
-        Ok(s) if s.success() => {}
-        Ok(s) => bail!("brctl failed: {}", s),
-        Err(e) => bail!("brctl failed: {}", e),
-    }
+    nl.set_master(&interface, &net.bridge)?;
+    nl.set_up(&interface)?;
 
-    let mut cmd = unshare::Command::new("/sbin/ip");
-    cmd.arg("link").arg("set");
-    cmd.arg(&interface);
-    cmd.arg("up");
-    debug!("Running {}", cmd.display(&Style::short()));
-    match cmd.status() {
-        Ok(s) if s.success() => {}
-        Ok(s) => bail!("ip link up failed: {}", s),
-        Err(e) => bail!("ip link up failed: {}", e),
+    // Traffic going out of the host side of veth is received by container
+    if let Some(limit) = bandwidth.ingress() {
+        nl.set_rate_limit(&interface, limit.rate, limit.burst)?;
+    }
+    if let Some(limit) = bandwidth.egress() {
+        let ifb = ifb_name(&interface);
+        if nl.link_index(&ifb)?.is_some() {
+            nl.delete_link(&ifb)?;
+        }
+        nl.add_ifb(&ifb)?;
+        nl.set_up(&ifb)?;
+        nl.redirect_ingress(&interface, &ifb)?;
+        nl.set_rate_limit(&ifb, limit.rate, limit.burst)?;
     }
 
     {
         // and again to the child to setup internal part and routing
         let _ns = NsGuard::enter(pid)?;
+        let mut nl = Netlink::open()?;
 
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("link").arg("set");
-        cmd.arg("lo").arg("up");
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link up lo failed: {}", s),
-            Err(e) => bail!("ip link up lo failed: {}", e),
+        nl.set_up("lo")?;
+        for &addr in addresses {
+            let network = if addr.is_ipv4() {
+                &net.network
+            } else {
+                net.ipv6_network.as_ref().ok_or_else(|| {
+                    format_err!("ipv6 address {} is set, but there is no \
+                        ipv6-network in sandbox config", addr)
+                })?
+            };
+            nl.add_address(&iinterface,
+                IpNetwork::new(addr, network.prefix())
+                .map_err(|e| format_err!("invalid address {}: {}",
+                                         addr, e))?)?;
         }
-
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("addr").arg("add");
-        cmd.arg(&format!("{}",
-            IpNetwork::new(ip, net.network.prefix())
-            .expect("network asways valid")));
-        cmd.arg("dev").arg(&iinterface);
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link addr failed: {}", s),
-            Err(e) => bail!("ip link addr failed: {}", e),
+        nl.set_up(&iinterface)?;
+        if let (Some(_), Some(gw)) = (ip, net.default_gateway) {
+            nl.add_default_route(gw)?;
         }
-
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("link").arg("set");
-        cmd.arg(&iinterface);
-        cmd.arg("up");
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link child up failed: {}", s),
-            Err(e) => bail!("ip link child up failed: {}", e),
-        }
-
-        if let Some(gw) = net.default_gateway {
-            let mut cmd = unshare::Command::new("/sbin/ip");
-            cmd.arg("route").arg("add");
-            cmd.arg("default");
-            cmd.arg("via").arg(&format!("{}", gw));
-            debug!("Running {}", cmd.display(&Style::short()));
-            match cmd.status() {
-                Ok(s) if s.success() => {}
-                Ok(s) => bail!("ip route failed: {}", s),
-                Err(e) => bail!("ip route failed: {}", e),
-            }
+        if let (Some(_), Some(gw)) = (ipv6, net.ipv6_default_gateway) {
+            nl.add_default_route(gw)?;
         }
 
         if net.after_setup_command.len() > 0 {
@@ -212,7 +249,10 @@ fn _setup_bridged(pid: u32, net: &BridgedNetwork, ip: IpAddr)
                 if item.contains('@') {
                     cmd.arg(&replace_vars(item, |v| {
                         match v {
-                            "container_ip" => ip.to_string(),
+                            "container_ip" => ip.map(|x| x.to_string())
+                                .unwrap_or_else(String::new),
+                            "container_ipv6" => ipv6.map(|x| x.to_string())
+                                .unwrap_or_else(String::new),
                             _ => {
                                 error!("No variable {:?} \
                                         for after-setup-command. \
@@ -239,29 +279,59 @@ fn _setup_bridged(pid: u32, net: &BridgedNetwork, ip: IpAddr)
 
 fn _setup_isolated(child: u32) -> Result<(), Error> {
     let _ns = NsGuard::enter(child)?;
-    let mut cmd = unshare::Command::new("/sbin/ip");
-    cmd.arg("link").arg("set");
-    cmd.arg("lo").arg("up");
-    debug!("Running {}", cmd.display(&Style::short()));
-    match cmd.status() {
-        Ok(s) if s.success() => {}
-        Ok(s) => bail!("ip link up lo failed: {}", s),
-        Err(e) => bail!("ip link up lo failed: {}", e),
-    }
+    Netlink::open()?.set_up("lo")?;
     Ok(())
 }
 
+/// Parameters of the socket opened inside of the container's network
+#[derive(Clone)]
+pub struct Listen {
+    pub fd: RawFd,
+    pub socket_type: c_int,
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+    pub set_non_block: bool,
+    pub listen_backlog: Option<usize>,
+}
+
+impl Listen {
+    pub fn tcp(cfg: &TcpPort) -> Listen {
+        Listen {
+            fd: cfg.fd,
+            socket_type: SOCK_STREAM,
+            reuse_addr: cfg.reuse_addr,
+            reuse_port: cfg.reuse_port,
+            set_non_block: cfg.set_non_block,
+            listen_backlog: Some(cfg.listen_backlog),
+        }
+    }
+    pub fn udp(cfg: &UdpPort) -> Listen {
+        Listen {
+            fd: cfg.fd,
+            socket_type: SOCK_DGRAM,
+            reuse_addr: cfg.reuse_addr,
+            reuse_port: cfg.reuse_port,
+            set_non_block: cfg.set_non_block,
+            listen_backlog: None,
+        }
+    }
+}
+
 /// NOTE!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
 /// This function is executed in restricted child environment
 ///
 /// No allocations, logging, etc. Just bare sys calls
-pub unsafe fn open_socket(cfg: &TcpPort, addr: &SockAddr)
+pub unsafe fn open_socket(cfg: &Listen, addr: &SockAddr)
     -> Result<(), io::Error>
 {
-    use libc::{socket, setsockopt, bind, listen, dup2, AF_INET, SOCK_STREAM};
-    use libc::{SOL_SOCKET, SO_REUSEADDR, SO_REUSEPORT};
+    use libc::{socket, setsockopt, bind, listen, dup2};
+    use libc::{AF_INET, AF_INET6, SOL_SOCKET, SO_REUSEADDR, SO_REUSEPORT};
     use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK, EINTR};
-    let s = match socket(AF_INET, SOCK_STREAM, 0) {
+    let family = match *addr {
+        SockAddr::Inet(InetAddr::V6(..)) => AF_INET6,
+        _ => AF_INET,
+    };
+    let s = match socket(family, cfg.socket_type, 0) {
         -1 => return Err(io::Error::last_os_error()),
         s => s,
     };
@@ -302,8 +372,10 @@ pub unsafe fn open_socket(cfg: &TcpPort, addr: &SockAddr)
     if bind(s, sockaddr, len) == -1 {
         return Err(io::Error::last_os_error());
     }
-    if listen(s, cfg.listen_backlog as i32) == -1 {
-        return Err(io::Error::last_os_error());
+    if let Some(backlog) = cfg.listen_backlog {
+        if listen(s, backlog as i32) == -1 {
+            return Err(io::Error::last_os_error());
+        }
     }
     if s != cfg.fd {
         if dup2(s, cfg.fd) == -1 {
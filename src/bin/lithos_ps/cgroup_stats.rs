@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::{Read, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use libc::pid_t;
+
+use lithos::cgroup::{parse_cgroups, CGroupPath};
+
+
+/// Instance is marked in output when memory usage is above this fraction
+/// of the limit
+pub const MEMORY_WARNING_LEVEL: f64 = 0.9;
+
+// Limits larger than that are treated as no limit (kernel rounds default
+// value of 0x7fffffffffffffff to the page size)
+const UNLIMITED: u64 = 1 << 62;
+
+
+#[derive(Default, Debug, Clone)]
+pub struct CgroupStats {
+    pub memory_usage: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub memory_cache: Option<u64>,
+    pub memory_rss: Option<u64>,
+    pub kernel_memory: Option<u64>,
+    pub memory_failcnt: Option<u64>,
+    pub cpu_usage_ns: Option<u64>,
+    pub cpu_user_ticks: Option<u64>,
+    pub cpu_system_ticks: Option<u64>,
+    pub cpu_periods: Option<u64>,
+    pub cpu_throttled: Option<u64>,
+    pub cpu_throttled_ns: Option<u64>,
+    pub pids: Option<u64>,
+    pub processes: Option<u64>,
+    pub threads: Option<u64>,
+}
+
+impl CgroupStats {
+    /// Fraction of memory limit used (None if there is no limit)
+    pub fn memory_fraction(&self) -> Option<f64> {
+        match (self.memory_usage, self.memory_limit) {
+            (Some(usage), Some(limit)) if limit > 0 && limit < UNLIMITED
+            => Some(usage as f64 / limit as f64),
+            _ => None,
+        }
+    }
+    pub fn near_limit(&self) -> bool {
+        self.memory_fraction()
+            .map(|x| x >= MEMORY_WARNING_LEVEL).unwrap_or(false)
+    }
+}
+
+fn read_value(path: &Path) -> Option<u64> {
+    let mut buf = String::with_capacity(32);
+    File::open(path).and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| debug!("Can't read {:?}: {}", path, e)).ok()?;
+    FromStr::from_str(buf.trim()).ok()
+}
+
+fn count_lines(path: &Path) -> Option<u64> {
+    let f = File::open(path)
+        .map_err(|e| debug!("Can't read {:?}: {}", path, e)).ok()?;
+    let mut count = 0;
+    for line in BufReader::new(f).lines() {
+        line.map_err(|e| debug!("Can't read {:?}: {}", path, e)).ok()?;
+        count += 1;
+    }
+    Some(count)
+}
+
+fn read_keys(path: &Path, mut fun: impl FnMut(&str, u64)) {
+    let f = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(e) => {
+            debug!("Can't read {:?}: {}", path, e);
+            return;
+        }
+    };
+    for line in f.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let mut pair = line.splitn(2, ' ');
+        let key = pair.next().unwrap();
+        if let Some(value) = pair.next().and_then(|x| x.trim().parse().ok()) {
+            fun(key, value);
+        }
+    }
+}
+
+/// Reads statistics of the cgroup the knot process is in
+///
+/// Knot puts itself in the `<cgroup_name>/<sandbox>:<child>.N.scope` group
+/// before running the process so we just look at `/proc/<pid>/cgroup`.
+pub fn read_stats(knot_pid: pid_t) -> Option<CgroupStats> {
+    let groups = parse_cgroups(Some(knot_pid))
+        .map_err(|e| debug!("Can't read cgroups of {}: {}", knot_pid, e))
+        .ok()?;
+    let dir = |name: &str| -> Option<PathBuf> {
+        let CGroupPath(ref folder, ref path) = **groups.by_name.get(name)?;
+        // knot is not in it's own cgroup if `cgroup-name` is null
+        if !path.to_str().map(|x| x.ends_with(".scope")).unwrap_or(false) {
+            return None;
+        }
+        Some(Path::new("/sys/fs/cgroup").join(folder)
+            .join(path.strip_prefix("/").unwrap_or(path)))
+    };
+    let mut stats = CgroupStats::default();
+    if let Some(dir) = dir("memory") {
+        stats.memory_usage = read_value(&dir.join("memory.usage_in_bytes"));
+        stats.memory_limit = read_value(&dir.join("memory.limit_in_bytes"));
+        stats.memory_failcnt = read_value(&dir.join("memory.failcnt"));
+        stats.kernel_memory =
+            read_value(&dir.join("memory.kmem.usage_in_bytes"));
+        read_keys(&dir.join("memory.stat"), |key, value| match key {
+            "total_cache" => stats.memory_cache = Some(value),
+            "total_rss" => stats.memory_rss = Some(value),
+            _ => {}
+        });
+    }
+    if let Some(dir) = dir("cpuacct") {
+        stats.cpu_usage_ns = read_value(&dir.join("cpuacct.usage"));
+        read_keys(&dir.join("cpuacct.stat"), |key, value| match key {
+            "user" => stats.cpu_user_ticks = Some(value),
+            "system" => stats.cpu_system_ticks = Some(value),
+            _ => {}
+        });
+    }
+    if let Some(dir) = dir("cpu") {
+        read_keys(&dir.join("cpu.stat"), |key, value| match key {
+            "nr_periods" => stats.cpu_periods = Some(value),
+            "nr_throttled" => stats.cpu_throttled = Some(value),
+            "throttled_time" => stats.cpu_throttled_ns = Some(value),
+            _ => {}
+        });
+    }
+    if let Some(dir) = dir("pids") {
+        stats.pids = read_value(&dir.join("pids.current"));
+    }
+    // any hierarchy will do, all of them contain the same processes
+    if let Some(dir) = dir("pids").or_else(|| dir("memory")) {
+        stats.processes = count_lines(&dir.join("cgroup.procs"));
+        stats.threads = count_lines(&dir.join("tasks"));
+    }
+    Some(stats)
+}
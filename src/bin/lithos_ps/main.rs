@@ -13,6 +13,7 @@ use regex::Regex;
 use std::rc::Rc;
 use std::io::{stdout, stderr, Write, Read, BufRead};
 use std::io::Error as IoError;
+use std::io::ErrorKind;
 use std::mem::swap;
 use std::fs::File;
 use std::path::{Path, PathBuf};
@@ -24,17 +25,28 @@ use std::default::Default;
 use std::process::exit;
 use std::collections::{BTreeMap, BTreeSet};
 
-use argparse::{ArgumentParser, StoreConst, Print};
+use argparse::{ArgumentParser, StoreConst, StoreOption, Parse, Print};
 use libc::{pid_t, _SC_CLK_TCK, sysconf};
+use quire::{parse_config, Options as QuireOptions};
 
 use lithos::utils::get_time;
+use lithos::ipam;
 use lithos::knot_options;
 use lithos::tree_options;
+use lithos::scheduling::{SchedPolicy, IoPriority};
+use lithos::scheduling::{get_sched_policy, get_ioprio};
+use lithos::master_config::MasterConfig;
+use lithos::quota;
 use ascii::Column;
+use cgroup_stats::{CgroupStats, read_stats};
 use self::LithosInfo::*;
 use self::Action::*;
 
 mod ascii;
+mod cgroup_stats;
+
+/// Volume is marked in output when usage is above this fraction of the size
+const VOLUME_WARNING_LEVEL: f64 = 0.9;
 
 static mut BOOT_TIME: u64 = 0;
 static mut CLOCK_TICKS: u64 = 100;
@@ -43,7 +55,6 @@ struct Options {
     printer_factory: ascii::PrinterFactory,
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
 enum LithosInfo {
     TreeInfo(PathBuf),
     KnotInfo(String, String, usize),
@@ -53,7 +64,6 @@ enum LithosInfo {
 struct Process {
     parent_id: pid_t,
     pid: pid_t,
-    lithos_info: Option<LithosInfo>,
     start_time: u64,
     mem_rss: usize,
     mem_swap: usize,
@@ -62,7 +72,9 @@ struct Process {
     child_user_time: u64,
     child_system_time: u64,
     threads: usize,
-    cmdline: Vec<String>,
+    nice: i32,
+    sched_policy: Option<SchedPolicy>,
+    ioprio: Option<IoPriority>,
 }
 
 #[allow(dead_code)]  // sub-groups are unused, but will be in future
@@ -78,9 +90,17 @@ struct Instance {
     index: usize,
     knot_pid: i32,
     totals: GroupTotals,
+    cgroup: Option<CgroupStats>,
+    volumes: Vec<VolumeUsage>,
     heads: Vec<Group>,
 }
 
+struct VolumeUsage {
+    mount_point: String,
+    size: u64,
+    used: Option<u64>,
+}
+
 #[derive(Default)]
 struct Child {
     totals: GroupTotals,
@@ -181,12 +201,42 @@ fn get_knot_info(pid: pid_t, cmdline: &Vec<String>) -> Result<LithosInfo, ()> {
     return Ok(KnotInfo(group, name, index));
 }
 
+impl Instance {
+    /// Memory usage of the cgroup if available, otherwise sum of processes
+    fn memory(&self) -> usize {
+        self.cgroup.as_ref().and_then(|x| x.memory_usage)
+            .map(|x| x as usize)
+            .unwrap_or(self.totals.memory)
+    }
+}
+
+/// Reads parent pid and executable name from `/proc/<pid>/stat`
+///
+/// This is the only thing read for every process in the system, the rest
+/// is read for lithos processes and their children only.
+fn read_brief(pid: pid_t) -> Result<(pid_t, String), IoError> {
+    let mut buf = String::with_capacity(256);
+    try!(try!(File::open(&Path::new(&format!("/proc/{}/stat", pid))))
+        .read_to_string(&mut buf));
+    // executable name may contain spaces and brackets
+    let (start, end) = match (buf.find('('), buf.rfind(')')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return Err(IoError::new(ErrorKind::InvalidData,
+            format!("bad /proc/{}/stat", pid))),
+    };
+    // fields after the name are: state, ppid
+    let ppid = buf[end+1..].split_whitespace().nth(1)
+        .and_then(|x| FromStr::from_str(x).ok())
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidData,
+            format!("no ppid in /proc/{}/stat", pid)))?;
+    Ok((ppid, buf[start+1..end].to_string()))
+}
+
 fn read_process(pid: pid_t) -> Result<Process, IoError> {
     let f = BufReader::new(try!(File::open(
         &Path::new(&format!("/proc/{}/status", pid)))));
     let mut result: Process = Default::default();
     result.pid = pid;
-    result.cmdline = try!(read_cmdline(pid));
     for line in f.lines() {
         let line = try!(line);
         let mut pair = line[..].splitn(2, ':');
@@ -207,19 +257,6 @@ fn read_process(pid: pid_t) -> Result<Process, IoError> {
                 result.threads = FromStr::from_str(value)
                     .ok().expect("Threads should be integer");
             }
-            "Name" => {
-                match value {
-                    "lithos_tree" => {
-                        result.lithos_info = get_tree_info(
-                            pid, &result.cmdline).ok();
-                    }
-                    "lithos_knot" => {
-                        result.lithos_info = get_knot_info(
-                            pid, &result.cmdline).ok();
-                    }
-                    _ => {}
-                };
-            }
             _ => {}
         }
     }
@@ -235,7 +272,7 @@ fn read_process(pid: pid_t) -> Result<Process, IoError> {
             r"\s+(?:\([^)]*\)|\(\([^)]*\)\))(?:\s+\S+){11}",
             r"\s+(?P<utime>\d+)\s+(?P<stime>\d+)",
             r"\s+(?P<cutime>\d+)\s+(?P<cstime>\d+)",
-            r"(?:\s+\S+){4}",
+            r"\s+\S+\s+(?P<nice>-?\d+)(?:\s+\S+){2}",
             r"\s+(?P<start_time>\d+)\b")).unwrap();
         // TODO(tailhook) we can get executable name from /status and match
         // it here, the executable in /status is escaped!
@@ -251,15 +288,40 @@ fn read_process(pid: pid_t) -> Result<Process, IoError> {
                 .map(|v| result.child_user_time = v).ok();
             FromStr::from_str(c.name("cstime").unwrap().as_str())
                 .map(|v| result.child_system_time = v).ok();
+            FromStr::from_str(c.name("nice").unwrap().as_str())
+                .map(|v| result.nice = v).ok();
         }
         None => {
             warn!("Error getting start_time for pid {}", pid);
         }
     }
 
+    // process might be already dead, or we may have no permissions
+    result.sched_policy = get_sched_policy(pid).ok().and_then(|x| x);
+    result.ioprio = get_ioprio(pid).ok().and_then(|x| x);
+
     return Ok(result);
 }
 
+fn format_sched(prc: &Process) -> Option<String> {
+    let mut items = Vec::new();
+    if prc.nice != 0 {
+        items.push(format!("nice:{}", prc.nice));
+    }
+    match prc.sched_policy {
+        Some(SchedPolicy::Other) | None => {}
+        Some(policy) => items.push(policy.name().to_string()),
+    }
+    if let Some(ref prio) = prc.ioprio {
+        items.push(format!("io:{}", prio));
+    }
+    if items.is_empty() {
+        None
+    } else {
+        Some(format!("({})", items.join(" ")))
+    }
+}
+
 impl GroupTotals {
     fn new(prc: &Process) -> GroupTotals {
         return GroupTotals {
@@ -276,6 +338,34 @@ impl GroupTotals {
             child_system_time: prc.child_system_time,
         };
     }
+    /// Totals of the instance from the cgroup of the knot
+    ///
+    /// Returns `None` if the cgroup doesn't provide enough info, so
+    /// processes have to be scanned instead.
+    fn from_cgroup(stats: &CgroupStats) -> Option<GroupTotals> {
+        let ticks = unsafe { CLOCK_TICKS };
+        // the knot process itself is in the cgroup too
+        let processes = (stats.processes? as usize).saturating_sub(1);
+        let threads = (stats.threads? as usize).saturating_sub(1);
+        let user_time = stats.cpu_user_ticks.unwrap_or(0);
+        let system_time = stats.cpu_system_ticks.unwrap_or(0);
+        let cpu_time = stats.cpu_usage_ns
+            .map(|ns| ns / (1_000_000_000 / ticks))
+            .unwrap_or(user_time + system_time);
+        Some(GroupTotals {
+            processes: processes,
+            threads: threads,
+            memory: stats.memory_usage? as usize,
+            mem_rss: stats.memory_rss.unwrap_or(0) as usize,
+            mem_swap: 0,
+            cpu_time: cpu_time,
+            user_time: user_time,
+            system_time: system_time,
+            // cgroup counters already include exited children
+            child_user_time: 0,
+            child_system_time: 0,
+        })
+    }
     fn add_group(&mut self, group: &GroupTotals) {
         self.processes += group.processes;
         self.threads += group.threads;
@@ -326,15 +416,21 @@ fn format_memory(mem: usize) -> String {
     }
 }
 
+fn _read_head(pid: pid_t) -> Option<Rc<Process>> {
+    read_process(pid)
+        .map_err(|e| info!("Error reading pid {}: {}", pid, e))
+        .ok().map(Rc::new)
+}
+
 fn _scan_group(head: Rc<Process>,
-    all_children: &BTreeMap<pid_t, Vec<Rc<Process>>>)
+    all_children: &BTreeMap<pid_t, Vec<pid_t>>)
     -> Group
 {
     let mut totals = GroupTotals::new(&*head);
     let mut groups = vec!();
     if let Some(children) = all_children.get(&head.pid) {
-        for child in children.iter() {
-            let grp = _scan_group(child.clone(), all_children);
+        for child in children.iter().filter_map(|&pid| _read_head(pid)) {
+            let grp = _scan_group(child, all_children);
             totals.add_group(&grp.totals);
             groups.push(grp);
         }
@@ -346,25 +442,51 @@ fn _scan_group(head: Rc<Process>,
     };
 }
 
-fn scan_processes() -> Result<ScanResult, IoError>
+fn read_state_dir(master_file: &Path) -> Option<PathBuf> {
+    parse_config(master_file, &MasterConfig::validator(),
+                 &QuireOptions::default())
+        .map_err(|e| debug!("Can't read {:?}: {}", master_file, e)).ok()
+        .map(|master: MasterConfig| master.runtime_dir.join(master.state_dir))
+}
+
+fn read_volumes(state_dir: &Path, name: &str, knot_pid: pid_t)
+    -> Vec<VolumeUsage>
+{
+    let quotas = quota::read_state(&state_dir.join(name))
+        .map_err(|e| debug!("Can't read volumes of {:?}: {}", name, e))
+        .unwrap_or_else(|()| Vec::new());
+    quotas.into_iter().map(|q| {
+        let used = quota::volume_usage(knot_pid, &q.mount_point)
+            .map_err(|e| debug!("Can't get usage of {:?} of {:?}: {}",
+                q.mount_point, name, e))
+            .ok().map(|u| u.used);
+        VolumeUsage { mount_point: q.mount_point, size: q.size, used }
+    }).collect()
+}
+
+/// Scans processes of all lithos trees
+///
+/// Totals of each instance are read from its cgroup when possible, in this
+/// case only direct children of the knot are read unless `full` is set
+/// (i.e. per-process totals are needed).
+fn scan_processes(full: bool) -> Result<ScanResult, IoError>
 {
-    let mut children = BTreeMap::<pid_t, Vec<Rc<Process>>>::new();
-    let mut roots = BTreeSet::<Rc<Process>>::new();
+    let mut children = BTreeMap::<pid_t, Vec<pid_t>>::new();
+    let mut roots = BTreeSet::<pid_t>::new();
+    let mut knots = BTreeSet::<pid_t>::new();
 
     scan_dir::ScanDir::dirs().read("/proc", |iter| {
         let pids = iter.filter_map(|(_, name)| FromStr::from_str(&name).ok());
         for pid in pids {
-            match read_process(pid) {
-                Ok(prc) => {
-                    let prc = Rc::new(prc);
-                    if let Some(TreeInfo(_)) = prc.lithos_info {
-                        roots.insert(prc.clone());
-                    }
-                    if let Some(vec) = children.get_mut(&prc.parent_id) {
-                        vec.push(prc);
-                        continue;
+            match read_brief(pid) {
+                Ok((parent_id, name)) => {
+                    match &name[..] {
+                        "lithos_tree" => { roots.insert(pid); }
+                        "lithos_knot" => { knots.insert(pid); }
+                        _ => {}
                     }
-                    children.insert(prc.parent_id, vec!(prc));
+                    children.entry(parent_id).or_insert_with(Vec::new)
+                        .push(pid);
                 }
                 Err(e) => {
                     info!("Error reading pid {}: {}", pid, e);
@@ -377,26 +499,45 @@ fn scan_processes() -> Result<ScanResult, IoError>
 
     let mut masters = BTreeMap::new();
     let mut totals: GroupTotals = Default::default();
+    let no_children = Vec::new();
 
-    for root in roots.iter() {
-        let cfg_file = if let Some(TreeInfo(ref cfg_file)) = root.lithos_info {
-            cfg_file
-        } else {
-            continue;
+    for &root in roots.iter() {
+        let cfg_file = match read_cmdline(root).ok()
+            .and_then(|cmdline| get_tree_info(root, &cmdline).ok())
+        {
+            Some(TreeInfo(cfg_file)) => cfg_file,
+            _ => continue,
         };
+        let state_dir = read_state_dir(&cfg_file);
         let mut trees = BTreeMap::<String, Tree>::new();
         let mut mtotals: GroupTotals = Default::default();
-        for prc in children.get(&root.pid).unwrap_or(&Vec::new()).iter() {
-            if let Some(KnotInfo(ref sub, ref name, idx)) = prc.lithos_info {
+        for &knot_pid in children.get(&root).unwrap_or(&no_children).iter()
+            .filter(|pid| knots.contains(pid))
+        {
+            let info = read_cmdline(knot_pid).ok()
+                .and_then(|cmdline| get_knot_info(knot_pid, &cmdline).ok());
+            if let Some(KnotInfo(ref sub, ref name, idx)) = info {
+                let cgroup = read_stats(knot_pid);
+                let cgroup_totals = cgroup.as_ref()
+                    .and_then(GroupTotals::from_cgroup);
                 let mut heads = vec!();
                 let mut ktotals: GroupTotals = Default::default();
-                if let Some(knot_children) = children.get(&prc.pid) {
-                    for child in knot_children.iter() {
-                        let grp = _scan_group(child.clone(), &children);
-                        ktotals.add_group(&grp.totals);
-                        heads.push(grp);
-                    }
+                for head in children.get(&knot_pid).unwrap_or(&no_children)
+                    .iter().filter_map(|&pid| _read_head(pid))
+                {
+                    let grp = if full || cgroup_totals.is_none() {
+                        _scan_group(head, &children)
+                    } else {
+                        Group {
+                            totals: GroupTotals::new(&*head),
+                            head: head,
+                            groups: vec!(),
+                        }
+                    };
+                    ktotals.add_group(&grp.totals);
+                    heads.push(grp);
                 }
+                let ktotals = cgroup_totals.unwrap_or(ktotals);
                 mtotals.add_group(&ktotals);
                 if !trees.contains_key(sub) {
                     trees.insert(sub.clone(), Default::default());
@@ -410,11 +551,16 @@ fn scan_processes() -> Result<ScanResult, IoError>
                         let mut nheads = vec!();
                         swap(&mut nheads, &mut heads);
                         child.totals.add_group(&ktotals);
+                        let name_idx = format!("{}/{}.{}", sub, name, idx);
                         child.instances.insert(idx, Instance {
-                            name: format!("{}/{}.{}", sub, name, idx),
-                            knot_pid: prc.pid,
+                            name: name_idx.clone(),
+                            knot_pid: knot_pid,
                             index: idx,
                             totals: ktotals,
+                            cgroup: cgroup,
+                            volumes: state_dir.as_ref().map(|dir| {
+                                read_volumes(dir, &name_idx, knot_pid)
+                            }).unwrap_or_else(Vec::new),
                             heads: nheads,
                         });
                     });
@@ -422,9 +568,9 @@ fn scan_processes() -> Result<ScanResult, IoError>
             }
         }
         totals.add_group(&mtotals);
-        masters.insert(root.pid, Master {
-            pid: root.pid,
-            config: cfg_file.clone(),
+        masters.insert(root, Master {
+            pid: root,
+            config: cfg_file,
             trees: trees,
             totals: mtotals,
             });
@@ -436,18 +582,62 @@ fn scan_processes() -> Result<ScanResult, IoError>
     });
 }
 
+fn format_cgroup(prn: ascii::Printer, inst: &Instance) -> ascii::Printer {
+    let stats = match inst.cgroup {
+        Some(ref stats) => stats,
+        None => return prn,
+    };
+    let mut prn = match stats.memory_fraction() {
+        Some(frac) if stats.near_limit() => {
+            prn.red(format!("{:.0}%!", frac*100.))
+        }
+        Some(frac) => prn.blue(format!("{:.0}%", frac*100.)),
+        None => prn,
+    };
+    if let Some(throttled) = stats.cpu_throttled {
+        if throttled > 0 {
+            prn = prn.red(format!("throttled:{}", throttled));
+        }
+    }
+    prn
+}
+
+fn format_volumes(mut prn: ascii::Printer, inst: &Instance)
+    -> ascii::Printer
+{
+    for vol in &inst.volumes {
+        let used = match vol.used {
+            Some(used) => used,
+            None => continue,
+        };
+        let text = format!("{}:{}/{}", vol.mount_point,
+            format_memory(used as usize), format_memory(vol.size as usize));
+        if used as f64 >= vol.size as f64 * VOLUME_WARNING_LEVEL {
+            prn = prn.red(format!("{}!", text));
+        } else {
+            prn = prn.blue(text);
+        }
+    }
+    prn
+}
+
 fn print_instance(inst: &Instance, opt: &Options) -> ascii::TreeNode {
     let label = if inst.heads.len() == 1 {
         let ref prc = inst.heads[0].head;
-        opt.printer_factory.new()
+        let mut prn = opt.printer_factory.new()
             .green(&prc.pid)
             .norm(&inst.name)
             .map(|p| format_uptime(p, prc.start_time))
             .blue(&format!("[{}/{}]",
                            inst.totals.processes,
                            inst.totals.threads))
-            .blue(&format_memory(inst.totals.memory))
-            .unwrap()
+            .blue(&format_memory(inst.memory()))
+            .map(|p| format_cgroup(p, inst))
+            .map(|p| format_volumes(p, inst));
+        if let Some(sched) = format_sched(prc) {
+            prn = prn.norm(&sched);
+        }
+        prn.unwrap()
     } else {
         let mut prn = opt.printer_factory.new()
             .red(&format!("({})", inst.knot_pid))
@@ -460,7 +650,9 @@ fn print_instance(inst: &Instance, opt: &Options) -> ascii::TreeNode {
                 .blue(&format!("[{}/{}]",
                                inst.totals.processes,
                                inst.totals.threads))
-                .blue(&format_memory(inst.totals.memory))
+                .blue(&format_memory(inst.memory()))
+                .map(|p| format_cgroup(p, inst))
+                .map(|p| format_volumes(p, inst))
         }
         prn.unwrap()
     };
@@ -558,13 +750,37 @@ fn print_json(scan: ScanResult, _opt: &Options) -> Result<(), IoError> {
                     "system_time": grp.totals.system_time,
                     "child_user_time": grp.totals.child_user_time,
                     "child_system_time": grp.totals.child_system_time,
+                    "nice": grp.head.nice,
+                    "sched_policy": grp.head.sched_policy.map(|x| x.name()),
+                    "ioprio": grp.head.ioprio.map(|x| x.to_string()),
                     }));
             }
+            let cgroup = instance.cgroup.as_ref().map(|cg| json!({
+                "memory_usage": cg.memory_usage,
+                "memory_limit": cg.memory_limit,
+                "memory_cache": cg.memory_cache,
+                "memory_rss": cg.memory_rss,
+                "kernel_memory": cg.kernel_memory,
+                "memory_failcnt": cg.memory_failcnt,
+                "memory_near_limit": cg.near_limit(),
+                "cpu_usage_ns": cg.cpu_usage_ns,
+                "cpu_periods": cg.cpu_periods,
+                "cpu_throttled": cg.cpu_throttled,
+                "cpu_throttled_ns": cg.cpu_throttled_ns,
+                "pids": cg.pids,
+            }));
+            let volumes = instance.volumes.iter().map(|v| json!({
+                "mount_point": v.mount_point,
+                "size": v.size,
+                "used": v.used,
+            })).collect::<Vec<_>>();
             knots.push(json!({
                 "name": instance.name.to_string(),
                 "pid": instance.knot_pid,
                 "ok": instance.heads.len() == 1,
                 "processes": processes,
+                "cgroup": cgroup,
+                "volumes": volumes,
             }));
         }
         trees.push(json!({
@@ -592,7 +808,7 @@ fn monitor_changes(scan: ScanResult, _opt: &Options) -> Result<(), IoError> {
     loop {
         sleep(Duration::new(1, 0));
 
-        let new_children: BTreeMap<String, Instance> = try!(scan_processes())
+        let new_children: BTreeMap<String, Instance> = try!(scan_processes(false))
             .masters.into_iter()
             .flat_map(|(_, master)| master.trees.into_iter())
             .flat_map(|(_, tree)| tree.children.into_iter())
@@ -608,21 +824,45 @@ fn monitor_changes(scan: ScanResult, _opt: &Options) -> Result<(), IoError> {
         let mut mem = vec!();
         let mut threads = vec!();
         let mut processes = vec!();
+        let mut mem_percent = vec!();
+        let mut throttled = vec!();
         for (name, inst) in new_children.iter() {
             if inst.heads.len() == 1 {
                 pids.push(inst.heads[0].head.pid as usize);
             } else {
                 pids.push(0);
             }
-            let ticks = old_children.get(name)
-                .map(|old| inst.totals.cpu_time
-                           .saturating_sub(old.totals.cpu_time))
-                .unwrap_or(0);
+            let old = old_children.get(name);
+            let cpu = match (
+                inst.cgroup.as_ref().and_then(|x| x.cpu_usage_ns),
+                old.and_then(|x| x.cgroup.as_ref())
+                    .and_then(|x| x.cpu_usage_ns))
+            {
+                (Some(new), Some(old)) => {
+                    let delta_ns = (new_time - old_time) * 1e9;
+                    (new.saturating_sub(old) as f64/delta_ns) * 100.
+                }
+                _ => {
+                    let ticks = old
+                        .map(|old| inst.totals.cpu_time
+                                   .saturating_sub(old.totals.cpu_time))
+                        .unwrap_or(0);
+                    (ticks as f64/delta_ticks) * 100.
+                }
+            };
             names.push(inst.name.to_string());
-            cpus.push((ticks as f64/delta_ticks) * 100.);
+            cpus.push(cpu);
             threads.push(inst.totals.threads);
-            processes.push(inst.totals.processes);
-            mem.push(inst.totals.memory);
+            processes.push(inst.cgroup.as_ref().and_then(|x| x.pids)
+                .map(|x| x as usize)
+                .unwrap_or(inst.totals.processes));
+            mem.push(inst.memory());
+            mem_percent.push(inst.cgroup.as_ref()
+                .and_then(|x| x.memory_fraction())
+                .map(|x| x*100.).unwrap_or(0.));
+            throttled.push(inst.cgroup.as_ref()
+                .and_then(|x| x.cpu_throttled)
+                .map(|x| x as usize).unwrap_or(0));
         }
 
         print!("\x1b[2J\x1b[;H");
@@ -633,6 +873,8 @@ fn monitor_changes(scan: ScanResult, _opt: &Options) -> Result<(), IoError> {
             ("THR", Column::Ordinal(threads)),
             ("PRC", Column::Ordinal(processes)),
             ("MEM", Column::Bytes(mem)),
+            ("MEM%", Column::Percent(mem_percent)),
+            ("THROT", Column::Ordinal(throttled)),
             ]);
 
         old_children = new_children;
@@ -640,6 +882,61 @@ fn monitor_changes(scan: ScanResult, _opt: &Options) -> Result<(), IoError> {
     }
 }
 
+fn read_runtime_dir(master_file: &Path) -> Result<PathBuf, IoError> {
+    parse_config(master_file, &MasterConfig::validator(),
+                 &QuireOptions::default())
+        .map_err(|e| IoError::new(ErrorKind::Other,
+            format!("can't read {:?}: {}", master_file, e)))
+        .map(|master: MasterConfig| master.runtime_dir)
+}
+
+fn running_instances(scan: ScanResult) -> BTreeSet<String> {
+    scan.masters.into_iter()
+        .flat_map(|(_, master)| master.trees.into_iter())
+        .flat_map(|(_, tree)| tree.children.into_iter())
+        .flat_map(|(_, child)| child.instances.into_iter())
+        .map(|(_, inst)| inst.name)
+        .collect()
+}
+
+fn print_leases(scan: ScanResult, master_file: &Path)
+    -> Result<(), IoError>
+{
+    let runtime_dir = read_runtime_dir(master_file)?;
+    let leases = ipam::read_leases(&runtime_dir)
+        .map_err(|e| IoError::new(ErrorKind::Other, e))?;
+    let running = running_instances(scan);
+    ascii::render_table(&[
+        ("NAME", Column::Text(leases.iter()
+            .map(|l| l.name.clone()).collect())),
+        ("ADDRESS", Column::Text(leases.iter()
+            .map(|l| l.address.to_string()).collect())),
+        ("STATE", Column::Text(leases.iter()
+            .map(|l| if running.contains(&l.name) {
+                "running".to_string()
+            } else {
+                "stopped".to_string()
+            }).collect())),
+        ]);
+    Ok(())
+}
+
+fn release_lease(scan: ScanResult, master_file: &Path, name: &str)
+    -> Result<(), IoError>
+{
+    let runtime_dir = read_runtime_dir(master_file)?;
+    if running_instances(scan).contains(name) {
+        return Err(IoError::new(ErrorKind::Other,
+            format!("{:?} is running, remove it from config first", name)));
+    }
+    match ipam::release(&runtime_dir, name) {
+        Ok(Some(ip)) => println!("Released {} of {:?}", ip, name),
+        Ok(None) => println!("No lease for {:?}", name),
+        Err(e) => return Err(IoError::new(ErrorKind::Other, e)),
+    }
+    Ok(())
+}
+
 fn read_global_consts() {
     unsafe {
         CLOCK_TICKS = sysconf(_SC_CLK_TCK) as u64;
@@ -662,6 +959,7 @@ enum Action {
     PrintFullTree,
     PrintJson,
     MonitorChanges,
+    PrintLeases,
 }
 
 fn main() {
@@ -670,6 +968,8 @@ fn main() {
     read_global_consts();
 
     let mut action = PrintFullTree;
+    let mut master_file = PathBuf::from("/etc/lithos/master.yaml");
+    let mut release: Option<String> = None;
     let mut options = Options {
         printer_factory: ascii::Printer::color_factory(),
     };
@@ -680,7 +980,20 @@ fn main() {
             .add_option(&["--json"], StoreConst(PrintJson),
                 "Print big json instead human-readable tree")
             .add_option(&["--monitor"], StoreConst(MonitorChanges),
-                "Print big json instead human-readable tree");
+                "Print big json instead human-readable tree")
+            .add_option(&["--leases"], StoreConst(PrintLeases),
+                "Print addresses allocated from `ip-pool` of sandboxes");
+        ap.refer(&mut release)
+            .add_option(&["--release-lease"], StoreOption,
+                "Release address allocated to the (stopped) instance, \
+                 so it can be allocated to another one")
+            .metavar("SANDBOX/CHILD.N");
+        ap.refer(&mut master_file)
+            .add_option(&["--master"], Parse,
+                "Name of the master configuration file, used for \
+                 `--leases` and `--release-lease` \
+                 (default /etc/lithos/master.yaml)")
+            .metavar("FILE");
         ap.refer(&mut options.printer_factory)
             .add_option(&["--force-color"],
                 StoreConst(ascii::Printer::color_factory()),
@@ -699,9 +1012,17 @@ fn main() {
             }
         }
     }
-    match scan_processes().and_then(|s| {
+    let full = match action {
+        PrintJson => true,
+        _ => false,
+    };
+    match scan_processes(full).and_then(|s| {
+        if let Some(ref name) = release {
+            return release_lease(s, &master_file, name);
+        }
         match action {
             PrintFullTree => print_full_tree(s, &options),
+            PrintLeases => print_leases(s, &master_file),
             PrintJson => print_json(s, &options),
             MonitorChanges => monitor_changes(s, &options),
         }
@@ -17,8 +17,13 @@ extern crate unshare;
 
 
 use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::mem::size_of;
 use std::mem::replace;
 use std::fs::{File, OpenOptions, metadata, remove_file, rename};
+use std::fs::{set_permissions, Permissions};
+use std::fs::symlink_metadata;
 use std::io::{self, stderr, Read, Write};
 use std::str::{FromStr};
 use std::fs::{remove_dir, read_dir};
@@ -27,18 +32,20 @@ use std::path::{Path, PathBuf};
 use std::time::{SystemTime, Instant, Duration};
 use std::process::exit;
 use std::collections::{HashMap, BTreeMap, HashSet};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt, PermissionsExt};
 use std::os::unix::io::{RawFd, AsRawFd};
 
 use failure::Error;
 use humantime::format_rfc3339_seconds;
-use libc::{close};
+use libc::{close, fchownat, AT_EMPTY_PATH, c_int, c_void, getsockopt, socklen_t};
+use libc::{SOL_SOCKET, SO_TYPE, SOCK_STREAM, SOCK_DGRAM};
 use nix::fcntl::{fcntl, FdFlag, OFlag, F_GETFD, F_SETFD, F_GETFL, F_SETFL};
 use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD};
 use nix::sys::signal::{kill, Signal};
 use nix::sys::socket::{getsockname, SockAddr};
 use nix::sys::socket::{setsockopt, bind, listen};
 use nix::sys::socket::{socket, AddressFamily, SockType, InetAddr};
-use nix::sys::socket::{SockFlag};
+use nix::sys::socket::{SockFlag, UnixAddr};
 use nix::sys::socket::sockopt::{ReuseAddr, ReusePort};
 use nix::unistd::{Pid, getpid};
 use quire::{parse_config, Options as COptions};
@@ -52,11 +59,18 @@ use lithos::MAX_CONFIG_LOGS;
 use lithos::cgroup;
 use lithos::child_config::ChildConfig;
 use lithos::child_config::ChildKind::Daemon;
-use lithos::container_config::{ContainerConfig, TcpPort, DEFAULT_KILL_TIMEOUT};
-use lithos::container_config::{InstantiatedConfig, Variables};
+use lithos::container_config::{ContainerConfig, DEFAULT_KILL_TIMEOUT};
+use lithos::container_config::UnixSocketType;
+use lithos::container_config::{InstantiatedConfig, Variables, Volume};
 use lithos::id_map::IdMapExt;
+use lithos::ipam;
+use lithos::knot_options::{REMAP_FDS_VAR, format_fd_map};
 use lithos::master_config::{MasterConfig, create_master_dirs};
 use lithos::metrics;
+use lithos::network_policy;
+use lithos::port_forward;
+use lithos::quota;
+use lithos::range::in_range;
 use lithos::sandbox_config::SandboxConfig;
 use lithos::setup::{clean_child, init_logging};
 use lithos::timer_queue::Queue;
@@ -71,6 +85,7 @@ mod args;
 
 
 pub const CONFIG_LOG_SIZE: u64 = 10_485_760;
+const VOLUME_METRICS_INTERVAL: Duration = Duration::from_secs(60);
 
 struct Process {
     restart_min: Instant,
@@ -79,7 +94,7 @@ struct Process {
     base_name: (String, String),
     config: String,
     inner_config: InstantiatedConfig,
-    addresses: Vec<InetAddr>,
+    listeners: Vec<(Listener, SocketOptions)>,
     socket_cred: (u32, u32),
     bridged_network: bool,
 }
@@ -88,6 +103,26 @@ struct Socket {
     fd: RawFd,
 }
 
+/// Address of the socket opened by lithos_tree for the child
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Listener {
+    Tcp(InetAddr),
+    Udp(InetAddr),
+    Unix(PathBuf, UnixSocketType),
+}
+
+#[derive(Debug, Clone)]
+struct SocketOptions {
+    fd: RawFd,
+    external: bool,
+    reuse_addr: bool,
+    reuse_port: bool,
+    set_non_block: bool,
+    listen_backlog: Option<usize>,
+    mode: u32,
+    owner: Option<(u32, u32)>,
+}
+
 enum Child {
     Process(Process),
     Unidentified(String),
@@ -107,6 +142,21 @@ impl Child {
     }
 }
 
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Listener::Tcp(ref addr) => write!(f, "{}", addr),
+            Listener::Udp(ref addr) => write!(f, "udp:{}", addr),
+            Listener::Unix(ref path, UnixSocketType::Stream) => {
+                write!(f, "unix:{}", path.display())
+            }
+            Listener::Unix(ref path, UnixSocketType::Datagram) => {
+                write!(f, "unix-dgram:{}", path.display())
+            }
+        }
+    }
+}
+
 impl AsRawFd for Socket {
     fn as_raw_fd(&self) -> RawFd {
         self.fd
@@ -226,33 +276,60 @@ fn check_process(cfg: &MasterConfig) -> Result<(), String> {
     return Ok(());
 }
 
-fn recover_sockets(sockets: &mut HashMap<InetAddr, Socket>) {
+fn socket_type(fd: RawFd) -> Option<c_int> {
+    let mut val: c_int = 0;
+    let mut len = size_of::<c_int>() as socklen_t;
+    let res = unsafe {
+        getsockopt(fd, SOL_SOCKET, SO_TYPE,
+            &mut val as *mut c_int as *mut c_void, &mut len)
+    };
+    if res == 0 { Some(val) } else { None }
+}
+
+fn recover_sockets(sockets: &mut HashMap<Listener, Socket>) {
     scan_dir::ScanDir::all().read("/proc/self/fd", |iter| {
         let fds = iter
             .filter_map(|(_, name)| FromStr::from_str(&name).ok())
             .filter(|&x| x >= 3);
         for fd in fds {
-            match getsockname(fd) {
-                Ok(SockAddr::Inet(addr)) => {
-                    let sock = Socket {
-                        fd: fd,
-                    };
-                    match sockets.insert(addr, sock) {
-                        None => {
-                            info!("Recovered fd {} as {}", fd, addr);
-                        }
-                        Some(old) => {
-                            error!("Address {} has two sockets: \
-                                fd={} and fd={}, discarding latter.",
-                                addr, fd, old.fd);
-                        }
-                    }
+            let listener = match (getsockname(fd), socket_type(fd)) {
+                (Ok(SockAddr::Inet(addr)), Some(SOCK_STREAM)) => {
+                    Listener::Tcp(addr)
+                }
+                (Ok(SockAddr::Inet(addr)), Some(SOCK_DGRAM)) => {
+                    Listener::Udp(addr)
                 }
-                Ok(_) => {
+                (Ok(SockAddr::Unix(ref addr)), Some(typ))
+                    if addr.path().is_some() &&
+                       (typ == SOCK_STREAM || typ == SOCK_DGRAM)
+                => {
+                    Listener::Unix(addr.path().unwrap().to_path_buf(),
+                        if typ == SOCK_STREAM {
+                            UnixSocketType::Stream
+                        } else {
+                            UnixSocketType::Datagram
+                        })
+                }
+                (Ok(_), _) => {
                     debug!("Fd {} is different kind of socket", fd);
+                    continue;
                 }
-                Err(_) => {
+                (Err(_), _) => {
                     debug!("Fd {} is not a socket", fd);
+                    continue;
+                }
+            };
+            let sock = Socket {
+                fd: fd,
+            };
+            match sockets.insert(listener.clone(), sock) {
+                None => {
+                    info!("Recovered fd {} as {}", fd, listener);
+                }
+                Some(old) => {
+                    error!("Address {} has two sockets: \
+                        fd={} and fd={}, discarding latter.",
+                        listener, fd, old.fd);
                 }
             }
         }
@@ -487,6 +564,19 @@ fn run(config_file: &Path, options: &Options)
             metrics::Process::new());
     }
 
+    for (_, pro) in &configs {
+        for (mp, volume) in &pro.inner_config.volumes {
+            match *volume {
+                Volume::Persistent(ref opt) if opt.size.is_some() => {
+                    metrics.volumes.insert(
+                        (pro.name.clone(), mp.clone()),
+                        metrics::Volume::new());
+                }
+                _ => {}
+            }
+        }
+    }
+
     // read counters so that we don't miss events in case lithos restarts
     // too often
     let _metrics = libcantal::start_with_reading(&metrics);
@@ -515,6 +605,16 @@ fn run(config_file: &Path, options: &Options)
 
         info!("Removing Dangling State Dirs");
         remove_dangling_state_dirs(&recovered, &master);
+
+        info!("Removing Dangling Port Forwards");
+        port_forward::remove_stale(|name| recovered.contains(name))
+            .map_err(|e| error!("Error cleaning port forwards: {}", e))
+            .ok();
+
+        info!("Removing Dangling Network Policies");
+        network_policy::remove_stale(|name| recovered.contains(name))
+            .map_err(|e| error!("Error cleaning network policies: {}", e))
+            .ok();
     }
 
     {
@@ -550,49 +650,157 @@ fn run(config_file: &Path, options: &Options)
     return Ok(());
 }
 
-fn close_unused_sockets(sockets: &mut HashMap<InetAddr, Socket>,
+fn close_unused_sockets(sockets: &mut HashMap<Listener, Socket>,
                         children: &HashMap<Pid, Child>)
 {
     let empty = Vec::new();
-    let used_addresses: HashSet<InetAddr> = children.values().flat_map(|ch| {
-        match ch {
-            &Child::Process(ref p) => p.addresses.iter().cloned(),
-            &Child::Unidentified(_) => empty.iter().cloned(),
-        }
-    }).collect();
+    let used_addresses: HashSet<&Listener> = children.values()
+        .flat_map(|ch| {
+            match ch {
+                &Child::Process(ref p) => p.listeners.iter(),
+                &Child::Unidentified(_) => empty.iter(),
+            }
+        })
+        .map(|&(ref listener, _)| listener)
+        .collect();
     *sockets = replace(sockets, HashMap::new())
-        .into_iter().filter(|&(p, ref s)| {
-            if used_addresses.contains(&p) {
+        .into_iter().filter(|&(ref p, ref s)| {
+            if used_addresses.contains(p) {
                 true
             } else {
                 info!("Closing fd {} addr {}", s.fd, p);
                 unsafe { close(s.fd) };
+                if let Listener::Unix(ref path, _) = *p {
+                    remove_file(path)
+                        .map_err(|e| warn!("Can't remove socket {:?}: {}",
+                            path, e))
+                        .ok();
+                }
                 false
             }
         }).collect();
 }
 
-fn open_socket(addr: InetAddr, cfg: &TcpPort, uid: u32, gid: u32)
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    match symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {
+            remove_file(path)
+                .map_err(|e| format_err!("Can't remove stale socket {:?}: {}",
+                    path, e))
+        }
+        Ok(_) => bail!("File {:?} exists and is not a socket", path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => bail!("Can't stat {:?}: {}", path, e),
+    }
+}
+
+/// Opens `O_PATH` handle of the freshly bound socket without following
+/// symlinks, so that the file can be chmod'ed safely
+fn socket_handle(path: &Path) -> Result<File, Error> {
+    let file = OpenOptions::new().read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| format_err!("Can't open {:?}: {}", path, e))?;
+    let meta = file.metadata()
+        .map_err(|e| format_err!("Can't stat {:?}: {}", path, e))?;
+    if !meta.file_type().is_socket() {
+        bail!("{:?} was replaced after bind", path);
+    }
+    Ok(file)
+}
+
+fn open_socket(listener: &Listener, opt: &SocketOptions, uid: u32, gid: u32)
     -> Result<RawFd, Error>
 {
+    let inet_family = |addr: &InetAddr| match *addr {
+        InetAddr::V4(..) => AddressFamily::Inet,
+        InetAddr::V6(..) => AddressFamily::Inet6,
+    };
+    let (family, typ, addr) = match *listener {
+        Listener::Tcp(ref addr) => {
+            (inet_family(addr), SockType::Stream, SockAddr::Inet(*addr))
+        }
+        Listener::Udp(ref addr) => {
+            (inet_family(addr), SockType::Datagram, SockAddr::Inet(*addr))
+        }
+        Listener::Unix(ref path, kind) => {
+            let addr = UnixAddr::new(path)
+                .map_err(|e| format_err!("Bad socket path {:?}: {}",
+                    path, e))?;
+            let typ = match kind {
+                UnixSocketType::Stream => SockType::Stream,
+                UnixSocketType::Datagram => SockType::Datagram,
+            };
+            (AddressFamily::Unix, typ, SockAddr::Unix(addr))
+        }
+    };
 
-    let sock = {
+    let (sock, handle) = {
         let _fsuid_guard = utils::FsUidGuard::set(uid, gid);
-        try!(socket(AddressFamily::Inet, SockType::Stream,
+        let sock = try!(socket(family, typ,
                     SockFlag::SOCK_CLOEXEC, None)
-            .map_err(|e| format_err!("Can't create socket: {:?}", e)))
+            .map_err(|e| format_err!("Can't create socket: {:?}", e)));
+        if let Listener::Unix(ref path, _) = *listener {
+            // Unix socket is bound with the permissions of the user, so
+            // it can only be created where the user has access
+            let res = remove_stale_socket(path).and_then(|()| {
+                bind(sock, &addr).map_err(|e| {
+                    format_err!("Can't bind {:?}: {:?}", path, e)
+                })
+            }).and_then(|()| socket_handle(path));
+            match res {
+                Ok(handle) => (sock, Some(handle)),
+                Err(e) => {
+                    unsafe { close(sock) };
+                    return Err(e);
+                }
+            }
+        } else {
+            (sock, None)
+        }
     };
+    if let Some(handle) = handle {
+        // the directory is writable by user, so chown and chmod the file we
+        // have just bound rather than whatever is at the path now
+        if let Some((uid, gid)) = opt.owner {
+            let empty = CString::new("").expect("no null bytes");
+            if unsafe { fchownat(handle.as_raw_fd(), empty.as_ptr(),
+                                 uid, gid, AT_EMPTY_PATH) } != 0
+            {
+                let e = io::Error::last_os_error();
+                unsafe { close(sock) };
+                bail!("Can't chown {:?}: {}", listener, e);
+            }
+        }
+        let fd_path = format!("/proc/self/fd/{}", handle.as_raw_fd());
+        if let Err(e) = set_permissions(&fd_path,
+            Permissions::from_mode(opt.mode & 0o7777))
+        {
+            unsafe { close(sock) };
+            bail!("Can't chmod {:?}: {}", listener, e);
+        }
+    }
 
     let mut result = Ok(());
-    if cfg.reuse_addr {
-        result = result.and_then(|_| setsockopt(sock, ReuseAddr, &true));
+    match *listener {
+        Listener::Tcp(..) | Listener::Udp(..) => {
+            if opt.reuse_addr {
+                result = result.and_then(|_| {
+                    setsockopt(sock, ReuseAddr, &true)
+                });
+            }
+            if opt.reuse_port {
+                result = result.and_then(|_| {
+                    setsockopt(sock, ReusePort, &true)
+                });
+            }
+            result = result.and_then(|_| bind(sock, &addr));
+        }
+        Listener::Unix(..) => {}  // bound above
     }
-    if cfg.reuse_port {
-        result = result.and_then(|_| setsockopt(sock, ReusePort, &true));
+    if let Some(backlog) = opt.listen_backlog {
+        result = result.and_then(|_| listen(sock, backlog));
     }
-    result = result.and_then(|_| bind(sock, &SockAddr::Inet(addr)));
-    result = result.and_then(|_| listen(sock, cfg.listen_backlog));
-    result = result.and_then(|_| listen(sock, cfg.listen_backlog));
     // Only reset cloexec flag when socket is fully ready
     result = result
         .and_then(|_| fcntl(sock, F_GETFD))
@@ -600,7 +808,7 @@ fn open_socket(addr: InetAddr, cfg: &TcpPort, uid: u32, gid: u32)
             FdFlag::from_bits(flags).expect("os returned valid flags")
             & !FdFlag::FD_CLOEXEC)))
         .map(|_| ());
-    if cfg.set_non_block {
+    if opt.set_non_block {
         result = result
             .and_then(|_| fcntl(sock, F_GETFL))
             .and_then(|flags| fcntl(sock, F_SETFL(
@@ -612,25 +820,24 @@ fn open_socket(addr: InetAddr, cfg: &TcpPort, uid: u32, gid: u32)
         unsafe { close(sock) };
         Err(format_err!("Socket option error: {:?}", e))
     } else {
-        info!("Socket {} open as {}", addr, sock);
+        info!("Socket {} open as {}", listener, sock);
         Ok(sock)
     }
 }
 
-fn open_sockets_for(socks: &mut HashMap<InetAddr, Socket>,
-                    ports: &HashMap<u16, TcpPort>,
+fn open_sockets_for(socks: &mut HashMap<Listener, Socket>,
+                    listeners: &[(Listener, SocketOptions)],
                     cmd: &mut Command,
                     uid: u32, gid: u32,
                     external_only: bool)
     -> Result<(), Error>
 {
-    for (&port, item) in ports {
+    for &(ref listener, ref item) in listeners {
         if external_only == true || item.external {
-            let addr = InetAddr::from_std(&SocketAddr::new(item.host.0, port));
-            if !socks.contains_key(&addr) {
+            if !socks.contains_key(listener) {
                 if !item.reuse_port {
-                    let sock = open_socket(addr, item, uid, gid)?;
-                    socks.insert(addr, Socket {
+                    let sock = open_socket(listener, item, uid, gid)?;
+                    socks.insert(listener.clone(), Socket {
                         fd: sock,
                     });
                 }
@@ -639,27 +846,37 @@ fn open_sockets_for(socks: &mut HashMap<InetAddr, Socket>,
     }
 
     cmd.reset_fds();
+    // lithos_knot writes its own output to fd 1 and 2, so sockets for these
+    // are passed above all other descriptors and moved in place by knot
+    let mut remapped = Vec::new();
     if socks.len() > 0 {
         cmd.close_fds(socks.values().map(|x| x.fd).min().unwrap()
                       ..(socks.values().map(|x| x.fd).max().unwrap() + 1));
-        for (&port, item) in ports {
+        let remap_base = listeners.iter().map(|&(_, ref item)| item.fd)
+            .max().unwrap_or(0).max(2) + 1;
+        for &(ref listener, ref item) in listeners {
             if external_only == false && !item.external {
                 continue;
             }
-            let addr = InetAddr::from_std(&SocketAddr::new(item.host.0, port));
             match item.fd {
                 0 => {
-                    let fd = Stdio::dup_file(socks.get(&addr).unwrap())
+                    let fd = Stdio::dup_file(socks.get(listener).unwrap())
                         .map_err(|e| {
                             format_err!("Can't dup file descriptor: {}", e)
                         })?;
                     cmd.stdin(fd);
                 }
-                // TODO(tailhook) these is dangerous to pass to lithos knot
-                // as stdout and stderr, we need to map them somehow
-                1|2 => bail!("passing fd 1 and fd 2 is not supported yet"),
+                1|2 => {
+                    let source = remap_base + remapped.len() as RawFd;
+                    let fd = Fd::dup_file(socks.get(listener).unwrap())
+                        .map_err(|e| {
+                            format_err!("Can't dup file descriptor: {}", e)
+                        })?;
+                    cmd.file_descriptor(source, fd);
+                    remapped.push((item.fd, source));
+                }
                 _ => {
-                    let fd = Fd::dup_file(socks.get(&addr).unwrap())
+                    let fd = Fd::dup_file(socks.get(listener).unwrap())
                         .map_err(|e| {
                             format_err!("Can't dup file descriptor: {}", e)
                         })?;
@@ -668,6 +885,11 @@ fn open_sockets_for(socks: &mut HashMap<InetAddr, Socket>,
             }
         }
     }
+    if remapped.len() > 0 {
+        cmd.env(REMAP_FDS_VAR, format_fd_map(&remapped));
+    } else {
+        cmd.env_remove(REMAP_FDS_VAR);
+    }
     Ok(())
 }
 
@@ -675,16 +897,48 @@ fn duration(inp: f32) -> Duration {
     Duration::from_millis((inp * 1000.) as u64)
 }
 
+fn update_volume_metrics(children: &HashMap<Pid, Child>,
+    metrics: &metrics::Metrics)
+{
+    for (pid, child) in children {
+        let name = match *child {
+            Child::Process(ref p) => &p.name,
+            Child::Unidentified(_) => continue,
+        };
+        for (&(ref vname, ref mp), volume) in &metrics.volumes {
+            if vname != name {
+                continue;
+            }
+            match quota::volume_usage(i32::from(*pid), mp) {
+                Ok(usage) => {
+                    volume.used.set(usage.used as i64);
+                    volume.size.set(usage.size as i64);
+                }
+                Err(e) => {
+                    debug!("Can't get usage of volume {:?} of {:?}: {}",
+                        mp, name, e);
+                }
+            }
+        }
+    }
+}
+
 fn normal_loop(queue: &mut Queue<Timeout>,
     children: &mut HashMap<Pid, Child>,
-    sockets: &mut HashMap<InetAddr, Socket>,
+    sockets: &mut HashMap<Listener, Socket>,
     trap: &mut Trap,
     metrics: &metrics::Metrics,
     master: &MasterConfig)
 {
+    let mut volumes_deadline = Instant::now();
     loop {
         let now = Instant::now();
 
+        if now >= volumes_deadline {
+            update_volume_metrics(children, metrics);
+            volumes_deadline = now + VOLUME_METRICS_INTERVAL;
+        }
+
         let mut buf = Vec::new();
         for timeout in queue.pop_until(now) {
             match timeout {
@@ -692,7 +946,7 @@ fn normal_loop(queue: &mut Queue<Timeout>,
                     let restart_min = now +
                         duration(child.inner_config.restart_timeout);
                     match open_sockets_for(
-                        sockets, &child.inner_config.tcp_ports,
+                        sockets, &child.listeners,
                         &mut child.cmd,
                         child.socket_cred.0, child.socket_cred.1,
                         !child.bridged_network)
@@ -750,7 +1004,13 @@ fn normal_loop(queue: &mut Queue<Timeout>,
         metrics.queue.set(queue.len() as i64);
 
         close_unused_sockets(sockets, children);
-        let next_signal = match queue.peek_time() {
+        let deadline = if metrics.volumes.len() > 0 {
+            Some(queue.peek_time().map_or(volumes_deadline,
+                                          |x| x.min(volumes_deadline)))
+        } else {
+            queue.peek_time()
+        };
+        let next_signal = match deadline {
             Some(deadline) => trap.wait(deadline),
             None => trap.next(),
         };
@@ -811,7 +1071,7 @@ fn normal_loop(queue: &mut Queue<Timeout>,
 }
 
 fn shutdown_loop(children: &mut HashMap<Pid, Child>,
-    sockets: &mut HashMap<InetAddr, Socket>,
+    sockets: &mut HashMap<Listener, Socket>,
     trap: &mut Trap,
     metrics: &metrics::Metrics,
     master: &MasterConfig)
@@ -1020,7 +1280,7 @@ fn read_subtree<'x>(master: &MasterConfig,
             let mut items = Vec::<(String, Process)>::new();
             for i in 0..instances {
                 let name = format!("{}/{}.{}", sandbox_name, child_name, i);
-                let child = match child.instantiate(i) {
+                let mut child = match child.instantiate(i) {
                     Ok(x) => x,
                     Err(e) => {
                         error!("Error instantiating child {:?} \
@@ -1029,6 +1289,18 @@ fn read_subtree<'x>(master: &MasterConfig,
                         continue;
                     }
                 };
+                let pool = sandbox.bridged_network.as_ref()
+                    .and_then(|net| net.ip_pool.as_ref());
+                if let (None, Some(pool)) = (child.ip_address, pool) {
+                    match ipam::allocate(&master.runtime_dir, &name, pool) {
+                        Ok(ip) => child.ip_address = Some(ip),
+                        Err(e) => {
+                            error!("Can't allocate address for {:?}: {}",
+                                name, e);
+                            continue;
+                        }
+                    }
+                }
                 let cfg = match cfg.instantiate(&Variables {
                         user_vars: &child.variables,
                         lithos_name: &name,
@@ -1043,6 +1315,13 @@ fn read_subtree<'x>(master: &MasterConfig,
                         continue;
                     }
                 };
+                let listeners = match listeners(&cfg, &sandbox, sock_uid) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("Error in sockets of {:?}: {}", name, e);
+                        continue;
+                    }
+                };
                 let child_string = to_string(&child)
                     .expect("can always serialize child config");
                 let cmd = new_child(bin, &name, master_file,
@@ -1054,10 +1333,7 @@ fn read_subtree<'x>(master: &MasterConfig,
                     base_name: (sandbox_name.clone(), child_name.clone()),
                     restart_min: restart_min,
                     config: child_string,
-                    addresses: cfg.tcp_ports.iter().map(|(&port, item)| {
-                            InetAddr::from_std(
-                                &SocketAddr::new(item.host.0, port))
-                        }).collect(),
+                    listeners,
                     inner_config: cfg,
                     socket_cred: (sock_uid, sock_gid),
                     bridged_network: sandbox.bridged_network.is_some(),
@@ -1068,6 +1344,97 @@ fn read_subtree<'x>(master: &MasterConfig,
         }).collect()
 }
 
+fn socket_owner(uid: Option<u32>, gid: Option<u32>,
+    cfg: &InstantiatedConfig, sandbox: &SandboxConfig)
+    -> Result<(u32, u32), String>
+{
+    let uid = uid.or(cfg.user_id).or(sandbox.default_user).unwrap_or(0);
+    let gid = gid.or(cfg.group_id).or(sandbox.default_group).unwrap_or(0);
+    let (uid_map, gid_map) = if sandbox.uid_map.len() > 0 {
+        (&sandbox.uid_map, &sandbox.gid_map)
+    } else if cfg.uid_map.len() > 0 {
+        (&cfg.uid_map, &cfg.gid_map)
+    } else {
+        if !in_range(&sandbox.allow_users, uid) {
+            return Err(format!("user {} is not allowed", uid));
+        }
+        if !in_range(&sandbox.allow_groups, gid) {
+            return Err(format!("group {} is not allowed", gid));
+        }
+        return Ok((uid, gid));
+    };
+    Ok((
+        uid_map.map_id(uid)
+            .ok_or_else(|| format!("user {} is not mapped", uid))?,
+        gid_map.map_id(gid)
+            .ok_or_else(|| format!("group {} is not mapped", gid))?,
+    ))
+}
+
+fn listeners(cfg: &InstantiatedConfig, sandbox: &SandboxConfig,
+    sock_uid: u32)
+    -> Result<Vec<(Listener, SocketOptions)>, String>
+{
+    let mut result = Vec::new();
+    for (&port, item) in &cfg.tcp_ports {
+        let addr = InetAddr::from_std(&SocketAddr::new(item.host.0, port));
+        result.push((Listener::Tcp(addr), SocketOptions {
+            fd: item.fd,
+            external: item.external,
+            reuse_addr: item.reuse_addr,
+            reuse_port: item.reuse_port,
+            set_non_block: item.set_non_block,
+            listen_backlog: Some(item.listen_backlog),
+            mode: 0,
+            owner: None,
+        }));
+    }
+    for (&port, item) in &cfg.udp_ports {
+        let addr = InetAddr::from_std(&SocketAddr::new(item.host.0, port));
+        result.push((Listener::Udp(addr), SocketOptions {
+            fd: item.fd,
+            external: item.external,
+            reuse_addr: item.reuse_addr,
+            reuse_port: item.reuse_port,
+            set_non_block: item.set_non_block,
+            listen_backlog: None,
+            mode: 0,
+            owner: None,
+        }));
+    }
+    for (path, item) in &cfg.unix_sockets {
+        sandbox.check_socket_path(path)?;
+        if sock_uid == 0 {
+            // socket is created with permissions of the user, and root
+            // could replace any socket of the host
+            return Err(format!("socket {:?}: unix sockets can't be created \
+                by root, container user must be non-zero and mapped",
+                path));
+        }
+        let owner = match (item.user_id, item.group_id) {
+            (None, None) => None,
+            (uid, gid) => Some(socket_owner(uid, gid, cfg, sandbox)
+                .map_err(|e| format!("socket {:?}: {}", path, e))?),
+        };
+        result.push((Listener::Unix(path.clone(), item.socket_type),
+            SocketOptions {
+                fd: item.fd,
+                // unix sockets are not affected by bridged network
+                external: true,
+                reuse_addr: false,
+                reuse_port: false,
+                set_non_block: item.set_non_block,
+                listen_backlog: match item.socket_type {
+                    UnixSocketType::Stream => Some(item.listen_backlog),
+                    UnixSocketType::Datagram => None,
+                },
+                mode: item.mode,
+                owner,
+            }));
+    }
+    Ok(result)
+}
+
 fn schedule_new_workers(configs: HashMap<String, Process>,
     queue: &mut Queue<Timeout>)
 {
@@ -1,5 +1,5 @@
 use std::rc::Rc;
-use std::io::{Write, BufRead, BufReader};
+use std::io::{Read, Write, BufRead, BufReader};
 use std::fs::{File, create_dir, remove_dir, metadata};
 use std::io::ErrorKind::NotFound;
 use std::fs::OpenOptions;
@@ -20,6 +20,8 @@ pub struct CGroupPath(pub String, pub PathBuf);
 pub enum Controller {
     Cpu,
     Memory,
+    Cpuset,
+    Devices,
 }
 
 
@@ -122,6 +124,10 @@ pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
             try!(create_dir(&fullpath)
                  .map_err(|e| format!("Error creating cgroup dir {:?}: {}",
                                       fullpath, e)));
+            if &ctr[..] == "cpuset" {
+                // tasks can't be added to cpuset with empty cpus or mems
+                try!(inherit_cpuset(&fullpath));
+            }
         } else {
             debug!("CGroup {} already exists", fullpath.display());
         }
@@ -138,12 +144,34 @@ pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
             "memory" => {
                 res.full_paths.insert(Controller::Memory, fullpath);
             }
+            "cpuset" => {
+                res.full_paths.insert(Controller::Cpuset, fullpath);
+            }
+            "devices" => {
+                res.full_paths.insert(Controller::Devices, fullpath);
+            }
             _ => {}
         };
     }
     return Ok(res);
 }
 
+fn inherit_cpuset(path: &Path) -> Result<(), String> {
+    let parent = path.parent().expect("cgroup has parent");
+    for key in &["cpuset.cpus", "cpuset.mems"] {
+        let mut value = String::new();
+        try!(File::open(parent.join(key))
+             .and_then(|mut f| f.read_to_string(&mut value))
+             .map_err(|e| format!("Can't read cgroup path {:?}/{}: {}",
+                                  parent, key, e)));
+        try!(File::create(path.join(key))
+             .and_then(|mut f| f.write_all(value.trim().as_bytes()))
+             .map_err(|e| format!("Can't write to cgroup path {:?}/{}: {}",
+                                  path, key, e)));
+    }
+    Ok(())
+}
+
 pub fn remove_child_cgroup(child: &str, master: &String,
     controllers: &Vec<String>)
     -> Result<(), String>
@@ -189,6 +217,9 @@ impl CGroups {
             .map_err(|e| format!("Can't write to cgroup path {:?}/{}: {}",
                 path, key, e))
     }
+    pub fn has_controller(&self, ctr: Controller) -> bool {
+        self.full_paths.contains_key(&ctr)
+    }
     pub fn set_value_if_exists(&self, ctr: Controller, key: &str, value: &str)
         -> Result<(), String>
     {
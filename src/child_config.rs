@@ -25,6 +25,10 @@ pub struct ChildInstance {
     pub extra_secrets_namespaces: Vec<String>,
     #[serde(skip_serializing_if="Option::is_none", default)]
     pub ip_address: Option<IpAddr>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub ipv6_address: Option<IpAddr>,
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
+    pub extra_addresses: Vec<IpAddr>,
     pub kind: ChildKind,
 }
 
@@ -42,27 +46,49 @@ pub struct ChildConfig {
     pub extra_secrets_namespaces: Vec<String>,
     #[serde(skip_serializing_if="Vec::is_empty", default)]
     pub ip_addresses: Vec<IpAddr>,
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
+    pub ipv6_addresses: Vec<IpAddr>,
+    #[serde(default="one")]
+    pub addresses_per_instance: usize,
     pub kind: ChildKind,
 }
 
+fn instance_addresses<'x>(addresses: &'x [IpAddr], instance: usize,
+    per_instance: usize, kind: &str)
+    -> Result<&'x [IpAddr], Error>
+{
+    if addresses.len() == 0 {
+        return Ok(&[]);
+    }
+    let start = instance * per_instance;
+    if start + per_instance > addresses.len() {
+        bail!("Instance no {} needs {} {} addresses, \
+            but there's only {} of them",
+            instance, per_instance, kind, addresses.len());
+    }
+    Ok(&addresses[start..start + per_instance])
+}
+
 impl ChildConfig {
     pub fn instantiate(&self, instance: usize) -> Result<ChildInstance, Error>
     {
+        if self.addresses_per_instance == 0 {
+            bail!("addresses-per-instance must be at least 1");
+        }
+        let ipv4 = instance_addresses(&self.ip_addresses, instance,
+            self.addresses_per_instance, "ip")?;
+        let ipv6 = instance_addresses(&self.ipv6_addresses, instance,
+            self.addresses_per_instance, "ipv6")?;
         let cfg = ChildInstance {
             instances: 1,  // TODO(tailhook) legacy, find a way to remove
             image: self.image.clone(),
             config: self.config.clone(),
             variables: self.variables.clone(),
-            ip_address: if self.ip_addresses.len() > 0 {
-                if let Some(addr) = self.ip_addresses.get(instance) {
-                    Some(*addr)
-                } else {
-                    bail!("Instance no {}, but there's only {} ip addresses",
-                        instance, self.ip_addresses.len());
-                }
-            } else {
-                None
-            },
+            ip_address: ipv4.first().cloned(),
+            ipv6_address: ipv6.first().cloned(),
+            extra_addresses: ipv4.iter().skip(1)
+                .chain(ipv6.iter().skip(1))
+                .cloned().collect(),
             extra_secrets_namespaces: self.extra_secrets_namespaces.clone(),
             kind: self.kind,
         };
@@ -82,9 +108,18 @@ impl ChildConfig {
         .member("extra_secrets_namespaces", Sequence::new(Scalar::new()))
         .member("kind", Scalar::new().default("Daemon"))
         .member("ip_addresses", Sequence::new(Scalar::new()))
+        .member("ipv6_addresses", Sequence::new(Scalar::new()))
+        .member("addresses_per_instance", Numeric::new().min(1).default(1))
     }
 }
 impl ChildInstance {
+    /// All addresses of the instance, primary IPv4 and IPv6 ones go first
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.ip_address.iter()
+            .chain(self.ipv6_address.iter())
+            .chain(self.extra_addresses.iter())
+            .cloned().collect()
+    }
     pub fn validator<'x>() -> Structure<'x> {
         Structure::new()
         .member("instances", Numeric::new().default(1))
@@ -94,6 +129,8 @@ impl ChildInstance {
         .member("extra_secrets_namespaces", Sequence::new(Scalar::new()))
         .member("kind", Scalar::new().default("Daemon"))
         .member("ip_address", Scalar::new().optional())
+        .member("ipv6_address", Scalar::new().optional())
+        .member("extra_addresses", Sequence::new(Scalar::new()))
     }
 }
 
@@ -109,8 +146,10 @@ impl FromStr for ChildInstance {
 #[cfg(test)]
 mod test {
     use std::collections::BTreeMap;
+    use std::net::IpAddr;
     use std::str::FromStr;
-    use super::ChildInstance;
+    use quire::{parse_string, Options};
+    use super::{ChildInstance, ChildConfig};
     use super::ChildKind::Daemon;
     use serde_json::{to_string, from_str};
 
@@ -129,6 +168,8 @@ mod test {
             variables: BTreeMap::new(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            ipv6_address: None,
+            extra_addresses: Vec::new(),
             kind: Daemon,
         });
 
@@ -140,6 +181,8 @@ mod test {
             variables: BTreeMap::new(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            ipv6_address: None,
+            extra_addresses: Vec::new(),
             kind: Daemon,
         });
     }
@@ -162,6 +205,8 @@ mod test {
             ].into_iter().collect(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            ipv6_address: None,
+            extra_addresses: Vec::new(),
             kind: Daemon,
         })
     }
@@ -175,6 +220,8 @@ mod test {
             variables: BTreeMap::new(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            ipv6_address: None,
+            extra_addresses: Vec::new(),
             kind: Daemon,
         }).unwrap();
         assert_eq!(data, "{\
@@ -196,6 +243,8 @@ mod test {
             ].into_iter().collect(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            ipv6_address: None,
+            extra_addresses: Vec::new(),
             kind: Daemon,
         }).unwrap();
         assert_eq!(data, "{\
@@ -205,4 +254,45 @@ mod test {
             \"variables\":{\"a\":\"b\",\"c\":\"d\"},\
             \"kind\":\"Daemon\"}");
     }
+
+    #[test]
+    fn instantiate_dual_stack() {
+        let cfg: ChildConfig = parse_string("<test>", r#"
+            image: myproj.4a20772b
+            config: /config/staging/myproj.yaml
+            instances: 2
+            ip-addresses: [10.0.0.2, 10.0.0.3]
+            ipv6-addresses: ["fd00::2", "fd00::3"]
+            "#, &ChildConfig::validator(), &Options::default()).unwrap();
+        let inst = cfg.instantiate(1).unwrap();
+        assert_eq!(inst.ip_address, Some("10.0.0.3".parse().unwrap()));
+        assert_eq!(inst.ipv6_address, Some("fd00::3".parse().unwrap()));
+        assert!(cfg.instantiate(2).is_err());
+        let data = to_string(&inst).unwrap();
+        assert!(data.contains(r#""ipv6_address":"fd00::3""#));
+        assert!(!data.contains("extra_addresses"));
+    }
+
+    #[test]
+    fn instantiate_multiple_addresses() {
+        let cfg: ChildConfig = parse_string("<test>", r#"
+            image: myproj.4a20772b
+            config: /config/staging/myproj.yaml
+            instances: 2
+            addresses-per-instance: 2
+            ip-addresses: [10.0.0.2, 10.0.0.3, 10.0.0.4, 10.0.0.5]
+            ipv6-addresses: ["fd00::2", "fd00::3", "fd00::4", "fd00::5"]
+            "#, &ChildConfig::validator(), &Options::default()).unwrap();
+        let inst = cfg.instantiate(1).unwrap();
+        assert_eq!(inst.ip_address, Some("10.0.0.4".parse().unwrap()));
+        assert_eq!(inst.ipv6_address, Some("fd00::4".parse().unwrap()));
+        assert_eq!(inst.extra_addresses, vec![
+            "10.0.0.5".parse::<IpAddr>().unwrap(),
+            "fd00::5".parse::<IpAddr>().unwrap(),
+        ]);
+        assert!(cfg.instantiate(2).is_err());
+        let data = to_string(&inst).unwrap();
+        let parsed = ChildInstance::from_str(&data).unwrap();
+        assert_eq!(parsed, inst);
+    }
 }
@@ -1,5 +1,5 @@
 use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 #[cfg(not(target_arch="wasm32"))] use std::os::unix::io::RawFd;
 
@@ -13,16 +13,64 @@ use id_map::{IdMap, IdMapExt, mapping_validator};
 use sandbox_config::SandboxConfig;
 use range::{in_range};
 use child_config::ChildKind;
+use limits::{Resource, Rlimit};
+use cpuset::CpusetConfig;
+use scheduling::{IoPriority, SchedPolicy};
+use quota::QuotaMethod;
+use port_forward::{PortForward, parse_key};
+use bandwidth::Bandwidth;
+use utils::instance_number;
 
 
 pub const DEFAULT_KILL_TIMEOUT: f32 = 5.;
 
 #[cfg(target_arch="wasm32")] type RawFd = i32;
 
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum Propagation {
+    Private,
+    Slave,
+    Shared,
+    Unbindable,
+}
+
+/// Flags applied to a volume by remounting it after mount
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct MountOptions {
+    pub noexec: bool,
+    pub nosuid: bool,
+    pub nodev: bool,
+    pub noatime: bool,
+    pub propagation: Option<Propagation>,
+}
+
+impl MountOptions {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("noexec", Scalar::new().default(false))
+        .member("nosuid", Scalar::new().default(false))
+        .member("nodev", Scalar::new().default(false))
+        .member("noatime", Scalar::new().default(false))
+        .member("propagation", Scalar::new().optional())
+    }
+    /// Adds flags forced by sandbox (see `force-mount-options`)
+    pub fn merge(&self, forced: &MountOptions) -> MountOptions {
+        MountOptions {
+            noexec: self.noexec || forced.noexec,
+            nosuid: self.nosuid || forced.nosuid,
+            nodev: self.nodev || forced.nodev,
+            noatime: self.noatime || forced.noatime,
+            propagation: forced.propagation.or(self.propagation),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct TmpfsInfo {
     pub size: usize,
     pub mode: u32,
+    pub options: MountOptions,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -32,6 +80,9 @@ pub struct PersistentInfo {
     pub mode: u32,
     pub user: u32,
     pub group: u32,
+    pub size: Option<usize>,
+    pub quota: QuotaMethod,
+    pub options: MountOptions,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -40,6 +91,55 @@ pub struct StatedirInfo {
     pub mode: u32,
     pub user: u32,
     pub group: u32,
+    pub options: MountOptions,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum OverlayStorage {
+    Tmpfs,
+    Statedir,
+    Persistent,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct OverlayInfo {
+    pub storage: OverlayStorage,
+    pub path: Option<PathBuf>,
+    pub size: usize,
+    pub mode: u32,
+    pub user: u32,
+    pub group: u32,
+    pub options: MountOptions,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub writable: bool,
+    pub options: MountOptions,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct SecretFilesInfo {
+    pub files: BTreeMap<String, Vec<String>>,
+    pub size: usize,
+    pub mode: u32,
+    pub user: u32,
+    pub group: u32,
+    pub options: MountOptions,
+}
+
+impl SecretFilesInfo {
+    /// Checks that file name doesn't escape the volume
+    pub fn check_name(name: &str) -> Result<(), String> {
+        if name.is_empty() || name == "." || name == ".." ||
+            name.contains('/') || name.contains('\0')
+        {
+            return Err(format!("invalid secret file name {:?}", name));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -48,6 +148,23 @@ pub enum Volume {
     Persistent(PersistentInfo),
     Tmpfs(TmpfsInfo),
     Statedir(StatedirInfo),
+    Overlay(OverlayInfo),
+    File(FileInfo),
+    SecretFiles(SecretFilesInfo),
+}
+
+impl Volume {
+    pub fn mount_options(&self) -> MountOptions {
+        match *self {
+            Volume::Readonly(_) => MountOptions::default(),
+            Volume::Persistent(ref x) => x.options.clone(),
+            Volume::Tmpfs(ref x) => x.options.clone(),
+            Volume::Statedir(ref x) => x.options.clone(),
+            Volume::Overlay(ref x) => x.options.clone(),
+            Volume::File(ref x) => x.options.clone(),
+            Volume::SecretFiles(ref x) => x.options.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
@@ -76,6 +193,9 @@ impl ContainerKind {
 pub struct ResolvConf {
     pub mount: Option<bool>,
     pub copy_from_host: bool,
+    pub nameservers: Vec<String>,
+    pub search: Vec<String>,
+    pub options: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -100,9 +220,45 @@ pub struct TcpPort {
     pub external: bool,
 }
 
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UdpPort {
+    pub host: Host,
+    pub fd: RawFd,
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+    pub set_non_block: bool,
+    pub external: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum UnixSocketType {
+    Stream,
+    Datagram,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UnixSocket {
+    pub fd: RawFd,
+    pub socket_type: UnixSocketType,
+    pub user_id: Option<u32>,
+    pub group_id: Option<u32>,
+    pub mode: u32,
+    pub listen_backlog: usize,
+    pub set_non_block: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PublishedPort {
+    pub host: Host,
+    pub port: Option<u16>,
+}
+
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
 pub enum Variable {
     TcpPort(TcpPortSettings),
+    UdpPort(TcpPortSettings),
+    UnixSocket(TcpPortSettings),
     Name,
     DottedName,
     Choice(Vec<String>),
@@ -119,13 +275,22 @@ pub struct ContainerConfig {
     pub variables: BTreeMap<String, Variable>,
     pub metadata: Json,
     pub volumes: BTreeMap<String, Volume>,
+    pub overlay_root: Option<OverlayInfo>,
+    pub devices: Vec<PathBuf>,
+    pub shm_size: Option<usize>,
+    pub shm_mode: u32,
     pub user_id: Option<u32>,
     pub group_id: Option<u32>,
     pub restart_timeout: f32,
     pub kill_timeout: f32,
     pub memory_limit: u64,
     pub fileno_limit: u64,
+    pub rlimits: BTreeMap<Resource, Rlimit>,
     pub cpu_shares: usize,
+    pub cpuset: Option<CpusetConfig>,
+    pub nice: i32,
+    pub ioprio: Option<IoPriority>,
+    pub sched_policy: Option<SchedPolicy>,
     pub executable: String,
     pub arguments: Vec<String>,
     pub environ: BTreeMap<String, String>,
@@ -141,19 +306,32 @@ pub struct ContainerConfig {
     pub restart_process_only: bool,
     pub normal_exit_codes: BTreeSet<i32>,
     pub tcp_ports: HashMap<String, TcpPort>,
+    pub udp_ports: HashMap<String, UdpPort>,
+    pub unix_sockets: BTreeMap<String, UnixSocket>,
+    pub published_ports: BTreeMap<String, PublishedPort>,
+    pub bandwidth: Bandwidth,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct InstantiatedConfig {
     pub kind: ContainerKind,
     pub volumes: BTreeMap<String, Volume>,
+    pub overlay_root: Option<OverlayInfo>,
+    pub devices: Vec<PathBuf>,
+    pub shm_size: Option<usize>,
+    pub shm_mode: u32,
     pub user_id: Option<u32>,
     pub group_id: Option<u32>,
     pub restart_timeout: f32,
     pub kill_timeout: f32,
     pub memory_limit: u64,
     pub fileno_limit: u64,
+    pub rlimits: BTreeMap<Resource, Rlimit>,
     pub cpu_shares: usize,
+    pub cpuset: Option<CpusetConfig>,
+    pub nice: i32,
+    pub ioprio: Option<IoPriority>,
+    pub sched_policy: Option<SchedPolicy>,
     pub executable: String,
     pub arguments: Vec<String>,
     pub environ: BTreeMap<String, String>,
@@ -167,6 +345,10 @@ pub struct InstantiatedConfig {
     pub restart_process_only: bool,
     pub normal_exit_codes: BTreeSet<i32>,
     pub tcp_ports: HashMap<u16, TcpPort>,
+    pub udp_ports: HashMap<u16, UdpPort>,
+    pub unix_sockets: BTreeMap<PathBuf, UnixSocket>,
+    pub published_ports: Vec<PortForward>,
+    pub bandwidth: Bandwidth,
     pub pid_env_vars: HashSet<String>,
 }
 
@@ -212,6 +394,14 @@ pub fn environ_validator<'x>() -> Mapping<'x> {
         .parser(wrap_into_list))
 }
 
+fn socket_variable_validator<'x>() -> Structure<'x> {
+    Structure::new()
+    .member("activation", Enum::new()
+        .option("systemd", Nothing)
+        .allow_plain()
+        .plain_default("none"))
+}
+
 impl ContainerConfig {
     pub fn validator<'x>() -> Structure<'x> {
         Structure::new()
@@ -219,11 +409,9 @@ impl ContainerConfig {
         .member("variables", Mapping::new(
             Scalar::new(),
             Enum::new()
-                .option("TcpPort", Structure::new()
-                    .member("activation", Enum::new()
-                        .option("systemd", Nothing)
-                        .allow_plain()
-                        .plain_default("none")))
+                .option("TcpPort", socket_variable_validator())
+                .option("UdpPort", socket_variable_validator())
+                .option("UnixSocket", socket_variable_validator())
                 .option("Name", Nothing)
                 .option("DottedName", Nothing)
                 .option("Choice", Sequence::new(Scalar::new()))
@@ -232,11 +420,22 @@ impl ContainerConfig {
         .member("volumes", Mapping::new(
                 Scalar::new(),
                 volume_validator()))
+        .member("overlay_root", overlay_validator().optional())
+        .member("devices", Sequence::new(Scalar::new()))
+        .member("shm_size", Numeric::new().min(1).optional())
+        .member("shm_mode", Numeric::new().min(0).max(0o1777).default(0o1777))
         .member("user_id", Numeric::new().optional())
         .member("group_id", Numeric::new().optional())
         .member("memory_limit", Numeric::new().default(0x7fffffffffffffffi64))
         .member("fileno_limit", Numeric::new().default(1024))
+        .member("rlimits", Mapping::new(
+            Scalar::new(),
+            Rlimit::validator()))
         .member("cpu_shares", Numeric::new().default(1024))
+        .member("cpuset", CpusetConfig::validator().optional())
+        .member("nice", Numeric::new().min(-20).max(19).default(0))
+        .member("ioprio", IoPriority::validator().optional())
+        .member("sched_policy", Scalar::new().optional())
         .member("restart_timeout", Numeric::new().min(0).max(86400).default(1))
         .member("kill_timeout",
             Numeric::new().min(0).max(86400)
@@ -251,7 +450,10 @@ impl ContainerConfig {
         .member("workdir", Scalar::new().default("/"))
         .member("resolv_conf", Structure::new()
             .member("mount", Scalar::new().optional())
-            .member("copy_from_host", Scalar::new().default(true)))
+            .member("copy_from_host", Scalar::new().default(true))
+            .member("nameservers", Sequence::new(Scalar::new()))
+            .member("search", Sequence::new(Scalar::new()))
+            .member("options", Sequence::new(Scalar::new())))
         .member("hosts_file", Structure::new()
             .member("mount", Scalar::new().optional())
             .member("copy_from_host", Scalar::new().default(true))
@@ -274,6 +476,35 @@ impl ContainerConfig {
                 .member("listen_backlog", Scalar::new().default(128))
                 .member("external", Scalar::new().default(false))
             ))
+        .member("udp_ports", Mapping::new(
+            Scalar::new(),
+            Structure::new()
+                .member("host", Scalar::new().default("0.0.0.0"))
+                .member("fd", Numeric::new().min(0))
+                .member("reuse_addr", Scalar::new().default(true))
+                .member("reuse_port", Scalar::new().default(false))
+                .member("set_non_block", Scalar::new().default(false))
+                .member("external", Scalar::new().default(false))
+            ))
+        .member("unix_sockets", Mapping::new(
+            Scalar::new(),
+            Structure::new()
+                .member("fd", Numeric::new().min(0))
+                .member("socket_type", Scalar::new().default("stream"))
+                .member("user_id", Numeric::new().optional())
+                .member("group_id", Numeric::new().optional())
+                .member("mode", Numeric::new().min(0).max(0o777)
+                    .default(0o660))
+                .member("listen_backlog", Scalar::new().default(128))
+                .member("set_non_block", Scalar::new().default(false))
+            ))
+        .member("published_ports", Mapping::new(
+            Scalar::new(),
+            Structure::new()
+                .member("host", Scalar::new().default("0.0.0.0"))
+                .member("port", Numeric::new().min(1).max(65535).optional())
+            ))
+        .member("bandwidth", Bandwidth::validator())
     }
     pub fn instantiate(&self, variables: &Variables)
         -> Result<InstantiatedConfig, Vec<String>>
@@ -289,6 +520,9 @@ impl ContainerConfig {
                         => Some(variables.lithos_name.to_string()),
                         "lithos:config_filename"
                         => Some(variables.lithos_config_filename.to_string()),
+                        "lithos:instance"
+                        => instance_number(variables.lithos_name)
+                            .map(|x| x.to_string()),
                         _ => None,
                     });
                 match val {
@@ -320,6 +554,44 @@ impl ContainerConfig {
                     (port, val.clone())
                 })
                 .collect::<HashMap<_, _>>();
+            let mut udp_ports = self.udp_ports.iter()
+                .map(|(key, val)| {
+                    let s = replace_vars(&key, &mut replacer);
+                    let port = match s.parse::<u16>() {
+                        Ok(x) => x,
+                        Err(e) => {
+                            errors2.insert(format!("Bad port {:?}: {}",
+                                key, e));
+                            return (0, val.clone());
+                        }
+                    };
+                    (port, val.clone())
+                })
+                .collect::<HashMap<_, _>>();
+            let mut unix_sockets = self.unix_sockets.iter()
+                .map(|(key, val)| {
+                    (PathBuf::from(replace_vars(&key, &mut replacer)),
+                     val.clone())
+                })
+                .collect::<BTreeMap<_, _>>();
+            let published_ports = self.published_ports.iter()
+                .filter_map(|(key, val)| {
+                    let s = replace_vars(&key, &mut replacer);
+                    match parse_key(&s) {
+                        Ok((host_port, protocol)) => Some(PortForward {
+                            protocol,
+                            host: val.host.0,
+                            host_port,
+                            port: val.port.unwrap_or(host_port),
+                        }),
+                        Err(e) => {
+                            errors2.insert(format!(
+                                "Bad published port {:?}: {}", key, e));
+                            None
+                        }
+                    }
+                })
+                .collect();
 
             let mut pid_env_vars = HashSet::new();
             let mut environ = self.environ.iter()
@@ -335,39 +607,65 @@ impl ContainerConfig {
 
             let mut names = Vec::new();
             for (key, typ) in &self.variables {
-                match typ {
-                    Variable::TcpPort(TcpPortSettings {
-                        activation: Activation::Systemd
-                    }) => {
-                        names.push(&key[..]);
-                        let fd = (2 + names.len()) as i32;
-                        let port_str = match variables.user_vars.get(key) {
-                            None => {
-                                errors3.push(
-                                    format_err!("can't find var {:?}", key));
-                                continue;
-                            }
-                            Some(port_str) => port_str,
-                        };
-                        let port = match port_str.parse() {
-                            Err(e) => {
-                                errors3.push(format_err!("can't parse port \
-                                    {:?}: value {:?}: {}", key, port_str, e));
-                                continue;
-                            }
-                            Ok(port) => port,
-                        };
-                        tcp_ports.insert(port, TcpPort {
-                            host: Host(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
-                            fd,
-                            reuse_addr: true,
-                            reuse_port: false,
-                            set_non_block: false,
-                            listen_backlog: 128,
-                            external: false,
-                        });
+                let settings = match *typ {
+                    Variable::TcpPort(ref s) => s,
+                    Variable::UdpPort(ref s) => s,
+                    Variable::UnixSocket(ref s) => s,
+                    _ => continue,
+                };
+                if settings.activation != Activation::Systemd {
+                    continue;
+                }
+                names.push(&key[..]);
+                let fd = (2 + names.len()) as i32;
+                let value = match variables.user_vars.get(key) {
+                    None => {
+                        errors3.push(
+                            format_err!("can't find var {:?}", key));
+                        continue;
                     }
-                    _ => {}
+                    Some(value) => value,
+                };
+                if let Variable::UnixSocket(..) = *typ {
+                    unix_sockets.insert(PathBuf::from(value), UnixSocket {
+                        fd,
+                        socket_type: UnixSocketType::Stream,
+                        user_id: None,
+                        group_id: None,
+                        mode: 0o660,
+                        listen_backlog: 128,
+                        set_non_block: false,
+                    });
+                    continue;
+                }
+                let port = match value.parse() {
+                    Err(e) => {
+                        errors3.push(format_err!("can't parse port \
+                            {:?}: value {:?}: {}", key, value, e));
+                        continue;
+                    }
+                    Ok(port) => port,
+                };
+                let host = Host(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+                if let Variable::UdpPort(..) = *typ {
+                    udp_ports.insert(port, UdpPort {
+                        host,
+                        fd,
+                        reuse_addr: true,
+                        reuse_port: false,
+                        set_non_block: false,
+                        external: false,
+                    });
+                } else {
+                    tcp_ports.insert(port, TcpPort {
+                        host,
+                        fd,
+                        reuse_addr: true,
+                        reuse_port: false,
+                        set_non_block: false,
+                        listen_backlog: 128,
+                        external: false,
+                    });
                 }
             }
             if !names.is_empty() {
@@ -379,13 +677,28 @@ impl ContainerConfig {
             InstantiatedConfig {
                 kind: self.kind.clone(),
                 volumes: self.volumes.clone(),
+                overlay_root: self.overlay_root.clone(),
+                devices: self.devices.clone(),
+                shm_size: self.shm_size,
+                shm_mode: self.shm_mode,
                 user_id: self.user_id.clone(),
                 group_id: self.group_id.clone(),
                 restart_timeout: self.restart_timeout.clone(),
                 kill_timeout: self.kill_timeout.clone(),
                 memory_limit: self.memory_limit.clone(),
                 fileno_limit: self.fileno_limit.clone(),
+                rlimits: self.rlimits.clone(),
                 cpu_shares: self.cpu_shares.clone(),
+                cpuset: self.cpuset.as_ref().map(|c| CpusetConfig {
+                    cpus: c.cpus.as_ref()
+                        .map(|x| replace_vars(x, &mut replacer)),
+                    cores_per_instance: c.cores_per_instance,
+                    mems: c.mems.as_ref()
+                        .map(|x| replace_vars(x, &mut replacer)),
+                }),
+                nice: self.nice,
+                ioprio: self.ioprio.clone(),
+                sched_policy: self.sched_policy.clone(),
                 executable: self.executable.clone(),
                 arguments: self.arguments.iter()
                     .map(|x| replace_vars(&x, &mut replacer).into())
@@ -402,9 +715,22 @@ impl ContainerConfig {
                 restart_process_only: self.restart_process_only.clone(),
                 normal_exit_codes: self.normal_exit_codes.clone(),
                 tcp_ports,
+                udp_ports,
+                unix_sockets,
+                published_ports,
+                bandwidth: self.bandwidth.clone(),
                 pid_env_vars,
             }
         };
+        for (mp, volume) in &self.volumes {
+            if let Volume::File(ref opt) = *volume {
+                if !opt.path.is_absolute() {
+                    errors2.insert(format!(
+                        "file volume {:?}: path {:?} must be absolute",
+                        mp, opt.path));
+                }
+            }
+        }
         if errors1.len() > 0 || errors2.len() > 0 || errors3.len() > 0 {
             return Err(errors1.into_iter()
                 .chain(errors2.into_iter())
@@ -416,6 +742,17 @@ impl ContainerConfig {
     }
 }
 
+pub fn overlay_validator<'x>() -> Structure<'x> {
+    Structure::new()
+    .member("storage", Scalar::new().default("tmpfs"))
+    .member("path", Scalar::new().optional())
+    .member("size", Numeric::new().min(0).default(100*1024*1024))
+    .member("mode", Numeric::new().min(0).max(0o1777).default(0o755))
+    .member("user", Numeric::new().default(0))
+    .member("group", Numeric::new().default(0))
+    .member("options", MountOptions::validator())
+}
+
 pub fn volume_validator<'x>() -> Enum<'x> {
     Enum::new()
     .option("Persistent",  Structure::new()
@@ -423,16 +760,33 @@ pub fn volume_validator<'x>() -> Enum<'x> {
         .member("mkdir",  Scalar::new().default(false))
         .member("mode",  Numeric::new().min(0).max(0o1777).default(0o777))
         .member("user",  Numeric::new().default(0))
-        .member("group",  Numeric::new().default(0)))
+        .member("group",  Numeric::new().default(0))
+        .member("size",  Numeric::new().min(1).optional())
+        .member("quota",  Scalar::new().default("project"))
+        .member("options", MountOptions::validator()))
     .option("Readonly", Scalar::new())
     .option("Tmpfs", Structure::new()
         .member("size", Numeric::new().min(0).default(100*1024*1024))
-        .member("mode", Numeric::new().min(0).max(0o1777).default(0o777)))
+        .member("mode", Numeric::new().min(0).max(0o1777).default(0o777))
+        .member("options", MountOptions::validator()))
     .option("Statedir", Structure::new()
         .member("path", Scalar::new().default("/"))
         .member("mode", Numeric::new().min(0).max(0o1777).default(0o777))
         .member("user", Numeric::new().default(0))
-        .member("group", Numeric::new().default(0)))
+        .member("group", Numeric::new().default(0))
+        .member("options", MountOptions::validator()))
+    .option("Overlay", overlay_validator())
+    .option("File", Structure::new()
+        .member("path", Scalar::new())
+        .member("writable", Scalar::new().default(false))
+        .member("options", MountOptions::validator()))
+    .option("SecretFiles", Structure::new()
+        .member("files", environ_validator())
+        .member("size", Numeric::new().min(0).default(1024*1024))
+        .member("mode", Numeric::new().min(0).max(0o777).default(0o400))
+        .member("user", Numeric::new().default(0))
+        .member("group", Numeric::new().default(0))
+        .member("options", MountOptions::validator()))
 }
 
 impl<'a> Deserialize<'a> for Host {
@@ -453,20 +807,26 @@ impl Variable {
         -> Result<(), String>
     {
         match *self {
-            Variable::TcpPort { .. } => {
+            Variable::TcpPort { .. } | Variable::UdpPort { .. } => {
                 let port = value.parse::<u16>()
                     .map_err(|e| format!(
-                        "invalid TcpPort {:?}: {}", value, e))?;
+                        "invalid port {:?}: {}", value, e))?;
                 // TODO(tailhook) This still has an issue with
                 //                validating "external" ports.
                 //                But we don't know if port is external here.
                 if sandbox.bridged_network.is_none() {
                     if !in_range(&sandbox.allow_tcp_ports, port as u32) {
                         return Err(format!(
-                            "TcpPort {:?} is not in allowed range", port));
+                            "Port {:?} is not in allowed range", port));
                     }
                 }
             }
+            Variable::UnixSocket { .. } => {
+                if !Path::new(value).is_absolute() {
+                    return Err(format!(
+                        "unix socket path {:?} must be absolute", value));
+                }
+            }
             Variable::Name => {
                 let chars_ok = value.chars().all(|x| {
                     x.is_ascii() && x.is_alphanumeric() || x == '-' || x == '_'
@@ -531,7 +891,12 @@ pub fn replace_vars<F, S>(mut s: &str, mut f: F)
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use quire::{parse_string, Options};
     use super::replace_vars;
+    use super::{MountOptions, Propagation};
+    use super::{ContainerConfig, Variables, UnixSocketType};
 
     #[test]
     fn just_var() {
@@ -573,4 +938,74 @@ mod test {
             "1"
         }), "a1b1c");
     }
+
+    #[test]
+    fn forced_mount_options() {
+        let opt = MountOptions {
+            noexec: true,
+            propagation: Some(Propagation::Shared),
+            .. MountOptions::default()
+        };
+        let forced = MountOptions {
+            nosuid: true,
+            propagation: Some(Propagation::Private),
+            .. MountOptions::default()
+        };
+        let result = opt.merge(&forced);
+        assert!(result.noexec && result.nosuid);
+        assert!(!result.nodev && !result.noatime);
+        assert_eq!(result.propagation, Some(Propagation::Private));
+        assert_eq!(opt.merge(&MountOptions::default()), opt);
+    }
+
+    #[test]
+    fn systemd_activation() {
+        let cfg: ContainerConfig = parse_string("<container>", r#"
+            executable: /bin/true
+            variables:
+              http: !TcpPort { activation: systemd }
+              dns: !UdpPort { activation: systemd }
+              api: !UnixSocket { activation: systemd }
+            unix-sockets:
+              /run/app/@{lithos:instance}.sock:
+                fd: 10
+                socket-type: datagram
+            "#, &ContainerConfig::validator(), &Options::default())
+            .unwrap();
+        let vars = vec![
+            ("http".to_string(), "8080".to_string()),
+            ("dns".to_string(), "5353".to_string()),
+            ("api".to_string(), "/run/app/api.sock".to_string()),
+        ].into_iter().collect::<BTreeMap<_, _>>();
+        let icfg = cfg.instantiate(&Variables {
+            user_vars: &vars,
+            lithos_name: "sandbox/child.1",
+            lithos_config_filename: "/config.yaml",
+        }).unwrap();
+        assert_eq!(icfg.environ["LISTEN_FDS"], "3");
+        assert_eq!(icfg.environ["LISTEN_FDNAMES"], "api:dns:http");
+        assert_eq!(icfg.unix_sockets[Path::new("/run/app/api.sock")].fd, 3);
+        assert_eq!(icfg.udp_ports[&5353].fd, 4);
+        assert_eq!(icfg.tcp_ports[&8080].fd, 5);
+        let sock = &icfg.unix_sockets[Path::new("/run/app/1.sock")];
+        assert_eq!(sock.socket_type, UnixSocketType::Datagram);
+        assert_eq!(sock.mode, 0o660);
+    }
+
+    #[test]
+    fn relative_file_volume() {
+        let cfg: ContainerConfig = parse_string("<container>", r#"
+            executable: /bin/true
+            volumes:
+              /etc/x: !File { path: etc/passwd }
+            "#, &ContainerConfig::validator(), &Options::default())
+            .unwrap();
+        let errors = cfg.instantiate(&Variables {
+            user_vars: &BTreeMap::new(),
+            lithos_name: "sandbox/child.0",
+            lithos_config_filename: "/config.yaml",
+        }).err().unwrap();
+        assert_eq!(errors, vec![String::from(
+            "file volume \"/etc/x\": path \"etc/passwd\" must be absolute")]);
+    }
 }
@@ -0,0 +1,190 @@
+use std::io;
+use std::ffi::{CStr, CString};
+use std::fs::{File, create_dir_all, read_dir, metadata};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use humantime::format_rfc3339_seconds;
+use libc::{openat, mkdirat, renameat, dup, close, fdopendir, readdir};
+use libc::{closedir, O_RDONLY, O_DIRECTORY, O_CLOEXEC};
+
+use utils::set_file_mode;
+
+/// Name of the directory where kernel writes core dumps for an instance
+///
+/// Note: it must not look like timestamp, so it's never pruned
+pub const INCOMING_DIR: &'static str = "incoming";
+
+
+/// Directory containing all the core dumps of `sandbox/child.N`
+pub fn instance_dir(base: &Path, name: &str) -> PathBuf {
+    base.join(name)
+}
+
+/// Directory that is mounted writable into the container for core dumps
+pub fn incoming_dir(base: &Path, name: &str) -> PathBuf {
+    instance_dir(base, name).join(INCOMING_DIR)
+}
+
+pub fn prepare_incoming(base: &Path, name: &str) -> Result<PathBuf, String> {
+    let dir = incoming_dir(base, name);
+    if metadata(&dir).is_err() {
+        create_dir_all(&dir)
+            .map_err(|e| format!("Error creating core dir {:?}: {}", dir, e))?;
+        set_file_mode(&dir, 0o1777)
+            .map_err(|e| format!("Can't chmod core dir {:?}: {}", dir, e))?;
+    }
+    Ok(dir)
+}
+
+/// Opens directory of the instance
+///
+/// Used by lithos_knot to collect core dumps after it's chrooted into the
+/// container, where host directory isn't visible by path.
+pub fn open_instance_dir(base: &Path, name: &str) -> Result<File, String> {
+    let dir = instance_dir(base, name);
+    File::open(&dir)
+        .map_err(|e| format!("Can't open core dir {:?}: {}", dir, e))
+}
+
+fn open_subdir(dir: &File, name: &CStr) -> io::Result<File> {
+    let fd = unsafe {
+        openat(dir.as_raw_fd(), name.as_ptr(),
+               O_RDONLY|O_DIRECTORY|O_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn list_dir(dir: &File) -> io::Result<Vec<CString>> {
+    // fdopendir takes ownership of the descriptor
+    let fd = unsafe { dup(dir.as_raw_fd()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let dirp = unsafe { fdopendir(fd) };
+    if dirp.is_null() {
+        let err = io::Error::last_os_error();
+        unsafe { close(fd) };
+        return Err(err);
+    }
+    let mut names = Vec::new();
+    loop {
+        let entry = unsafe { readdir(dirp) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() != b"." && name.to_bytes() != b".." {
+            names.push(name.to_owned());
+        }
+    }
+    unsafe { closedir(dirp) };
+    Ok(names)
+}
+
+/// Moves core dumps from incoming directory to a timestamped directory
+///
+/// Returns `None` if there were no files to move.
+pub fn collect(base: &Path, name: &str) -> Result<Option<PathBuf>, String> {
+    let dir = instance_dir(base, name);
+    match File::open(&dir) {
+        Ok(file) => Ok(collect_at(&file)?.map(|stamp| dir.join(stamp))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Can't open core dir {:?}: {}", dir, e)),
+    }
+}
+
+/// Same as `collect` but for the instance dir opened with
+/// `open_instance_dir`, returns name of the created directory
+pub fn collect_at(dir: &File) -> Result<Option<String>, String> {
+    let incoming = match open_subdir(dir, &CString::new(INCOMING_DIR).unwrap())
+    {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Can't open core dir: {}", e)),
+    };
+    let files = list_dir(&incoming)
+        .map_err(|e| format!("Can't read core dir: {}", e))?;
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let stamp = format_rfc3339_seconds(SystemTime::now()).to_string();
+    let mut dest_name = stamp.clone();
+    let mut idx = 1;
+    loop {
+        let cname = CString::new(dest_name.clone()).unwrap();
+        if unsafe { mkdirat(dir.as_raw_fd(), cname.as_ptr(), 0o777) } == 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(format!("Error creating core dir {:?}: {}",
+                dest_name, err));
+        }
+        dest_name = format!("{}.{}", stamp, idx);
+        idx += 1;
+    }
+    let dest = open_subdir(dir, &CString::new(dest_name.clone()).unwrap())
+        .map_err(|e| format!("Can't open core dir {:?}: {}", dest_name, e))?;
+    for name in &files {
+        let res = unsafe {
+            renameat(incoming.as_raw_fd(), name.as_ptr(),
+                     dest.as_raw_fd(), name.as_ptr())
+        };
+        if res != 0 {
+            return Err(format!("Error moving core dump {:?}: {}",
+                name, io::Error::last_os_error()));
+        }
+    }
+    Ok(Some(dest_name))
+}
+
+/// Returns core dump directories of the instance except `keep` newest ones
+pub fn old_dumps(instance_dir: &Path, keep: usize)
+    -> Result<Vec<PathBuf>, io::Error>
+{
+    let mut dumps = Vec::new();
+    for entry in read_dir(instance_dir)? {
+        let entry = entry?;
+        if entry.file_name() == INCOMING_DIR || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        dumps.push(entry.path());
+    }
+    // timestamps are sorted lexicographically
+    dumps.sort();
+    let num = dumps.len().saturating_sub(keep);
+    dumps.truncate(num);
+    Ok(dumps)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{File, create_dir_all, remove_dir_all, read_dir};
+    use super::{incoming_dir, open_instance_dir, collect_at, collect};
+
+    #[test]
+    fn collect_dumps() {
+        let base = temp_dir().join("lithos-test-core-dumps");
+        remove_dir_all(&base).ok();
+        assert_eq!(collect(&base, "sb/web.0"), Ok(None));
+        let incoming = incoming_dir(&base, "sb/web.0");
+        create_dir_all(&incoming).unwrap();
+        let dir = open_instance_dir(&base, "sb/web.0").unwrap();
+        assert_eq!(collect_at(&dir), Ok(None));
+        File::create(incoming.join("core.1")).unwrap();
+        let first = collect_at(&dir).unwrap().unwrap();
+        File::create(incoming.join("core.2")).unwrap();
+        let second = collect(&base, "sb/web.0").unwrap().unwrap();
+        assert_ne!(base.join("sb/web.0").join(&first), second);
+        assert!(base.join("sb/web.0").join(&first).join("core.1").exists());
+        assert!(second.join("core.2").exists());
+        assert_eq!(read_dir(&incoming).unwrap().count(), 0);
+        remove_dir_all(&base).unwrap();
+    }
+}
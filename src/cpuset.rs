@@ -0,0 +1,216 @@
+use std::fmt;
+use std::str::FromStr;
+
+use quire::validate::{Structure, Scalar, Numeric};
+use serde::de::{Deserializer, Deserialize, Error};
+
+
+/// Cpus and memory nodes must be less than this, which is way more than
+/// any real machine has, but keeps lists from user configs small
+pub const MAX_CPUS: u32 = 4096;
+
+/// A list of cpus or memory nodes in the kernel format: `0-3,8,10-11`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuList(Vec<u32>);
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CpusetConfig {
+    pub cpus: Option<String>,
+    pub cores_per_instance: Option<usize>,
+    pub mems: Option<String>,
+}
+
+/// Cpus and memory nodes that are written into the cpuset cgroup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub cpus: CpuList,
+    pub mems: Option<CpuList>,
+}
+
+impl CpuList {
+    pub fn new(mut items: Vec<u32>) -> CpuList {
+        items.sort();
+        items.dedup();
+        CpuList(items)
+    }
+    pub fn items(&self) -> &[u32] {
+        &self.0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn contains(&self, other: &CpuList) -> bool {
+        other.0.iter().all(|x| self.0.binary_search(x).is_ok())
+    }
+    pub fn intersects(&self, other: &CpuList) -> bool {
+        other.0.iter().any(|x| self.0.binary_search(x).is_ok())
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = String;
+    fn from_str(s: &str) -> Result<CpuList, String> {
+        let mut items = Vec::new();
+        for chunk in s.split(',').map(|x| x.trim()).filter(|x| x.len() > 0) {
+            let mut pair = chunk.splitn(2, '-');
+            let start: u32 = pair.next().and_then(|x| x.trim().parse().ok())
+                .ok_or_else(|| format!("bad cpu list {:?}", s))?;
+            let end = match pair.next() {
+                Some(x) => x.trim().parse()
+                    .map_err(|_| format!("bad cpu list {:?}", s))?,
+                None => start,
+            };
+            if end < start {
+                return Err(format!("bad range {:?} in cpu list", chunk));
+            }
+            if end >= MAX_CPUS {
+                return Err(format!("cpu {} in cpu list is too large, \
+                    maximum is {}", end, MAX_CPUS - 1));
+            }
+            items.extend(start..end+1);
+        }
+        Ok(CpuList::new(items))
+    }
+}
+
+impl fmt::Display for CpuList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut iter = self.0.iter().cloned().peekable();
+        let mut first = true;
+        while let Some(start) = iter.next() {
+            let mut end = start;
+            while iter.peek() == Some(&(end+1)) {
+                end = iter.next().unwrap();
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Deserialize<'a> for CpuList {
+    fn deserialize<D: Deserializer<'a>>(d: D) -> Result<CpuList, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+impl CpusetConfig {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("cpus", Scalar::new().optional())
+        .member("cores_per_instance", Numeric::new().min(1).optional())
+        .member("mems", Scalar::new().optional())
+    }
+    /// Returns cpus and memory nodes for the specified instance
+    ///
+    /// `cpus` must be already substituted (see `ContainerConfig::instantiate`)
+    pub fn allocate(&self, cpu_pool: Option<&CpuList>,
+        memory_nodes: Option<&CpuList>, instance: usize)
+        -> Result<Allocation, String>
+    {
+        let cpus = match (&self.cpus, self.cores_per_instance) {
+            (&Some(_), Some(_)) => {
+                return Err(format!("`cpus` and `cores-per-instance` \
+                    are mutually exclusive"));
+            }
+            (&None, None) => {
+                return Err(format!("either `cpus` or `cores-per-instance` \
+                    must be specified"));
+            }
+            (&Some(ref cpus), None) => {
+                let cpus: CpuList = cpus.parse()?;
+                if let Some(pool) = cpu_pool {
+                    if !pool.contains(&cpus) {
+                        return Err(format!("cpus {} are not in the \
+                            sandbox's cpu-pool {}", cpus, pool));
+                    }
+                }
+                cpus
+            }
+            (&None, Some(num)) => {
+                let pool = cpu_pool.ok_or_else(|| format!(
+                    "`cores-per-instance` requires `cpu-pool` in sandbox"))?;
+                let start = instance * num;
+                if start + num > pool.0.len() {
+                    return Err(format!("instance {} needs cpus {}..{} \
+                        of the pool, but cpu-pool {} has only {} cpus",
+                        instance, start, start + num, pool, pool.0.len()));
+                }
+                CpuList::new(pool.0[start..start+num].to_vec())
+            }
+        };
+        if cpus.is_empty() {
+            return Err(format!("cpu list is empty"));
+        }
+        let mems = match self.mems {
+            Some(ref mems) => {
+                let mems: CpuList = mems.parse()?;
+                if let Some(nodes) = memory_nodes {
+                    if !nodes.contains(&mems) {
+                        return Err(format!("memory nodes {} are not in the \
+                            sandbox's memory-nodes {}", mems, nodes));
+                    }
+                }
+                Some(mems)
+            }
+            None => memory_nodes.cloned(),
+        };
+        Ok(Allocation { cpus, mems })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CpuList, CpusetConfig};
+
+    fn list(s: &str) -> CpuList {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_format() {
+        assert_eq!(list("0-3,8,10-11").items(), &[0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(list("3,1,2,7").to_string(), "1-3,7");
+        assert_eq!(list("").to_string(), "");
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("a".parse::<CpuList>().is_err());
+        assert!("0-4000000000".parse::<CpuList>().is_err());
+        assert!("0-4294967295".parse::<CpuList>().is_err());
+        assert!("4096".parse::<CpuList>().is_err());
+        assert_eq!(list("4095").items(), &[4095]);
+    }
+
+    #[test]
+    fn per_instance() {
+        let cfg = CpusetConfig {
+            cpus: None, cores_per_instance: Some(2), mems: None };
+        let pool = list("0-1,4-7");
+        let a = cfg.allocate(Some(&pool), None, 0).unwrap();
+        assert_eq!(a.cpus, list("0-1"));
+        let a = cfg.allocate(Some(&pool), None, 1).unwrap();
+        assert_eq!(a.cpus, list("4-5"));
+        assert!(cfg.allocate(Some(&pool), None, 3).is_err());
+        assert!(cfg.allocate(None, None, 0).is_err());
+    }
+
+    #[test]
+    fn explicit() {
+        let cfg = CpusetConfig {
+            cpus: Some("2-3".into()), cores_per_instance: None,
+            mems: Some("1".into()) };
+        let a = cfg.allocate(Some(&list("0-7")), Some(&list("0-1")), 0)
+            .unwrap();
+        assert_eq!(a.cpus, list("2-3"));
+        assert_eq!(a.mems, Some(list("1")));
+        assert!(cfg.allocate(Some(&list("0-2")), None, 0).is_err());
+        assert!(cfg.allocate(None, Some(&list("0")), 0).is_err());
+    }
+}
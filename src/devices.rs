@@ -0,0 +1,176 @@
+use std::io;
+use std::fs::{read_dir, symlink_metadata, create_dir_all, read_link};
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use libc::{mknod, major, minor, dev_t, mode_t, S_IFCHR, S_IFBLK};
+
+use sandbox_config::SandboxConfig;
+use utils::{cpath, relative};
+
+
+/// Major number of devpts, it's mounted separately for each container
+const PTS_MAJOR: u32 = 136;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Char,
+    Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// Path relative to the /dev directory
+    pub path: PathBuf,
+    pub kind: DeviceKind,
+    pub rdev: dev_t,
+    pub mode: u32,
+}
+
+impl Device {
+    /// Reads device node at `host_path`, returns `None` if it's not a device
+    pub fn read(host_path: &Path, path: &Path)
+        -> Result<Option<Device>, io::Error>
+    {
+        let meta = symlink_metadata(host_path)?;
+        let kind = if meta.file_type().is_char_device() {
+            DeviceKind::Char
+        } else if meta.file_type().is_block_device() {
+            DeviceKind::Block
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Device {
+            path: path.to_path_buf(),
+            kind: kind,
+            rdev: meta.rdev() as dev_t,
+            mode: meta.mode() & 0o7777,
+        }))
+    }
+    /// Rule for `devices.allow` file of the devices cgroup
+    pub fn cgroup_rule(&self) -> String {
+        let (major, minor) = unsafe { (major(self.rdev), minor(self.rdev)) };
+        format!("{} {}:{} rwm", match self.kind {
+            DeviceKind::Char => "c",
+            DeviceKind::Block => "b",
+        }, major, minor)
+    }
+    /// Creates device node in the `dev_dir` at the same relative path
+    pub fn create(&self, dev_dir: &Path) -> Result<(), io::Error> {
+        let dest = dev_dir.join(&self.path);
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent)?;
+        }
+        let kind = match self.kind {
+            DeviceKind::Char => S_IFCHR,
+            DeviceKind::Block => S_IFBLK,
+        };
+        let c_dest = cpath(&dest);
+        let rc = unsafe {
+            mknod(c_dest.as_ptr(), kind | self.mode as mode_t, self.rdev)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Checks that all devices of the container are allowed by sandbox
+pub fn check(devices: &[PathBuf], sandbox: &SandboxConfig)
+    -> Result<(), String>
+{
+    for dev in devices {
+        if !dev.starts_with("/dev") || dev == Path::new("/dev") {
+            return Err(format!("device {:?} must be inside /dev", dev));
+        }
+        if !sandbox.allow_devices.contains(dev) {
+            return Err(format!("device {:?} is not allowed by sandbox \
+                (see allow-devices)", dev));
+        }
+    }
+    Ok(())
+}
+
+/// Reads host device nodes configured for the container
+pub fn read_devices(devices: &[PathBuf]) -> Result<Vec<Device>, String> {
+    let mut result = Vec::new();
+    for path in devices {
+        let rel = relative(path, Path::new("/dev"));
+        match Device::read(path, &rel) {
+            Ok(Some(dev)) => result.push(dev),
+            Ok(None) => {
+                return Err(format!("{:?} is not a device node", path));
+            }
+            Err(e) => return Err(format!("can't read {:?}: {}", path, e)),
+        }
+    }
+    Ok(result)
+}
+
+/// Copies device nodes, directories and symlinks of the devfs dir
+///
+/// Returns device nodes found (they have to be allowed in cgroup)
+pub fn copy_devfs(devfs_dir: &Path, dest: &Path)
+    -> Result<Vec<Device>, io::Error>
+{
+    let mut devices = Vec::new();
+    _copy_devfs(devfs_dir, Path::new(""), dest, &mut devices)?;
+    Ok(devices)
+}
+
+fn _copy_devfs(base: &Path, dir: &Path, dest: &Path, devices: &mut Vec<Device>)
+    -> Result<(), io::Error>
+{
+    for entry in read_dir(base.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let typ = entry.file_type()?;
+        if typ.is_dir() {
+            create_dir_all(dest.join(&path))?;
+            _copy_devfs(base, &path, dest, devices)?;
+        } else if typ.is_symlink() {
+            symlink(read_link(entry.path())?, dest.join(&path))?;
+        } else if let Some(dev) = Device::read(&entry.path(), &path)? {
+            dev.create(dest)?;
+            devices.push(dev);
+        }
+    }
+    Ok(())
+}
+
+/// Rules for the `devices.allow` file of the devices cgroup
+///
+/// Besides the specified devices this includes pseudo-terminals, since
+/// devpts is mounted for each container.
+pub fn cgroup_rules(devices: &[Device]) -> Vec<String> {
+    let mut rules = vec![
+        format!("c {}:* rwm", PTS_MAJOR),
+        format!("c 5:2 rwm"),  // /dev/ptmx
+    ];
+    rules.extend(devices.iter().map(|d| d.cgroup_rule()));
+    rules.sort();
+    rules.dedup();
+    rules
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use libc::makedev;
+    use super::{Device, DeviceKind, cgroup_rules};
+
+    #[test]
+    fn rules() {
+        let dev = Device {
+            path: PathBuf::from("fuse"),
+            kind: DeviceKind::Char,
+            rdev: unsafe { makedev(10, 229) },
+            mode: 0o666,
+        };
+        assert_eq!(dev.cgroup_rule(), "c 10:229 rwm");
+        assert_eq!(cgroup_rules(&[dev.clone(), dev]),
+            vec!["c 10:229 rwm", "c 136:* rwm", "c 5:2 rwm"]);
+    }
+}
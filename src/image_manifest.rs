@@ -0,0 +1,250 @@
+//! Signed manifests of image directories
+//!
+//! Manifest lists every directory, file and symlink of the image with its
+//! mode, owner and sha256 hash. It's stored in the root of the image together with
+//! an ed25519 signature of the manifest file.
+use std::collections::BTreeMap;
+use std::fs::{File, read_dir, symlink_metadata, read_link};
+use std::io::{self, Read, BufReader, BufRead, Write};
+use std::os::unix::fs::{PermissionsExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use base64;
+use crypto::ed25519;
+use serde_json;
+use sha2::{Sha256, Digest};
+use ssh_keys::{PublicKey, openssh};
+
+
+pub const MANIFEST_FILE: &str = ".lithos-manifest.json";
+pub const SIGNATURE_FILE: &str = ".lithos-manifest.sig";
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag="type", rename_all="lowercase")]
+pub enum Entry {
+    Dir { mode: u32, uid: u32, gid: u32 },
+    File { mode: u32, uid: u32, gid: u32, sha256: String },
+    Symlink { target: PathBuf },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub entries: BTreeMap<String, Entry>,
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn file_hash(path: &Path) -> Result<String, io::Error> {
+    let mut file = File::open(path)?;
+    let mut hash = Sha256::default();
+    let mut buf = [0u8; 65536];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hash.input(&buf[..n]),
+        }
+    }
+    Ok(hex(&hash.result()))
+}
+
+impl Manifest {
+    /// Builds manifest of the directory (skipping manifest files)
+    pub fn scan(dir: &Path) -> Result<Manifest, String> {
+        let mut entries = BTreeMap::new();
+        scan_dir(dir, Path::new(""), &mut entries)?;
+        entries.remove(MANIFEST_FILE);
+        entries.remove(SIGNATURE_FILE);
+        Ok(Manifest { entries })
+    }
+    /// Returns description of the first difference from other manifest
+    pub fn diff(&self, actual: &Manifest) -> Option<String> {
+        for (path, entry) in &self.entries {
+            match actual.entries.get(path) {
+                None => return Some(format!("{:?} is missing", path)),
+                Some(x) if x != entry => {
+                    return Some(format!("{:?} is changed", path));
+                }
+                Some(_) => {}
+            }
+        }
+        for path in actual.entries.keys() {
+            if !self.entries.contains_key(path) {
+                return Some(format!("{:?} is not in manifest", path));
+            }
+        }
+        None
+    }
+}
+
+fn scan_dir(base: &Path, dir: &Path, entries: &mut BTreeMap<String, Entry>)
+    -> Result<(), String>
+{
+    let full = base.join(dir);
+    let items = read_dir(&full)
+        .map_err(|e| format!("can't read dir {:?}: {}", full, e))?;
+    for item in items {
+        let item = item
+            .map_err(|e| format!("can't read dir {:?}: {}", full, e))?;
+        let path = dir.join(item.file_name());
+        let name = path.to_str()
+            .ok_or_else(|| format!("non-utf8 file name {:?}", path))?
+            .to_string();
+        let fpath = item.path();
+        let meta = symlink_metadata(&fpath)
+            .map_err(|e| format!("can't stat {:?}: {}", fpath, e))?;
+        let mode = meta.permissions().mode() & 0o7777;
+        let (uid, gid) = (meta.uid(), meta.gid());
+        let typ = meta.file_type();
+        if typ.is_dir() {
+            entries.insert(name, Entry::Dir { mode, uid, gid });
+            scan_dir(base, &path, entries)?;
+        } else if typ.is_symlink() {
+            let target = read_link(&fpath)
+                .map_err(|e| format!("can't readlink {:?}: {}", fpath, e))?;
+            entries.insert(name, Entry::Symlink { target });
+        } else if typ.is_file() {
+            let hash = file_hash(&fpath)
+                .map_err(|e| format!("can't read {:?}: {}", fpath, e))?;
+            entries.insert(name, Entry::File { mode, uid, gid,
+                                               sha256: hash });
+        } else {
+            return Err(format!("unsupported file type of {:?}", fpath));
+        }
+    }
+    Ok(())
+}
+
+/// Reads openssh-formatted ed25519 public keys (one per file)
+pub fn read_public_keys(paths: &[PathBuf]) -> Result<Vec<[u8; 32]>, String> {
+    let mut keys = Vec::new();
+    for path in paths {
+        let mut buf = String::with_capacity(1024);
+        File::open(path)
+            .and_then(|f| BufReader::new(f).read_line(&mut buf))
+            .map_err(|e| format!("can't read {:?}: {}", path, e))?;
+        match openssh::parse_public_key(&buf) {
+            Ok(PublicKey::Ed25519(key)) => keys.push(key),
+            Ok(_) => {
+                return Err(format!("{:?}: only ed25519 keys are supported",
+                    path));
+            }
+            Err(e) => return Err(format!("{:?}: {}", path, e)),
+        }
+    }
+    Ok(keys)
+}
+
+/// Writes manifest and signature into the image directory
+///
+/// `private_key` is an ed25519 key as stored by openssh (64 bytes)
+pub fn sign(dir: &Path, private_key: &[u8]) -> Result<(), String> {
+    let manifest = Manifest::scan(dir)?;
+    let data = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| format!("can't serialize manifest: {}", e))?;
+    let signature = ed25519::signature(&data, private_key);
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        File::create(&path).and_then(|mut f| f.write_all(data))
+            .map_err(|e| format!("can't write {:?}: {}", path, e))
+    };
+    write(MANIFEST_FILE, &data)?;
+    write(SIGNATURE_FILE, base64::encode(&signature[..]).as_bytes())?;
+    Ok(())
+}
+
+/// Checks that manifest of the image is signed by one of the keys
+///
+/// Returns the manifest, files are not checked.
+pub fn verify_signature(dir: &Path, keys: &[[u8; 32]])
+    -> Result<Manifest, String>
+{
+    let read = |name: &str| {
+        let path = dir.join(name);
+        let mut buf = Vec::new();
+        File::open(&path).and_then(|mut f| f.read_to_end(&mut buf))
+            .map_err(|e| format!("image is not signed, can't read {:?}: {}",
+                path, e))?;
+        Ok::<_, String>(buf)
+    };
+    let data = read(MANIFEST_FILE)?;
+    let signature = base64::decode(&read(SIGNATURE_FILE)?[..])
+        .map_err(|e| format!("invalid signature: {}", e))?;
+    if signature.len() != 64 {
+        return Err(format!("invalid signature length"));
+    }
+    if !keys.iter().any(|k| ed25519::verify(&data, &k[..], &signature)) {
+        return Err(format!("manifest is not signed by any of the \
+            image-public-keys"));
+    }
+    serde_json::from_slice(&data)
+        .map_err(|e| format!("invalid manifest: {}", e))
+}
+
+/// Checks that image is signed by one of the keys and is not modified
+pub fn verify(dir: &Path, keys: &[[u8; 32]]) -> Result<(), String> {
+    let manifest = verify_signature(dir, keys)?;
+    let actual = Manifest::scan(dir)?;
+    if let Some(diff) = manifest.diff(&actual) {
+        return Err(format!("image is modified: {}", diff));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::env::temp_dir;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::fs::set_permissions;
+    use std::path::PathBuf;
+    use crypto::ed25519::keypair;
+    use super::{Manifest, Entry, sign, verify};
+
+    #[test]
+    fn sign_and_verify() {
+        let dir = temp_dir().join("lithos-test-image-manifest");
+        remove_dir_all(&dir).ok();
+        create_dir_all(dir.join("bin")).unwrap();
+        File::create(dir.join("bin/hello")).unwrap()
+            .write_all(b"hello").unwrap();
+        let (private, public) = keypair(&[1u8; 32]);
+        let (_, other) = keypair(&[2u8; 32]);
+        sign(&dir, &private).unwrap();
+        verify(&dir, &[other, public]).unwrap();
+        assert!(verify(&dir, &[other]).unwrap_err()
+            .contains("not signed"));
+        set_permissions(dir.join("bin/hello"),
+            PermissionsExt::from_mode(0o4755)).unwrap();
+        assert_eq!(verify(&dir, &[public]).unwrap_err(),
+            r#"image is modified: "bin/hello" is changed"#);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff() {
+        let mut entries = BTreeMap::new();
+        entries.insert("bin".to_string(),
+            Entry::Dir { mode: 0o755, uid: 0, gid: 0 });
+        entries.insert("bin/sh".to_string(), Entry::Symlink {
+            target: PathBuf::from("busybox"),
+        });
+        let signed = Manifest { entries: entries.clone() };
+        assert_eq!(signed.diff(&signed.clone()), None);
+        entries.insert("bin".to_string(),
+            Entry::Dir { mode: 0o755, uid: 1000, gid: 0 });
+        assert_eq!(signed.diff(&Manifest { entries: entries.clone() }),
+            Some(r#""bin" is changed"#.to_string()));
+        entries.insert("bin/ls".to_string(), Entry::File {
+            mode: 0o4755, uid: 0, gid: 0, sha256: "00".to_string(),
+        });
+        entries.insert("bin".to_string(),
+            Entry::Dir { mode: 0o755, uid: 0, gid: 0 });
+        assert_eq!(signed.diff(&Manifest { entries }),
+            Some(r#""bin/ls" is not in manifest"#.to_string()));
+    }
+}
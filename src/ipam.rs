@@ -0,0 +1,264 @@
+//! Automatic address allocation for bridged networks
+//!
+//! Every lease is a file `<runtime_dir>/leases/<sandbox>/<child>.<N>`
+//! containing the address, so an instance gets the same address when it
+//! is restarted or when the whole tree is restarted.
+use std::fmt;
+use std::fs::{File, create_dir_all, read_dir, remove_file, rename};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf, Component};
+use std::str::FromStr;
+
+use serde::de::{Deserializer, Deserialize, Error};
+
+
+/// Name of the directory in the `runtime_dir` where leases are stored
+pub const LEASE_DIR: &'static str = "leases";
+
+/// Range of addresses (inclusive) to allocate from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pool {
+    pub start: IpAddr,
+    pub end: IpAddr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    /// Name of the instance in the form of `sandbox/child.N`
+    pub name: String,
+    pub address: IpAddr,
+}
+
+fn to_num(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+impl Pool {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.start.is_ipv4() &&
+            to_num(ip) >= to_num(self.start) &&
+            to_num(ip) <= to_num(self.end)
+    }
+    pub fn addresses(&self) -> impl Iterator<Item=IpAddr> {
+        let v4 = self.start.is_ipv4();
+        (to_num(self.start)..=to_num(self.end)).map(move |x| if v4 {
+            IpAddr::V4(Ipv4Addr::from(x as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(x))
+        })
+    }
+}
+
+impl FromStr for Pool {
+    type Err = String;
+    fn from_str(val: &str) -> Result<Pool, String> {
+        let mut pair = val.splitn(2, '-');
+        let start: IpAddr = pair.next().unwrap().trim().parse()
+            .map_err(|e| format!("invalid pool start: {}", e))?;
+        let end: IpAddr = pair.next()
+            .ok_or_else(|| format!("pool must be in form `START-END`"))?
+            .trim().parse()
+            .map_err(|e| format!("invalid pool end: {}", e))?;
+        if start.is_ipv4() != end.is_ipv4() {
+            return Err(format!("both ends of pool must be of \
+                the same address family"));
+        }
+        if to_num(start) > to_num(end) {
+            return Err(format!("pool start is larger than end"));
+        }
+        Ok(Pool { start, end })
+    }
+}
+
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl<'a> Deserialize<'a> for Pool {
+    fn deserialize<D: Deserializer<'a>>(d: D) -> Result<Pool, D::Error> {
+        let val = String::deserialize(d)?;
+        val.parse().map_err(D::Error::custom)
+    }
+}
+
+fn lease_path(runtime_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let rel = Path::new(name);
+    let ok = rel.components().count() == 2 &&
+        rel.components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        });
+    if !ok {
+        return Err(format!("invalid instance name {:?}, \
+            `sandbox/child.N` expected", name));
+    }
+    Ok(runtime_dir.join(LEASE_DIR).join(rel))
+}
+
+fn read_lease(path: &Path) -> Result<Option<IpAddr>, String> {
+    let mut buf = String::with_capacity(64);
+    match File::open(path) {
+        Ok(mut f) => f.read_to_string(&mut buf)
+            .map_err(|e| format!("can't read {:?}: {}", path, e))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("can't read {:?}: {}", path, e)),
+    };
+    buf.trim().parse().map(Some)
+        .map_err(|e| format!("invalid lease {:?}: {}", path, e))
+}
+
+/// Writes the lease atomically, so it's never seen partially written
+fn write_lease(path: &Path, ip: IpAddr) -> Result<(), String> {
+    let tmp = path.with_file_name(format!(".{}.tmp",
+        path.file_name().and_then(|x| x.to_str()).unwrap()));
+    create_dir_all(path.parent().unwrap())
+        .and_then(|()| File::create(&tmp))
+        .and_then(|mut f| {
+            f.write_all(format!("{}\n", ip).as_bytes())?;
+            f.sync_all()
+        })
+        .and_then(|()| rename(&tmp, path))
+        .map_err(|e| format!("can't write {:?}: {}", path, e))
+}
+
+/// Reads all leases (of all sandboxes) sorted by name
+///
+/// Leases that can't be read or parsed are skipped with a warning.
+pub fn read_leases(runtime_dir: &Path) -> Result<Vec<Lease>, String> {
+    let dir = runtime_dir.join(LEASE_DIR);
+    let sandboxes = match read_dir(&dir) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(format!("can't read {:?}: {}", dir, e)),
+    };
+    let mut leases = Vec::new();
+    for sandbox in sandboxes {
+        let sandbox = sandbox
+            .map_err(|e| format!("can't read {:?}: {}", dir, e))?;
+        let sdir = sandbox.path();
+        let items = read_dir(&sdir)
+            .map_err(|e| format!("can't read {:?}: {}", sdir, e))?;
+        for item in items {
+            let item = item
+                .map_err(|e| format!("can't read {:?}: {}", sdir, e))?;
+            let name = match (sandbox.file_name().to_str(),
+                              item.file_name().to_str())
+            {
+                (Some(s), Some(c)) => format!("{}/{}", s, c),
+                _ => {
+                    warn!("Skipping lease with non-utf8 name {:?}",
+                        item.path());
+                    continue;
+                }
+            };
+            if item.file_name().to_str().map_or(false, |x| x.starts_with("."))
+            {
+                continue;  // temporary file of `write_lease`
+            }
+            match read_lease(&item.path()) {
+                Ok(Some(address)) => leases.push(Lease { name, address }),
+                Ok(None) => {}
+                Err(e) => warn!("Skipping lease: {}", e),
+            }
+        }
+    }
+    leases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(leases)
+}
+
+/// Returns address leased to the instance, allocating a new one if needed
+///
+/// Existing lease is reused if it's still within the pool. Addresses are
+/// unique among leases of all sandboxes.
+pub fn allocate(runtime_dir: &Path, name: &str, pool: &Pool)
+    -> Result<IpAddr, String>
+{
+    let path = lease_path(runtime_dir, name)?;
+    let lease = read_lease(&path).unwrap_or_else(|e| {
+        warn!("Replacing lease: {}", e);
+        None
+    });
+    match lease {
+        Some(ip) if pool.contains(ip) => return Ok(ip),
+        Some(ip) => {
+            info!("Address {} of {:?} is not in pool {} any more",
+                ip, name, pool);
+        }
+        None => {}
+    }
+    let used = read_leases(runtime_dir)?.into_iter()
+        .filter(|l| l.name != name)
+        .map(|l| l.address)
+        .collect::<Vec<_>>();
+    let ip = pool.addresses().find(|ip| !used.contains(ip))
+        .ok_or_else(|| format!("no free addresses left in pool {}", pool))?;
+    write_lease(&path, ip)?;
+    Ok(ip)
+}
+
+/// Removes the lease, returns released address if there was one
+pub fn release(runtime_dir: &Path, name: &str)
+    -> Result<Option<IpAddr>, String>
+{
+    let path = lease_path(runtime_dir, name)?;
+    let ip = read_lease(&path)?;
+    if ip.is_some() {
+        remove_file(&path)
+            .map_err(|e| format!("can't remove {:?}: {}", path, e))?;
+    }
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{remove_dir_all, write};
+    use super::{Pool, Lease, allocate, release, read_leases};
+
+    #[test]
+    fn parse_pool() {
+        let pool: Pool = "10.0.0.254-10.0.1.1".parse().unwrap();
+        assert_eq!(pool.addresses().map(|x| x.to_string())
+            .collect::<Vec<_>>(),
+            vec!["10.0.0.254", "10.0.0.255", "10.0.1.0", "10.0.1.1"]);
+        assert!(pool.contains("10.0.1.0".parse().unwrap()));
+        assert!(!pool.contains("10.0.1.2".parse().unwrap()));
+        assert!("10.0.0.2-fd00::1".parse::<Pool>().is_err());
+        assert!("10.0.0.2-10.0.0.1".parse::<Pool>().is_err());
+        assert!("10.0.0.2".parse::<Pool>().is_err());
+    }
+
+    #[test]
+    fn leases() {
+        let dir = temp_dir().join("lithos-test-ipam");
+        remove_dir_all(&dir).ok();
+        let pool: Pool = "10.0.0.10-10.0.0.11".parse().unwrap();
+        let a = allocate(&dir, "sb/web.0", &pool).unwrap();
+        let b = allocate(&dir, "sb/web.1", &pool).unwrap();
+        assert_eq!(a.to_string(), "10.0.0.10");
+        assert_eq!(b.to_string(), "10.0.0.11");
+        assert_eq!(allocate(&dir, "sb/web.0", &pool).unwrap(), a);
+        assert!(allocate(&dir, "other/web.0", &pool).is_err());
+        assert!(allocate(&dir, "../web.0", &pool).is_err());
+        assert_eq!(release(&dir, "sb/web.0").unwrap(), Some(a));
+        assert_eq!(release(&dir, "sb/web.0").unwrap(), None);
+        assert_eq!(allocate(&dir, "other/web.0", &pool).unwrap(), a);
+        assert_eq!(read_leases(&dir).unwrap(), vec![
+            Lease { name: "other/web.0".into(), address: a },
+            Lease { name: "sb/web.1".into(), address: b },
+        ]);
+        // partially written leases are skipped
+        write(dir.join("leases/sb/web.2"), "10.0.").unwrap();
+        write(dir.join("leases/sb/.web.3.tmp"), "10.0.0.12\n").unwrap();
+        assert_eq!(read_leases(&dir).unwrap().len(), 2);
+        remove_dir_all(&dir).unwrap();
+    }
+}
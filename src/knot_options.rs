@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 use std::env;
 use std::io::{stdout, stderr};
 use std::io::{Write};
+use std::os::unix::io::RawFd;
 use std::path::{PathBuf};
 
 use log;
@@ -11,6 +12,14 @@ use argparse::{Print};
 use child_config::ChildInstance;
 use child_config::ChildKind::Daemon;
 
+/// Environment variable used by lithos_tree to tell lithos_knot which
+/// inherited file descriptors must be moved to which numbers in container
+///
+/// The value is a comma-separated list of `TARGET:SOURCE` pairs. This is
+/// used for descriptors that can't be passed as is, like 1 and 2 which
+/// lithos_knot uses for its own output.
+pub const REMAP_FDS_VAR: &'static str = "LITHOS_REMAP_FDS";
+
 
 pub struct Options {
     pub master_config: PathBuf,
@@ -39,6 +48,8 @@ impl Options {
                 variables: BTreeMap::new(),
                 extra_secrets_namespaces: Vec::new(),
                 ip_address: None,
+                ipv6_address: None,
+                extra_addresses: Vec::new(),
                 kind: Daemon,
             },
             name: "".to_string(),
@@ -83,3 +94,42 @@ impl Options {
         }
     }
 }
+
+pub fn format_fd_map(map: &[(RawFd, RawFd)]) -> String {
+    map.iter()
+        .map(|&(target, source)| format!("{}:{}", target, source))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn parse_fd_map(value: &str) -> Result<Vec<(RawFd, RawFd)>, String> {
+    let mut result = Vec::new();
+    for pair in value.split(',').filter(|x| !x.is_empty()) {
+        let mut items = pair.splitn(2, ':');
+        let target = items.next().unwrap().parse();
+        let source = items.next().map(|x| x.parse());
+        match (target, source) {
+            (Ok(target), Some(Ok(source))) if target >= 0 && source > 2 => {
+                result.push((target, source));
+            }
+            _ => return Err(format!("invalid fd mapping {:?}", pair)),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_fd_map, parse_fd_map};
+
+    #[test]
+    fn fd_map() {
+        let map = vec![(1, 5), (2, 6)];
+        assert_eq!(format_fd_map(&map), "1:5,2:6");
+        assert_eq!(parse_fd_map("1:5,2:6").unwrap(), map);
+        assert_eq!(parse_fd_map("").unwrap(), vec![]);
+        assert!(parse_fd_map("1:2").is_err());
+        assert!(parse_fd_map("1").is_err());
+        assert!(parse_fd_map("x:7").is_err());
+    }
+}
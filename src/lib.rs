@@ -20,6 +20,7 @@ extern crate serde_json;
 extern crate serde_str;
 extern crate signal;
 extern crate sha2;
+extern crate ssh_keys;
 extern crate syslog;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate log;
@@ -35,6 +36,20 @@ pub mod network;
 pub mod setup;
 pub mod pipe;
 pub mod limits;
+pub mod core_dumps;
+pub mod cpuset;
+pub mod scheduling;
+pub mod quota;
+pub mod devices;
+pub mod mount_plan;
+pub mod image_manifest;
+pub mod netlink;
+pub mod ipam;
+pub mod nftables;
+pub mod port_forward;
+pub mod network_policy;
+pub mod bandwidth;
+pub mod resolv_conf;
 pub mod cgroup;
 pub mod itertools;
 pub mod timer_queue;
@@ -1,25 +1,148 @@
 use std::io::Error as IoError;
-use libc::c_int;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-static RLIMIT_NOFILE: c_int = 7;
+use libc::{c_int, setrlimit, rlimit, RLIM_INFINITY};
+use libc::{RLIMIT_NOFILE, RLIMIT_CORE, RLIMIT_NPROC, RLIMIT_MEMLOCK};
+use libc::{RLIMIT_STACK, RLIMIT_AS, RLIMIT_NICE, RLIMIT_RTPRIO};
+use libc::{RLIMIT_MSGQUEUE};
+use quire::validate::{Structure, Numeric};
 
-#[repr(C)]
-struct rlimit {
-    rlim_cur: u64,
-    rlim_max: u64,
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all="lowercase")]
+pub enum Resource {
+    Core,
+    Nproc,
+    Memlock,
+    Stack,
+    As,
+    Nice,
+    Rtprio,
+    Msgqueue,
 }
 
-extern "C" {
-    fn setrlimit(resource: c_int, rlimit: *const rlimit) -> c_int;
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    pub soft: u64,
+    pub hard: u64,
 }
 
-pub fn set_fileno_limit(limit: u64) -> Result<(), IoError> {
-    let res = unsafe { setrlimit(RLIMIT_NOFILE, &rlimit {
-        rlim_cur: limit,
-        rlim_max: limit,
+impl Rlimit {
+    pub fn unlimited() -> Rlimit {
+        Rlimit { soft: RLIM_INFINITY, hard: RLIM_INFINITY }
+    }
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("soft", Numeric::new().min(0))
+        .member("hard", Numeric::new().min(0))
+    }
+    /// Checks that limit is consistent and fits into sandbox's maximum
+    ///
+    /// `None` as maximum means that sandbox doesn't allow the resource
+    pub fn check(&self, resource: Resource, maximum: Option<u64>)
+        -> Result<(), String>
+    {
+        if self.soft > self.hard {
+            return Err(format!("Soft limit {} of rlimit {:?} is larger \
+                than hard limit {}", self.soft, resource, self.hard));
+        }
+        match maximum {
+            Some(max) if self.hard > max => {
+                Err(format!("Hard limit {} of rlimit {:?} is larger than \
+                    allowed by sandbox ({})", self.hard, resource, max))
+            }
+            Some(_) => Ok(()),
+            None => {
+                Err(format!("Rlimit {:?} is not allowed by sandbox \
+                    (probably missing entry in max-rlimits)", resource))
+            }
+        }
+    }
+}
+
+fn set_limit(resource: c_int, soft: u64, hard: u64) -> Result<(), IoError> {
+    let res = unsafe { setrlimit(resource, &rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
     }) };
     if res != 0 {
         return Err(IoError::last_os_error());
     }
     return Ok(());
 }
+
+pub fn set_fileno_limit(limit: u64) -> Result<(), IoError> {
+    set_limit(RLIMIT_NOFILE, limit, limit)
+}
+
+pub fn set_rlimit(resource: Resource, limit: &Rlimit) -> Result<(), IoError> {
+    let num = match resource {
+        Resource::Core => RLIMIT_CORE,
+        Resource::Nproc => RLIMIT_NPROC,
+        Resource::Memlock => RLIMIT_MEMLOCK,
+        Resource::Stack => RLIMIT_STACK,
+        Resource::As => RLIMIT_AS,
+        Resource::Nice => RLIMIT_NICE,
+        Resource::Rtprio => RLIMIT_RTPRIO,
+        Resource::Msgqueue => RLIMIT_MSGQUEUE,
+    };
+    set_limit(num, limit.soft, limit.hard)
+}
+
+/// Returns directory where kernel writes core dumps
+///
+/// Directory is interpreted relative to the root of the crashing process, so
+/// it's a path inside the container. Returns `None` if core pattern is a
+/// pipe or a relative path (i.e. cores are written to working directory).
+pub fn core_pattern_dir() -> Result<Option<PathBuf>, String> {
+    let mut buf = String::with_capacity(256);
+    File::open("/proc/sys/kernel/core_pattern")
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| format!("Can't read core_pattern: {}", e))?;
+    Ok(parse_core_pattern(buf.trim()))
+}
+
+fn parse_core_pattern(pattern: &str) -> Option<PathBuf> {
+    if pattern.starts_with("|") {
+        return None;
+    }
+    let path = Path::new(pattern);
+    if !path.is_absolute() {
+        return None;
+    }
+    path.parent().map(|x| x.to_path_buf())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::{Rlimit, Resource, parse_core_pattern};
+
+    #[test]
+    fn check_soft_hard() {
+        let lim = Rlimit { soft: 10, hard: 5 };
+        assert!(lim.check(Resource::Nproc, Some(100)).is_err());
+        let lim = Rlimit { soft: 5, hard: 10 };
+        assert!(lim.check(Resource::Nproc, Some(100)).is_ok());
+    }
+
+    #[test]
+    fn check_maximum() {
+        let lim = Rlimit { soft: 5, hard: 10 };
+        assert!(lim.check(Resource::Core, Some(10)).is_ok());
+        assert!(lim.check(Resource::Core, Some(9)).is_err());
+        assert!(lim.check(Resource::Core, None).is_err());
+    }
+
+    #[test]
+    fn core_pattern() {
+        assert_eq!(parse_core_pattern("core"), None);
+        assert_eq!(parse_core_pattern("|/usr/lib/systemd/coredump %P"),
+            None);
+        assert_eq!(parse_core_pattern("/var/cores/core.%e.%p"),
+            Some(Path::new("/var/cores").to_path_buf()));
+    }
+}
@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 use quire::validate::{Structure, Sequence};
@@ -21,6 +22,8 @@ pub struct MasterConfig {
     pub log_level: String,
     pub cgroup_name: Option<String>,
     pub cgroup_controllers: Vec<String>,
+    pub public_hostname: Option<String>,
+    pub public_addresses: Vec<IpAddr>,
 }
 
 impl MasterConfig {
@@ -45,6 +48,8 @@ impl MasterConfig {
         .member("cgroup_name",
             Scalar::new().optional().default("lithos.slice"))
         .member("cgroup_controllers", Sequence::new(Scalar::new()))
+        .member("public_hostname", Scalar::new().optional())
+        .member("public_addresses", Sequence::new(Scalar::new()))
     }
 }
 
@@ -10,6 +10,11 @@ pub struct Process {
     pub running: Integer,
 }
 
+pub struct Volume {
+    pub used: Integer,
+    pub size: Integer,
+}
+
 pub struct Metrics {
     pub restarts: Counter,
     pub sandboxes: Integer,
@@ -23,11 +28,15 @@ pub struct Metrics {
     pub unknown: Integer,
 
     pub processes: HashMap<(String, String), Process>,
+    /// Size-limited volumes by (process name, mount point)
+    pub volumes: HashMap<(String, String), Volume>,
 }
 
 pub struct MasterName(&'static str);
 pub struct GlobalName(&'static str);
 pub struct ProcessName<'a>(&'a str, &'a str, &'static str);
+/// Group (derived from the process name), mount point and metric
+pub struct VolumeName<'a>(String, &'a str, &'static str);
 
 impl Metrics {
     pub fn new() -> Metrics {
@@ -44,6 +53,7 @@ impl Metrics {
             queue: Integer::new(),
 
             processes: HashMap::new(),
+            volumes: HashMap::new(),
         }
     }
 }
@@ -59,6 +69,14 @@ impl Process {
     }
 }
 
+impl Volume {
+    pub fn new() -> Volume {
+        Volume {
+            used: Integer::new(),
+            size: Integer::new(),
+        }
+    }
+}
 
 impl Collection for Metrics {
     fn visit<'x>(&'x self, visitor: &mut Visitor<'x>) {
@@ -77,6 +95,12 @@ impl Collection for Metrics {
             visitor.metric(&ProcessName(g, n, "deaths"), &p.deaths);
             visitor.metric(&ProcessName(g, n, "running"), &p.running);
         }
+        for (&(ref n, ref mp), ref v) in &self.volumes {
+            let group = format!("processes.{}", n.replace("/", "."));
+            visitor.metric(&VolumeName(group.clone(), mp, "volume_used"),
+                &v.used);
+            visitor.metric(&VolumeName(group, mp, "volume_size"), &v.size);
+        }
     }
 }
 
@@ -117,3 +141,19 @@ impl<'a> Name for ProcessName<'a> {
         s.visit_pair("metric", self.2);
     }
 }
+
+impl<'a> Name for VolumeName<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "group" => Some(&self.0),
+            "volume" => Some(self.1),
+            "metric" => Some(self.2),
+            _ => None,
+        }
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", &self.0);
+        s.visit_pair("volume", self.1);
+        s.visit_pair("metric", self.2);
+    }
+}
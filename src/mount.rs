@@ -1,59 +1,25 @@
-#![allow(dead_code)]
 use std::io::Error as IoError;
 use std::ffi::CString;
+use std::mem::zeroed;
 use std::ptr::null;
 use std::path::Path;
-use libc::{c_ulong, c_int};
+use libc::{c_ulong, c_void, statvfs, mount, umount2};
+use libc::{MS_RDONLY, MS_NOSUID, MS_NODEV, MS_NOEXEC, MS_REMOUNT, MS_BIND};
+use libc::{MS_NOATIME, MS_NODIRATIME, MS_RELATIME, MS_REC};
+use libc::{MS_PRIVATE, MS_SLAVE, MS_SHARED, MS_UNBINDABLE, MNT_DETACH};
 
 use super::itertools::{NextValue, NextStr, words};
 use super::utils::cpath;
+use super::container_config::{MountOptions, Propagation};
 
-// sys/mount.h
-static MS_RDONLY: c_ulong = 1;                /* Mount read-only.  */
-static MS_NOSUID: c_ulong = 2;                /* Ignore suid and sgid bits.  */
-static MS_NODEV: c_ulong = 4;                 /* Disallow access to device special files.  */
-static MS_NOEXEC: c_ulong = 8;                /* Disallow program execution.  */
-static MS_SYNCHRONOUS: c_ulong = 16;          /* Writes are synced at once.  */
-static MS_REMOUNT: c_ulong = 32;              /* Alter flags of a mounted FS.  */
-static MS_MANDLOCK: c_ulong = 64;             /* Allow mandatory locks on an FS.  */
-static MS_DIRSYNC: c_ulong = 128;             /* Directory modifications are synchronous.  */
-static MS_NOATIME: c_ulong = 1024;            /* Do not update access times.  */
-static MS_NODIRATIME: c_ulong = 2048;         /* Do not update directory access times.  */
-static MS_BIND: c_ulong = 4096;               /* Bind directory at different place.  */
-static MS_MOVE: c_ulong = 8192;
-static MS_REC: c_ulong = 16384;
-static MS_SILENT: c_ulong = 32768;
-static MS_POSIXACL: c_ulong = 1 << 16;        /* VFS does not apply the umask.  */
-static MS_UNBINDABLE: c_ulong = 1 << 17;      /* Change to unbindable.  */
-static MS_PRIVATE: c_ulong = 1 << 18;         /* Change to private.  */
-static MS_SLAVE: c_ulong = 1 << 19;           /* Change to slave.  */
-static MS_SHARED: c_ulong = 1 << 20;          /* Change to shared.  */
-static MS_RELATIME: c_ulong = 1 << 21;        /* Update atime relative to mtime/ctime.  */
-static MS_KERNMOUNT: c_ulong = 1 << 22;       /* This is a kern_mount call.  */
-static MS_I_VERSION: c_ulong =  1 << 23;      /* Update inode I_version field.  */
-static MS_STRICTATIME: c_ulong = 1 << 24;     /* Always perform atime updates.  */
-static MS_ACTIVE: c_ulong = 1 << 30;
-static MS_NOUSER: c_ulong = 1 << 31;
-
-static MNT_FORCE: c_int = 1;           /* Force unmounting.  */
-static MNT_DETACH: c_int = 2;          /* Just detach from the tree.  */
-static MNT_EXPIRE: c_int = 4;          /* Mark for expiry.  */
-static UMOUNT_NOFOLLOW: c_int = 8;     /* Don't follow symlink on umount.  */
-
-
-extern {
-    fn mount(source: *const i8, target: *const i8,
-        filesystemtype: *const i8, flags: c_ulong,
-        data: *const i8) -> c_int;
-    fn umount(target: *const i8) -> c_int;
-    fn umount2(target: *const i8, flags: c_int) -> c_int;
-}
+// sys/statvfs.h (other ST_* flags are the same as MS_*)
+const ST_RELATIME: c_ulong = 4096;
 
 
 pub struct MountRecord<'a> {
     pub mount_id: usize,
     pub parent_id: usize,
-    _device: &'a str,  // TODO(tailhook) parse if ever need
+    pub device: &'a str,
     pub relative_root: &'a str,
     pub mount_point: &'a str,
     pub mount_options: &'a str,
@@ -106,7 +72,7 @@ impl<'a> MountRecord<'a> {
         return Ok(MountRecord {
             mount_id: mount_id,
             parent_id: parent_id,
-            _device: device,
+            device: device,
             relative_root: relative_root,
             mount_point: mount_point,
             mount_options: mount_options,
@@ -142,6 +108,76 @@ pub fn mount_ro_recursive(target: &Path) -> Result<(), String> {
     return Ok(());
 }
 
+/// Remounts a mount point with options of the volume
+///
+/// Flags that the mount point already has (e.g. `nosuid` inherited from
+/// the source of a bind mount) are kept, since bind remount replaces all of
+/// them.
+pub fn remount(target: &Path, readonly: bool, options: &MountOptions)
+    -> Result<(), String>
+{
+    if options.noexec || options.nosuid || options.nodev || options.noatime
+        || readonly
+    {
+        let c_target = cpath(target);
+        let mut stat: statvfs = unsafe { zeroed() };
+        if unsafe { statvfs(c_target.as_ptr(), &mut stat) } != 0 {
+            let err = IoError::last_os_error();
+            return Err(format!("Can't stat {}: {}", target.display(), err));
+        }
+        let old = stat.f_flag as c_ulong;
+        let mut flags = MS_BIND | MS_REMOUNT |
+            (old & (MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC |
+                    MS_NOATIME | MS_NODIRATIME));
+        if old & ST_RELATIME != 0 {
+            flags |= MS_RELATIME;
+        }
+        if readonly { flags |= MS_RDONLY; }
+        if options.noexec { flags |= MS_NOEXEC; }
+        if options.nosuid { flags |= MS_NOSUID; }
+        if options.nodev { flags |= MS_NODEV; }
+        if options.noatime { flags = (flags & !MS_RELATIME) | MS_NOATIME; }
+        let none = CString::new("none").unwrap();
+        debug!("Remount {:?} flags {:x}", target, flags);
+        let rc = unsafe { mount(
+           none.as_ptr(),
+           c_target.as_ptr(),
+           null(), flags, null()) };
+        if rc != 0 {
+            let err = IoError::last_os_error();
+            return Err(format!("Remount {}: {}", target.display(), err));
+        }
+    }
+    if let Some(propagation) = options.propagation {
+        set_propagation(target, propagation)?;
+    }
+    return Ok(());
+}
+
+pub fn set_propagation(target: &Path, propagation: Propagation)
+    -> Result<(), String>
+{
+    let none = CString::new("none").unwrap();
+    let c_target = cpath(target);
+    let flag = match propagation {
+        Propagation::Private => MS_PRIVATE,
+        Propagation::Slave => MS_SLAVE,
+        Propagation::Shared => MS_SHARED,
+        Propagation::Unbindable => MS_UNBINDABLE,
+    };
+    debug!("Making {:?} {:?}", target, propagation);
+    let rc = unsafe { mount(
+        none.as_ptr(),
+        c_target.as_ptr(),
+        null(), flag, null()) };
+    if rc != 0 {
+        let err = IoError::last_os_error();
+        return Err(format!("Can't set propagation {:?} for {}: {}",
+            propagation, target.display(), err));
+    }
+    return Ok(());
+}
+
 pub fn mount_private(target: &Path) -> Result<(), String> {
     let none = CString::new("none").unwrap();
     let c_target = cpath(target);
@@ -175,7 +211,7 @@ pub fn mount_pseudo(target: &Path, name: &str, options: &str, readonly: bool)
         c_target.as_ptr(),
         c_name.as_ptr(),
         flags,
-        c_opts.as_ptr()) };
+        c_opts.as_ptr() as *const c_void) };
     if rc == 0 {
         return Ok(());
     } else {
@@ -185,6 +221,30 @@ pub fn mount_pseudo(target: &Path, name: &str, options: &str, readonly: bool)
     }
 }
 
+pub fn mount_device(device: &Path, target: &Path, fstype: &str)
+    -> Result<(), String>
+{
+    let c_device = cpath(device);
+    let c_target = cpath(target);
+    let c_fstype = CString::new(fstype).unwrap();
+    let flags = MS_NOSUID | MS_NODEV;
+    debug!("Device mount {} {} {}",
+        device.display(), target.display(), fstype);
+    let rc = unsafe { mount(
+        c_device.as_ptr(),
+        c_target.as_ptr(),
+        c_fstype.as_ptr(),
+        flags,
+        null()) };
+    if rc == 0 {
+        return Ok(());
+    } else {
+        let err = IoError::last_os_error();
+        return Err(format!("Can't mount {} on {} ({}): {}",
+            device.display(), target.display(), fstype, err));
+    }
+}
+
 pub fn mount_pts(target: &Path)
     -> Result<(), String>
 {
@@ -199,7 +259,7 @@ pub fn mount_pts(target: &Path)
         c_target.as_ptr(),
         c_name.as_ptr(),
         flags,
-        c_opts.as_ptr()) };
+        c_opts.as_ptr() as *const c_void) };
     if rc == 0 {
         return Ok(());
     } else {
@@ -220,3 +280,23 @@ pub fn unmount(target: &Path) -> Result<(), String> {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::MountRecord;
+
+    #[test]
+    fn parse_record() {
+        let rec = MountRecord::from_str("36 35 98:0 /mnt1 /mnt2 \
+            rw,noatime master:1 - ext3 /dev/root rw,errors=continue").unwrap();
+        assert_eq!(rec.mount_id, 36);
+        assert_eq!(rec.parent_id, 35);
+        assert_eq!(rec.device, "98:0");
+        assert_eq!(rec.relative_root, "/mnt1");
+        assert_eq!(rec.mount_point, "/mnt2");
+        assert_eq!(rec.tag_master, Some(1));
+        assert_eq!(rec.fstype, "ext3");
+        assert_eq!(rec.mount_source, "/dev/root");
+        assert!(!rec.is_private());
+    }
+}
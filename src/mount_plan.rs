@@ -0,0 +1,834 @@
+//! Computes the list of mounts for the container
+//!
+//! The plan is computed from configs only (no filesystem access, except
+//! things that are checked by the executor), so it can be shown by
+//! `lithos_check --explain-mounts` and tested without root privileges.
+//! Steps are executed by `lithos_knot` in order.
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use container_config::{InstantiatedConfig, Volume, MountOptions};
+use container_config::{OverlayInfo, OverlayStorage};
+use master_config::MasterConfig;
+use sandbox_config::SandboxConfig;
+use limits::Resource;
+use quota::{QuotaMethod, VolumeQuota};
+use resolv_conf;
+use utils::{relative, map_dir};
+
+
+/// Size of the tmpfs for private /dev (it only contains device nodes)
+const DEVFS_SIZE: usize = 65536;
+
+/// Paths hidden from the container unless listed in `unmask-paths`
+pub const MASKED_PATHS: &[&str] = &[
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/acpi",
+    "/proc/scsi",
+    "/sys/firmware",
+];
+
+/// Paths made read-only in the container unless listed in `unmask-paths`
+pub const READONLY_PATHS: &[&str] = &[
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+    "/proc/irq",
+    "/proc/bus",
+];
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag="action", rename_all="kebab-case")]
+pub enum Step {
+    /// Creates directory with all the parents
+    ///
+    /// If `only_new` is set, mode and owner are only applied if the
+    /// directory didn't exist before.
+    CreateDir {
+        path: PathBuf,
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+        only_new: bool,
+    },
+    /// Fails with `error` if the directory doesn't exist
+    ///
+    /// Used instead of `CreateDir` when the directory can't be created
+    /// because owner isn't mapped into the container, as the mapping is
+    /// only needed for new directories.
+    RequireDir {
+        path: PathBuf,
+        error: String,
+    },
+    Bind {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// Bind mount of a single file, checks that source isn't a directory
+    /// and target is a regular file
+    BindFile {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// Bind mount of a generated file into image's `/etc`
+    ///
+    /// Skipped if the file doesn't exist in the image unless `required`
+    BindEtcFile {
+        source: PathBuf,
+        root: PathBuf,
+        file: String,
+        required: bool,
+    },
+    Tmpfs {
+        target: PathBuf,
+        size: usize,
+        mode: u32,
+        owner: Option<(u32, u32)>,
+    },
+    Overlay {
+        lower: PathBuf,
+        upper: PathBuf,
+        work: PathBuf,
+        target: PathBuf,
+    },
+    Pseudo {
+        target: PathBuf,
+        fstype: String,
+        readonly: bool,
+    },
+    Devpts {
+        target: PathBuf,
+    },
+    /// Hides a path of /proc or /sys (skipped if there is no such path)
+    ///
+    /// Files are covered by `/dev/null` and directories by an empty
+    /// read-only tmpfs.
+    Mask {
+        target: PathBuf,
+    },
+    /// Bind-mounts a path of /proc or /sys on itself read-only
+    /// (skipped if there is no such path)
+    ReadonlyPath {
+        target: PathBuf,
+    },
+    /// Copies nodes, dirs and symlinks of `devfs-dir` into private /dev
+    CopyDevfs {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// Creates node of the host device at the same path in private /dev
+    Device {
+        source: PathBuf,
+        dev_dir: PathBuf,
+    },
+    /// Size-limited image file (created on first use)
+    Image {
+        image: PathBuf,
+        target: PathBuf,
+        size: u64,
+        mode: u32,
+        owner: (u32, u32),
+    },
+    ProjectQuota {
+        path: PathBuf,
+        size: u64,
+    },
+    Remount {
+        target: PathBuf,
+        readonly: bool,
+        options: MountOptions,
+    },
+    /// Collects core dumps of previous run and mounts directory for new ones
+    CoreDumps {
+        host_dir: PathBuf,
+        name: String,
+        root: PathBuf,
+    },
+    /// Writes list of size-limited volumes for `lithos_ps`
+    QuotaState {
+        state_dir: PathBuf,
+        quotas: Vec<VolumeQuota>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MountPlan {
+    pub steps: Vec<Step>,
+}
+
+struct Planner<'a> {
+    name: &'a str,
+    sandbox: &'a SandboxConfig,
+    local: &'a InstantiatedConfig,
+    state_dir: PathBuf,
+    steps: Vec<Step>,
+}
+
+impl MountPlan {
+    pub fn new(master: &MasterConfig, sandbox: &SandboxConfig, name: &str,
+        local: &InstantiatedConfig)
+        -> Result<MountPlan, String>
+    {
+        let mut planner = Planner {
+            name, sandbox, local,
+            state_dir: master.runtime_dir.join(&master.state_dir).join(name),
+            steps: Vec::new(),
+        };
+        planner.plan(master, name)?;
+        Ok(MountPlan { steps: planner.steps })
+    }
+}
+
+impl<'a> Planner<'a> {
+    fn owner(&self, user: u32, group: u32, kind: &str, mp: &str)
+        -> Result<(u32, u32), String>
+    {
+        let user = self.local.map_uid(user).ok_or_else(|| {
+            format!("Non-mapped user {} for {} {}", user, kind, mp)
+        })?;
+        let group = self.local.map_gid(group).ok_or_else(|| {
+            format!("Non-mapped group {} for {} {}", group, kind, mp)
+        })?;
+        Ok((user, group))
+    }
+
+    fn remount(&mut self, target: &Path, readonly: bool,
+        options: &MountOptions)
+    {
+        let options = options.merge(&self.sandbox.force_mount_options);
+        if readonly || options != MountOptions::default() {
+            self.steps.push(Step::Remount {
+                target: target.to_path_buf(),
+                readonly, options,
+            });
+        }
+    }
+
+    fn plan(&mut self, master: &MasterConfig, name: &str)
+        -> Result<(), String>
+    {
+        let root = Path::new("/");
+        let mntdir = master.runtime_dir.join(&master.mount_dir);
+        assert!(mntdir.is_absolute());
+        let local = self.local;
+
+        let mut volumes: Vec<(&String, &Volume)> =
+            local.volumes.iter().collect();
+        volumes.sort_by(|&(mp1, _), &(mp2, _)| mp1.len().cmp(&mp2.len()));
+        let mut quotas = Vec::new();
+
+        if let Some(ref opt) = local.overlay_root {
+            self.overlay(&mntdir, "/", opt)?;
+            self.remount(&mntdir, false, &opt.options);
+        }
+
+        let devdir = mntdir.join("dev");
+        if local.devices.is_empty() {
+            self.steps.push(Step::Bind {
+                source: master.devfs_dir.clone(),
+                target: devdir.clone(),
+            });
+        } else {
+            self.steps.push(Step::Tmpfs {
+                target: devdir.clone(),
+                size: DEVFS_SIZE,
+                mode: 0o755,
+                owner: None,
+            });
+            self.steps.push(Step::CopyDevfs {
+                source: master.devfs_dir.clone(),
+                target: devdir.clone(),
+            });
+            for dev in &local.devices {
+                self.steps.push(Step::Device {
+                    source: dev.clone(),
+                    dev_dir: devdir.clone(),
+                });
+            }
+        }
+        self.steps.push(Step::Remount {
+            target: devdir,
+            readonly: true,
+            options: MountOptions::default(),
+        });
+        self.steps.push(Step::Devpts { target: mntdir.join("dev/pts") });
+        if let Some(size) = local.shm_size {
+            // pages are charged to the memory cgroup of the container
+            // processes that touch them
+            self.steps.push(Step::Tmpfs {
+                target: mntdir.join("dev/shm"),
+                size,
+                mode: local.shm_mode,
+                owner: None,
+            });
+        }
+        self.steps.push(Step::Pseudo {
+            target: mntdir.join("sys"),
+            fstype: "sysfs".into(),
+            readonly: true,
+        });
+        self.steps.push(Step::Pseudo {
+            target: mntdir.join("proc"),
+            fstype: "proc".into(),
+            readonly: false,
+        });
+        self.mask_paths(&mntdir);
+
+        for &(mp_str, volume) in volumes.iter() {
+            let tmp_mp = PathBuf::from(&mp_str[..]);
+            if !tmp_mp.is_absolute() {
+                return Err(format!("Volume mount point {:?} must be absolute",
+                    mp_str));
+            }
+
+            let dest = mntdir.join(relative(&tmp_mp, &root));
+            let mut readonly = false;
+            match *volume {
+                Volume::Readonly(ref dir) => {
+                    check_absolute(dir, mp_str)?;
+                    let path = map_dir(dir, &self.sandbox.readonly_paths)
+                        .or_else(|| map_dir(dir, &self.sandbox.writable_paths))
+                        .ok_or_else(|| format!("Can't find volume for {}, \
+                            probably missing entry in readonly-paths",
+                            dir.display()))?;
+                    self.steps.push(Step::Bind {
+                        source: path,
+                        target: dest.clone(),
+                    });
+                    readonly = true;
+                }
+                Volume::Persistent(ref opt) => {
+                    check_absolute(&opt.path, mp_str)?;
+                    let path = map_dir(&opt.path, &self.sandbox.writable_paths)
+                        .ok_or_else(|| format!("Can't find volume for {:?}, \
+                            probably missing entry in writable-paths",
+                            opt.path))?;
+                    match (opt.size, opt.quota) {
+                        (Some(size), QuotaMethod::Loop) => {
+                            let owner = self.owner(opt.user, opt.group,
+                                                   "volume", mp_str)?;
+                            self.steps.push(Step::Image {
+                                image: path,
+                                target: dest.clone(),
+                                size: size as u64,
+                                mode: opt.mode,
+                                owner,
+                            });
+                        }
+                        (size, _) => {
+                            if opt.mkdir {
+                                match self.owner(opt.user, opt.group,
+                                                 "volume", mp_str)
+                                {
+                                    Ok(owner) => {
+                                        self.steps.push(Step::CreateDir {
+                                            path: path.clone(),
+                                            mode: Some(opt.mode),
+                                            owner: Some(owner),
+                                            only_new: true,
+                                        });
+                                    }
+                                    Err(error) => {
+                                        self.steps.push(Step::RequireDir {
+                                            path: path.clone(),
+                                            error,
+                                        });
+                                    }
+                                }
+                            }
+                            if let Some(size) = size {
+                                self.steps.push(Step::ProjectQuota {
+                                    path: path.clone(),
+                                    size: size as u64,
+                                });
+                            }
+                            self.steps.push(Step::Bind {
+                                source: path,
+                                target: dest.clone(),
+                            });
+                        }
+                    }
+                    if let Some(size) = opt.size {
+                        quotas.push(VolumeQuota {
+                            mount_point: mp_str.clone(),
+                            method: opt.quota,
+                            size: size as u64,
+                        });
+                    }
+                }
+                Volume::Tmpfs(ref opt) => {
+                    self.steps.push(Step::Tmpfs {
+                        target: dest.clone(),
+                        size: opt.size,
+                        mode: opt.mode,
+                        owner: None,
+                    });
+                }
+                Volume::Statedir(ref opt) => {
+                    let relative_dir = relative(&opt.path, &root);
+                    let dir = self.state_dir.join(&relative_dir);
+                    if Path::new(&relative_dir) != Path::new(".") {
+                        let owner = self.owner(opt.user, opt.group,
+                                               "volume", mp_str)?;
+                        self.steps.push(Step::CreateDir {
+                            path: dir.clone(),
+                            mode: Some(opt.mode),
+                            owner: Some(owner),
+                            only_new: false,
+                        });
+                    }
+                    self.steps.push(Step::Bind {
+                        source: dir,
+                        target: dest.clone(),
+                    });
+                }
+                Volume::Overlay(ref opt) => {
+                    self.overlay(&dest, mp_str, opt)?;
+                }
+                Volume::File(ref opt) => {
+                    check_absolute(&opt.path, mp_str)?;
+                    let path = if opt.writable {
+                        map_dir(&opt.path, &self.sandbox.writable_paths)
+                    } else {
+                        map_dir(&opt.path, &self.sandbox.readonly_paths)
+                        .or_else(
+                            || map_dir(&opt.path, &self.sandbox.writable_paths))
+                    };
+                    let path = path.ok_or_else(|| format!(
+                        "Can't find volume for {:?}, probably missing \
+                        entry in {}", opt.path,
+                        if opt.writable { "writable-paths" }
+                        else { "readonly-paths" }))?;
+                    self.steps.push(Step::BindFile {
+                        source: path,
+                        target: dest.clone(),
+                    });
+                    readonly = !opt.writable;
+                }
+                Volume::SecretFiles(ref opt) => {
+                    // files are written by the knot when decrypted
+                    let owner = self.owner(opt.user, opt.group,
+                                           "volume", mp_str)?;
+                    self.steps.push(Step::Tmpfs {
+                        target: dest.clone(),
+                        size: opt.size,
+                        mode: 0o750,
+                        owner: Some(owner),
+                    });
+                }
+            }
+            self.remount(&dest, readonly, &volume.mount_options());
+        }
+
+        self.steps.push(Step::QuotaState {
+            state_dir: self.state_dir.clone(),
+            quotas,
+        });
+
+        match local.rlimits.get(&Resource::Core) {
+            Some(lim) if lim.hard > 0 => {
+                let host_dir = self.sandbox.core_dump_dir.as_ref()
+                    .ok_or_else(|| format!("core dumps are enabled in \
+                        rlimits, but there is no core-dump-dir in \
+                        sandbox config"))?;
+                self.steps.push(Step::CoreDumps {
+                    host_dir: host_dir.clone(),
+                    name: name.to_string(),
+                    root: mntdir.clone(),
+                });
+            }
+            _ => {}
+        }
+
+        let resolv = &local.resolv_conf;
+        if resolv.mount != Some(false) &&
+            (resolv.mount.is_some() || resolv.copy_from_host ||
+             resolv_conf::is_configured(&self.sandbox.dns, resolv))
+        {
+            self.steps.push(Step::BindEtcFile {
+                source: self.state_dir.join("resolv.conf"),
+                root: mntdir.clone(),
+                file: "resolv.conf".into(),
+                required: resolv.mount == Some(true),
+            });
+        }
+        if local.hosts_file.mount != Some(false) {
+            self.steps.push(Step::BindEtcFile {
+                source: self.state_dir.join("hosts"),
+                root: mntdir.clone(),
+                file: "hosts".into(),
+                required: local.hosts_file.mount == Some(true),
+            });
+        }
+        Ok(())
+    }
+
+    fn mask_paths(&mut self, mntdir: &Path) {
+        let unmask = &self.sandbox.unmask_paths;
+        let defaults = MASKED_PATHS.iter().map(Path::new)
+            .filter(|p| !unmask.iter().any(|u| u == p));
+        let masked = defaults.chain(
+            self.sandbox.mask_paths.iter().map(|p| p.as_path()))
+            .map(|p| mntdir.join(relative(p, Path::new("/"))))
+            .collect::<Vec<_>>();
+        let readonly = READONLY_PATHS.iter().map(Path::new)
+            .filter(|p| !unmask.iter().any(|u| u == p))
+            .map(|p| mntdir.join(relative(p, Path::new("/"))))
+            .collect::<Vec<_>>();
+        for target in readonly {
+            self.steps.push(Step::ReadonlyPath { target });
+        }
+        for target in masked {
+            self.steps.push(Step::Mask { target });
+        }
+    }
+
+    /// Mounts overlayfs on top of `dest` using `dest` itself as a lower layer
+    fn overlay(&mut self, dest: &Path, mp: &str, opt: &OverlayInfo)
+        -> Result<(), String>
+    {
+        // name of the directory for this overlay, e.g. `/app/data` -> `app:data`
+        let name = match mp.trim_matches('/') {
+            "" => "root".to_string(),
+            x => x.replace("/", ":"),
+        };
+        let base = match opt.storage {
+            OverlayStorage::Tmpfs | OverlayStorage::Statedir => {
+                let dir = self.state_dir.join(".overlays").join(&name);
+                self.steps.push(Step::CreateDir {
+                    path: dir.clone(),
+                    mode: None,
+                    owner: None,
+                    only_new: false,
+                });
+                if opt.storage == OverlayStorage::Tmpfs {
+                    self.steps.push(Step::Tmpfs {
+                        target: dir.clone(),
+                        size: opt.size,
+                        mode: 0o755,
+                        owner: None,
+                    });
+                }
+                dir
+            }
+            OverlayStorage::Persistent => {
+                let path = opt.path.as_ref().ok_or_else(|| format!(
+                    "Overlay {:?} with persistent storage requires `path`",
+                    mp))?;
+                check_absolute(path, mp)?;
+                let dir = map_dir(path, &self.sandbox.writable_paths)
+                    .ok_or_else(|| format!("Can't find volume for {:?}, \
+                        probably missing entry in writable-paths", path))?;
+                // layers can't be shared, so every instance has its own
+                dir.join(self.name.replace("/", ":"))
+            }
+        };
+        let upper = base.join("upper");
+        let work = base.join("work");
+        let owner = self.owner(opt.user, opt.group, "overlay", mp)?;
+        self.steps.push(Step::CreateDir {
+            path: upper.clone(),
+            mode: Some(opt.mode),
+            owner: Some(owner),
+            only_new: false,
+        });
+        self.steps.push(Step::CreateDir {
+            path: work.clone(),
+            mode: None,
+            owner: None,
+            only_new: false,
+        });
+        self.steps.push(Step::Overlay {
+            lower: dest.to_path_buf(),
+            upper, work,
+            target: dest.to_path_buf(),
+        });
+        Ok(())
+    }
+}
+
+fn check_absolute(path: &Path, mp: &str) -> Result<(), String> {
+    if !path.is_absolute() {
+        return Err(format!("Volume path {:?} of {:?} must be absolute",
+            path, mp));
+    }
+    Ok(())
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Step::*;
+        match *self {
+            CreateDir { ref path, mode, owner, only_new } => {
+                write!(f, "mkdir {:?}", path)?;
+                if let Some(mode) = mode {
+                    write!(f, " mode 0o{:o}", mode)?;
+                }
+                if let Some((user, group)) = owner {
+                    write!(f, " owner {}:{}", user, group)?;
+                }
+                if only_new {
+                    write!(f, " (if missing)")?;
+                }
+                Ok(())
+            }
+            RequireDir { ref path, ref error } => {
+                write!(f, "require dir {:?} (can't create: {})", path, error)
+            }
+            Bind { ref source, ref target } => {
+                write!(f, "bind {:?} -> {:?}", source, target)
+            }
+            BindFile { ref source, ref target } => {
+                write!(f, "bind file {:?} -> {:?}", source, target)
+            }
+            BindEtcFile { ref source, ref root, ref file, required } => {
+                write!(f, "bind {:?} -> {:?}{}", source,
+                    root.join("etc").join(file),
+                    if required { "" } else { " (if exists in image)" })
+            }
+            Tmpfs { ref target, size, mode, owner } => {
+                write!(f, "tmpfs {:?} size {} mode 0o{:o}", target, size, mode)?;
+                if let Some((user, group)) = owner {
+                    write!(f, " owner {}:{}", user, group)?;
+                }
+                Ok(())
+            }
+            Overlay { ref lower, ref upper, ref work, ref target } => {
+                write!(f, "overlay {:?} lower {:?} upper {:?} work {:?}",
+                    target, lower, upper, work)
+            }
+            Pseudo { ref target, ref fstype, readonly } => {
+                write!(f, "{} {:?}{}", fstype, target,
+                    if readonly { " (ro)" } else { "" })
+            }
+            Devpts { ref target } => write!(f, "devpts {:?}", target),
+            Mask { ref target } => write!(f, "mask {:?}", target),
+            ReadonlyPath { ref target } => {
+                write!(f, "bind {:?} (ro)", target)
+            }
+            CopyDevfs { ref source, ref target } => {
+                write!(f, "copy devices {:?} -> {:?}", source, target)
+            }
+            Device { ref source, ref dev_dir } => {
+                write!(f, "device {:?} in {:?}", source, dev_dir)
+            }
+            Image { ref image, ref target, size, mode, owner } => {
+                write!(f, "image {:?} -> {:?} size {} mode 0o{:o} \
+                    owner {}:{}", image, target, size, mode, owner.0, owner.1)
+            }
+            ProjectQuota { ref path, size } => {
+                write!(f, "project quota {:?} size {}", path, size)
+            }
+            Remount { ref target, readonly, ref options } => {
+                write!(f, "remount {:?}", target)?;
+                let flags = [
+                    (readonly, "ro"),
+                    (options.noexec, "noexec"),
+                    (options.nosuid, "nosuid"),
+                    (options.nodev, "nodev"),
+                    (options.noatime, "noatime"),
+                ];
+                for &(enabled, name) in &flags {
+                    if enabled {
+                        write!(f, " {}", name)?;
+                    }
+                }
+                if let Some(prop) = options.propagation {
+                    write!(f, " {:?}", prop)?;
+                }
+                Ok(())
+            }
+            CoreDumps { ref host_dir, ref name, .. } => {
+                write!(f, "core dumps {:?}", host_dir.join(name))
+            }
+            QuotaState { ref state_dir, ref quotas } => {
+                write!(f, "write quota state {:?} ({} volumes)",
+                    state_dir, quotas.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use quire::{parse_string, Options};
+    use std::collections::BTreeMap;
+    use container_config::{ContainerConfig, InstantiatedConfig, Variables};
+    use master_config::MasterConfig;
+    use sandbox_config::SandboxConfig;
+    use super::{MountPlan, Step};
+
+    fn master() -> MasterConfig {
+        parse_string("<master>", "runtime_dir: /run/lithos",
+            &MasterConfig::validator(), &Options::default()).unwrap()
+    }
+
+    fn sandbox(data: &str) -> SandboxConfig {
+        parse_string("<sandbox>", data,
+            &SandboxConfig::validator(), &Options::default()).unwrap()
+    }
+
+    fn container(data: &str) -> InstantiatedConfig {
+        let cfg: ContainerConfig = parse_string("<container>", data,
+            &ContainerConfig::validator(), &Options::default()).unwrap();
+        cfg.instantiate(&Variables {
+            user_vars: &BTreeMap::new(),
+            lithos_name: "sandbox/child.0",
+            lithos_config_filename: "/config.yaml",
+        }).unwrap()
+    }
+
+    fn plan(sandbox_data: &str, container_data: &str)
+        -> Result<Vec<Step>, String>
+    {
+        plan_instance("sandbox/child.0", sandbox_data, container_data)
+    }
+
+    fn plan_instance(name: &str, sandbox_data: &str, container_data: &str)
+        -> Result<Vec<Step>, String>
+    {
+        MountPlan::new(&master(), &sandbox(sandbox_data), name,
+            &container(container_data))
+        .map(|p| p.steps)
+    }
+
+    #[test]
+    fn minimal() {
+        let steps = plan("{}", "executable: /bin/true").unwrap();
+        assert_eq!(steps[0], Step::Bind {
+            source: PathBuf::from("/var/lib/lithos/dev"),
+            target: PathBuf::from("/run/lithos/mnt/dev"),
+        });
+        assert!(steps.iter().any(|s| *s == Step::Pseudo {
+            target: PathBuf::from("/run/lithos/mnt/proc"),
+            fstype: "proc".into(),
+            readonly: false,
+        }));
+        match steps.last() {
+            Some(&Step::BindEtcFile { ref file, required: false, .. }) => {
+                assert_eq!(file, "hosts");
+            }
+            _ => panic!("hosts file is expected last"),
+        }
+    }
+
+    #[test]
+    fn masked_paths() {
+        let steps = plan(r#"
+            mask_paths: [/proc/modules]
+            unmask_paths: [/proc/kcore, /proc/sys]
+            "#, "executable: /bin/true").unwrap();
+        let mask = |p: &str| Step::Mask { target: PathBuf::from(p) };
+        let ro = |p: &str| Step::ReadonlyPath { target: PathBuf::from(p) };
+        assert!(steps.contains(&mask("/run/lithos/mnt/proc/modules")));
+        assert!(steps.contains(&mask("/run/lithos/mnt/sys/firmware")));
+        assert!(!steps.contains(&mask("/run/lithos/mnt/proc/kcore")));
+        assert!(steps.contains(&ro("/run/lithos/mnt/proc/sysrq-trigger")));
+        assert!(!steps.contains(&ro("/run/lithos/mnt/proc/sys")));
+    }
+
+    #[test]
+    fn shm() {
+        let steps = plan("{}", r#"
+            executable: /bin/true
+            shm_size: 64Mi
+            "#).unwrap();
+        assert!(steps.contains(&Step::Tmpfs {
+            target: PathBuf::from("/run/lithos/mnt/dev/shm"),
+            size: 64 << 20,
+            mode: 0o1777,
+            owner: None,
+        }));
+    }
+
+    #[test]
+    fn volumes_in_order() {
+        let steps = plan("writable_paths: {/data: /srv/data}", r#"
+            executable: /bin/true
+            volumes:
+              /app/data/tmp: !Tmpfs { size: 1Mi }
+              /app/data: !Persistent { path: /data/app, mkdir: true }
+            "#).unwrap();
+        let targets = steps.iter().filter_map(|s| match *s {
+            Step::Bind { ref target, .. } | Step::Tmpfs { ref target, .. }
+            => Some(target.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(&targets[1..], &[
+            Path::new("/run/lithos/mnt/app/data").to_path_buf(),
+            Path::new("/run/lithos/mnt/app/data/tmp").to_path_buf(),
+        ]);
+        assert!(steps.contains(&Step::CreateDir {
+            path: PathBuf::from("/srv/data/app"),
+            mode: Some(0o777),
+            owner: Some((0, 0)),
+            only_new: true,
+        }));
+    }
+
+    #[test]
+    fn persistent_unmapped_owner() {
+        let steps = plan("writable_paths: {/data: /srv/data}", r#"
+            executable: /bin/true
+            uid_map: [{inside: 0, outside: 1000, count: 1}]
+            gid_map: [{inside: 0, outside: 1000, count: 1}]
+            volumes:
+              /app/data: !Persistent { path: /data/app, mkdir: true,
+                                       user: 5 }
+            "#).unwrap();
+        assert!(steps.contains(&Step::RequireDir {
+            path: PathBuf::from("/srv/data/app"),
+            error: "Non-mapped user 5 for volume /app/data".into(),
+        }));
+    }
+
+    #[test]
+    fn missing_writable_path() {
+        let err = plan("{}", r#"
+            executable: /bin/true
+            volumes:
+              /data: !Persistent { path: /data }
+            "#).unwrap_err();
+        assert!(err.contains("writable-paths"));
+    }
+
+    #[test]
+    fn persistent_overlay_per_instance() {
+        let layers = |name| plan_instance(name,
+            "writable_paths: {/data: /srv/data}", r#"
+            executable: /bin/true
+            volumes:
+              /app: !Overlay { storage: persistent, path: /data/app }
+            "#).unwrap().into_iter().filter_map(|s| match s {
+                Step::Overlay { upper, work, .. } => Some((upper, work)),
+                _ => None,
+            }).next().unwrap();
+        assert_eq!(layers("sandbox/child.0"), (
+            PathBuf::from("/srv/data/app/sandbox:child.0/upper"),
+            PathBuf::from("/srv/data/app/sandbox:child.0/work"),
+        ));
+        assert_eq!(layers("sandbox/child.1").0,
+            PathBuf::from("/srv/data/app/sandbox:child.1/upper"));
+    }
+
+    #[test]
+    fn relative_volume_path() {
+        let err = plan("readonly_paths: {/etc: /etc}", r#"
+            executable: /bin/true
+            volumes:
+              /etc/x: !Readonly etc
+            "#).unwrap_err();
+        assert!(err.contains("must be absolute"), "{}", err);
+        let err = plan("writable_paths: {/data: /srv/data}", r#"
+            executable: /bin/true
+            volumes:
+              /app/data: !Persistent { path: data/app }
+            "#).unwrap_err();
+        assert!(err.contains("must be absolute"), "{}", err);
+    }
+}
@@ -0,0 +1,752 @@
+//! Minimal rtnetlink client for setting up container network
+//!
+//! Only the requests needed by lithos are implemented: veth pairs, moving
+//! links to another namespace, attaching to a bridge, bringing links up,
+//! addresses, default routes and traffic control for bandwidth limits.
+//! Every request is acknowledged by kernel so errors are reported for the
+//! exact request that failed.
+use std::ffi::CString;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use std::slice;
+
+use ipnetwork::IpNetwork;
+use libc::{c_void, socket, bind, sendto, recv, close, sockaddr, sockaddr_nl};
+use libc::{if_nametoindex, AF_NETLINK, AF_INET, AF_INET6, AF_UNSPEC};
+use libc::{SOCK_RAW, SOCK_CLOEXEC, NETLINK_ROUTE, IFF_UP};
+
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_NEWQDISC: u16 = 36;
+const RTM_NEWTFILTER: u16 = 44;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const VETH_INFO_PEER: u16 = 1;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_F_NODAD: u8 = 0x02;
+
+const RTA_GATEWAY: u16 = 5;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+const TCA_KIND: u16 = 1;
+const TCA_OPTIONS: u16 = 2;
+const TCA_TBF_PARMS: u16 = 1;
+const TCA_TBF_RATE64: u16 = 4;
+const TCA_TBF_BURST: u16 = 6;
+const TCA_U32_SEL: u16 = 5;
+const TCA_U32_ACT: u16 = 7;
+const TCA_ACT_KIND: u16 = 1;
+const TCA_ACT_OPTIONS: u16 = 2;
+const TCA_MIRRED_PARMS: u16 = 2;
+const TC_H_ROOT: u32 = 0xFFFF_FFFF;
+const TC_H_INGRESS: u32 = 0xFFFF_FFF1;
+const INGRESS_HANDLE: u32 = 0xFFFF_0000;
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+const TC_U32_TERMINAL: u8 = 1;
+const TC_ACT_STOLEN: i32 = 4;
+const TCA_EGRESS_REDIR: i32 = 1;
+const ETH_P_ALL: u16 = 0x0003;
+/// Queue of the token bucket filter in terms of time of sending it at rate
+const TBF_LATENCY_MS: u64 = 50;
+
+const NLA_F_NESTED: u16 = 1 << 15;
+
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display="netlink socket error: {}", _0)]
+    Socket(#[cause] io::Error),
+    #[fail(display="{}: {}", request, error)]
+    Request { request: String, #[cause] error: io::Error },
+    #[fail(display="interface {:?} not found", _0)]
+    NoInterface(String),
+    #[fail(display="malformed netlink reply")]
+    BadReply,
+}
+
+impl Error {
+    /// Error code returned by kernel for the request (if any)
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            Error::Request { ref error, .. } => error.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C)]
+struct NlMsgHdr {
+    len: u32,
+    typ: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    typ: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+struct IfAddrMsg {
+    family: u8,
+    prefixlen: u8,
+    flags: u8,
+    scope: u8,
+    index: u32,
+}
+
+#[repr(C)]
+struct RtMsg {
+    family: u8,
+    dst_len: u8,
+    src_len: u8,
+    tos: u8,
+    table: u8,
+    protocol: u8,
+    scope: u8,
+    typ: u8,
+    flags: u32,
+}
+
+#[repr(C)]
+struct TcMsg {
+    family: u8,
+    pad1: u8,
+    pad2: u16,
+    index: i32,
+    handle: u32,
+    parent: u32,
+    info: u32,
+}
+
+#[repr(C)]
+struct TcRateSpec {
+    cell_log: u8,
+    linklayer: u8,
+    overhead: u16,
+    cell_align: i16,
+    mpu: u16,
+    rate: u32,
+}
+
+#[repr(C)]
+struct TcTbfQopt {
+    rate: TcRateSpec,
+    peakrate: TcRateSpec,
+    limit: u32,
+    buffer: u32,
+    mtu: u32,
+}
+
+#[repr(C)]
+struct TcU32Sel {
+    flags: u8,
+    offshift: u8,
+    nkeys: u8,
+    offmask: u16,
+    off: u16,
+    offoff: i16,
+    hoff: i16,
+    hmask: u32,
+}
+
+#[repr(C)]
+struct TcU32Key {
+    mask: u32,
+    val: u32,
+    off: i32,
+    offmask: i32,
+}
+
+#[repr(C)]
+struct TcMirred {
+    index: u32,
+    capab: u32,
+    action: i32,
+    refcnt: i32,
+    bindcnt: i32,
+    eaction: i32,
+    ifindex: u32,
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn bytes<T>(val: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(val as *const T as *const u8,
+                                   size_of::<T>()) }
+}
+
+fn family(ip: &IpAddr) -> u8 {
+    match *ip {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    }
+}
+
+fn ip_bytes(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Netlink request being built
+pub(crate) struct Message {
+    buf: Vec<u8>,
+    nested: Vec<usize>,
+}
+
+impl Message {
+    pub(crate) fn new(typ: u16, flags: u16) -> Message {
+        let mut msg = Message { buf: Vec::with_capacity(256), nested: vec![] };
+        msg.push(&NlMsgHdr {
+            len: 0,
+            typ,
+            flags: NLM_F_REQUEST | NLM_F_ACK | flags,
+            seq: 0,
+            pid: 0,
+        });
+        msg
+    }
+    fn push<T>(&mut self, val: &T) {
+        self.buf.extend_from_slice(bytes(val));
+        let len = align(self.buf.len());
+        self.buf.resize(len, 0);
+    }
+    pub(crate) fn attr(&mut self, typ: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(bytes(&len));
+        self.buf.extend_from_slice(bytes(&typ));
+        self.buf.extend_from_slice(data);
+        let len = align(self.buf.len());
+        self.buf.resize(len, 0);
+    }
+    pub(crate) fn attr_str(&mut self, typ: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(typ, &data);
+    }
+    pub(crate) fn attr_u32(&mut self, typ: u16, value: u32) {
+        self.attr(typ, bytes(&value));
+    }
+    pub(crate) fn attr_u64(&mut self, typ: u16, value: u64) {
+        self.attr(typ, bytes(&value));
+    }
+    pub(crate) fn begin(&mut self, typ: u16) {
+        self.nested.push(self.buf.len());
+        self.attr(typ | NLA_F_NESTED, &[]);
+    }
+    pub(crate) fn end(&mut self) {
+        let start = self.nested.pop().expect("nested attribute started");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start+2].copy_from_slice(bytes(&len));
+    }
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        assert!(self.nested.is_empty());
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(bytes(&len));
+        self.buf[8..12].copy_from_slice(bytes(&seq));
+        self.buf
+    }
+}
+
+pub struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Netlink {
+    /// Opens netlink socket in the current network namespace
+    pub fn open() -> Result<Netlink, Error> {
+        let fd = unsafe {
+            socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(Error::Socket(io::Error::last_os_error()));
+        }
+        let nl = Netlink { fd, seq: 0 };
+        let mut addr: sockaddr_nl = unsafe { zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+        let rc = unsafe {
+            bind(fd, &addr as *const sockaddr_nl as *const sockaddr,
+                 size_of::<sockaddr_nl>() as u32)
+        };
+        if rc < 0 {
+            return Err(Error::Socket(io::Error::last_os_error()));
+        }
+        Ok(nl)
+    }
+
+    /// Sends request and waits for acknowledgement
+    pub(crate) fn request(&mut self, msg: Message, request: String)
+        -> Result<(), Error>
+    {
+        self.seq += 1;
+        let seq = self.seq;
+        let data = msg.finish(seq);
+        let mut addr: sockaddr_nl = unsafe { zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+        let rc = unsafe {
+            sendto(self.fd, data.as_ptr() as *const c_void, data.len(), 0,
+                   &addr as *const sockaddr_nl as *const sockaddr,
+                   size_of::<sockaddr_nl>() as u32)
+        };
+        if rc < 0 {
+            return Err(Error::Socket(io::Error::last_os_error()));
+        }
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = unsafe {
+                recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len(), 0)
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Socket(err));
+            }
+            let mut data = &buf[..n as usize];
+            while data.len() >= size_of::<NlMsgHdr>() {
+                let hdr = unsafe { &*(data.as_ptr() as *const NlMsgHdr) };
+                let len = hdr.len as usize;
+                if len < size_of::<NlMsgHdr>() || len > data.len() {
+                    return Err(Error::BadReply);
+                }
+                if hdr.seq == seq && hdr.typ == NLMSG_ERROR {
+                    if len < size_of::<NlMsgHdr>() + 4 {
+                        return Err(Error::BadReply);
+                    }
+                    let code = unsafe {
+                        *(data[size_of::<NlMsgHdr>()..].as_ptr()
+                          as *const i32)
+                    };
+                    if code == 0 {
+                        return Ok(());
+                    }
+                    return Err(Error::Request {
+                        request,
+                        error: io::Error::from_raw_os_error(-code),
+                    });
+                }
+                data = &data[align(len).min(data.len())..];
+            }
+        }
+    }
+
+    /// Returns index of the interface or `None` if there is no such one
+    pub fn link_index(&self, name: &str) -> Result<Option<u32>, Error> {
+        let cname = CString::new(name)
+            .map_err(|_| Error::NoInterface(name.to_string()))?;
+        match unsafe { if_nametoindex(cname.as_ptr()) } {
+            0 => {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(::libc::ENODEV) {
+                    Ok(None)
+                } else {
+                    Err(Error::Request {
+                        request: format!("find interface {:?}", name),
+                        error: err,
+                    })
+                }
+            }
+            idx => Ok(Some(idx)),
+        }
+    }
+
+    fn index(&self, name: &str) -> Result<u32, Error> {
+        self.link_index(name)?
+            .ok_or_else(|| Error::NoInterface(name.to_string()))
+    }
+
+    fn link_message(&self, typ: u16, flags: u16, index: u32) -> Message {
+        let mut msg = Message::new(typ, flags);
+        msg.push(&IfInfoMsg {
+            family: AF_UNSPEC as u8,
+            pad: 0,
+            typ: 0,
+            index: index as i32,
+            flags: 0,
+            change: 0,
+        });
+        msg
+    }
+
+    /// Creates veth pair, both ends are in the current namespace
+    pub fn add_veth(&mut self, name: &str, peer: &str) -> Result<(), Error> {
+        let mut msg = self.link_message(RTM_NEWLINK,
+            NLM_F_CREATE | NLM_F_EXCL, 0);
+        msg.attr_str(IFLA_IFNAME, name);
+        msg.begin(IFLA_LINKINFO);
+        msg.attr_str(IFLA_INFO_KIND, "veth");
+        msg.begin(IFLA_INFO_DATA);
+        msg.begin(VETH_INFO_PEER);
+        msg.push(&IfInfoMsg {
+            family: AF_UNSPEC as u8,
+            pad: 0,
+            typ: 0,
+            index: 0,
+            flags: 0,
+            change: 0,
+        });
+        msg.attr_str(IFLA_IFNAME, peer);
+        msg.end();
+        msg.end();
+        msg.end();
+        self.request(msg, format!("create veth {:?} (peer {:?})", name, peer))
+    }
+
+    pub fn delete_link(&mut self, name: &str) -> Result<(), Error> {
+        let index = self.index(name)?;
+        let msg = self.link_message(RTM_DELLINK, 0, index);
+        self.request(msg, format!("delete interface {:?}", name))
+    }
+
+    /// Moves interface to the network namespace referred by `ns_fd`
+    pub fn set_netns(&mut self, name: &str, ns_fd: RawFd)
+        -> Result<(), Error>
+    {
+        let index = self.index(name)?;
+        let mut msg = self.link_message(RTM_NEWLINK, 0, index);
+        msg.attr_u32(IFLA_NET_NS_FD, ns_fd as u32);
+        self.request(msg, format!("move interface {:?} to namespace", name))
+    }
+
+    /// Attaches interface to the bridge
+    pub fn set_master(&mut self, name: &str, bridge: &str)
+        -> Result<(), Error>
+    {
+        let index = self.index(name)?;
+        let master = self.index(bridge)?;
+        let mut msg = self.link_message(RTM_NEWLINK, 0, index);
+        msg.attr_u32(IFLA_MASTER, master);
+        self.request(msg, format!("add interface {:?} to bridge {:?}",
+            name, bridge))
+    }
+
+    pub fn set_up(&mut self, name: &str) -> Result<(), Error> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        msg.push(&IfInfoMsg {
+            family: AF_UNSPEC as u8,
+            pad: 0,
+            typ: 0,
+            index: index as i32,
+            flags: IFF_UP as u32,
+            change: IFF_UP as u32,
+        });
+        self.request(msg, format!("bring interface {:?} up", name))
+    }
+
+    pub fn add_address(&mut self, name: &str, addr: IpNetwork)
+        -> Result<(), Error>
+    {
+        let index = self.index(name)?;
+        let ip = addr.ip();
+        let mut msg = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL);
+        msg.push(&IfAddrMsg {
+            family: family(&ip),
+            prefixlen: addr.prefix(),
+            // addresses are unique within the bridge (checked by lithos_check
+            // and ipam), and duplicate address detection would keep the
+            // address tentative (so unusable) for a second or so
+            flags: if ip.is_ipv6() { IFA_F_NODAD } else { 0 },
+            scope: RT_SCOPE_UNIVERSE,
+            index,
+        });
+        msg.attr(IFA_LOCAL, &ip_bytes(&ip));
+        msg.attr(IFA_ADDRESS, &ip_bytes(&ip));
+        self.request(msg, format!("add address {} to {:?}", addr, name))
+    }
+
+    pub fn add_default_route(&mut self, gateway: IpAddr)
+        -> Result<(), Error>
+    {
+        let mut msg = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL);
+        msg.push(&RtMsg {
+            family: family(&gateway),
+            dst_len: 0,
+            src_len: 0,
+            tos: 0,
+            table: RT_TABLE_MAIN,
+            protocol: RTPROT_BOOT,
+            scope: RT_SCOPE_UNIVERSE,
+            typ: RTN_UNICAST,
+            flags: 0,
+        });
+        msg.attr(RTA_GATEWAY, &ip_bytes(&gateway));
+        self.request(msg, format!("add default route via {}", gateway))
+    }
+
+    fn tc_message(&self, typ: u16, flags: u16, index: u32,
+        handle: u32, parent: u32, info: u32)
+        -> Message
+    {
+        let mut msg = Message::new(typ, flags);
+        msg.push(&TcMsg {
+            family: AF_UNSPEC as u8,
+            pad1: 0,
+            pad2: 0,
+            index: index as i32,
+            handle,
+            parent,
+            info,
+        });
+        msg
+    }
+
+    /// Creates intermediate functional block device
+    ///
+    /// Traffic redirected to the device can be shaped by its root qdisc.
+    pub fn add_ifb(&mut self, name: &str) -> Result<(), Error> {
+        let mut msg = self.link_message(RTM_NEWLINK,
+            NLM_F_CREATE | NLM_F_EXCL, 0);
+        msg.attr_str(IFLA_IFNAME, name);
+        msg.begin(IFLA_LINKINFO);
+        msg.attr_str(IFLA_INFO_KIND, "ifb");
+        msg.end();
+        self.request(msg, format!("create ifb {:?}", name))
+    }
+
+    /// Limits outgoing traffic of the interface by token bucket filter
+    ///
+    /// The `rate` is in bytes per second, `burst` is in bytes. Root qdisc
+    /// of the interface is replaced.
+    pub fn set_rate_limit(&mut self, name: &str, rate: u64, burst: u32)
+        -> Result<(), Error>
+    {
+        let index = self.index(name)?;
+        let limit = burst as u64 + rate * TBF_LATENCY_MS / 1000;
+        let mut msg = self.tc_message(RTM_NEWQDISC,
+            NLM_F_CREATE | NLM_F_REPLACE, index, 0x1_0000, TC_H_ROOT, 0);
+        msg.attr_str(TCA_KIND, "tbf");
+        msg.begin(TCA_OPTIONS);
+        msg.attr(TCA_TBF_PARMS, bytes(&TcTbfQopt {
+            rate: TcRateSpec {
+                cell_log: 0,
+                linklayer: TC_LINKLAYER_ETHERNET,
+                overhead: 0,
+                cell_align: 0,
+                mpu: 0,
+                rate: rate.min(u32::max_value() as u64) as u32,
+            },
+            peakrate: unsafe { zeroed() },
+            limit: limit.min(u32::max_value() as u64) as u32,
+            buffer: 0,
+            mtu: 0,
+        }));
+        if rate > u32::max_value() as u64 {
+            msg.attr_u64(TCA_TBF_RATE64, rate);
+        }
+        msg.attr_u32(TCA_TBF_BURST, burst);
+        msg.end();
+        self.request(msg, format!("set rate limit of {:?}", name))
+    }
+
+    /// Redirects all incoming traffic of the interface to `target`
+    ///
+    /// This adds ingress qdisc and a match-all filter with mirred action,
+    /// so traffic can be shaped on `target` (usually ifb).
+    pub fn redirect_ingress(&mut self, name: &str, target: &str)
+        -> Result<(), Error>
+    {
+        let index = self.index(name)?;
+        let target_index = self.index(target)?;
+        let mut msg = self.tc_message(RTM_NEWQDISC,
+            NLM_F_CREATE | NLM_F_EXCL, index, INGRESS_HANDLE, TC_H_INGRESS, 0);
+        msg.attr_str(TCA_KIND, "ingress");
+        self.request(msg, format!("add ingress qdisc to {:?}", name))?;
+
+        // priority 1, protocol all (in network byte order)
+        let info = (1 << 16) | ETH_P_ALL.to_be() as u32;
+        let mut msg = self.tc_message(RTM_NEWTFILTER,
+            NLM_F_CREATE | NLM_F_EXCL, index, 0, INGRESS_HANDLE, info);
+        msg.attr_str(TCA_KIND, "u32");
+        msg.begin(TCA_OPTIONS);
+        // single key with zero mask matches every packet
+        let mut sel = bytes(&TcU32Sel {
+            flags: TC_U32_TERMINAL,
+            offshift: 0,
+            nkeys: 1,
+            offmask: 0,
+            off: 0,
+            offoff: 0,
+            hoff: 0,
+            hmask: 0,
+        }).to_vec();
+        sel.extend_from_slice(bytes(&TcU32Key {
+            mask: 0, val: 0, off: 0, offmask: 0,
+        }));
+        msg.attr(TCA_U32_SEL, &sel);
+        msg.begin(TCA_U32_ACT);
+        msg.begin(1);  // order of the action
+        msg.attr_str(TCA_ACT_KIND, "mirred");
+        msg.begin(TCA_ACT_OPTIONS);
+        msg.attr(TCA_MIRRED_PARMS, bytes(&TcMirred {
+            index: 0,
+            capab: 0,
+            action: TC_ACT_STOLEN,
+            refcnt: 0,
+            bindcnt: 0,
+            eaction: TCA_EGRESS_REDIR,
+            ifindex: target_index,
+        }));
+        msg.end();
+        msg.end();
+        msg.end();
+        msg.end();
+        self.request(msg, format!("redirect traffic of {:?} to {:?}",
+            name, target))
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use libc::{unshare, CLONE_NEWUSER, CLONE_NEWNET, EEXIST};
+    use libc::{c_char, c_void, open, write, close, getuid, getgid, O_WRONLY};
+    use super::{Netlink, Message, Error, RTM_NEWLINK, NLM_F_CREATE};
+    use super::{NLM_F_EXCL, IFLA_IFNAME, IFLA_LINKINFO, IFLA_INFO_KIND};
+
+    const ENV: &str = "LITHOS_NETLINK_TEST";
+
+    #[test]
+    fn message() {
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        msg.begin(IFLA_LINKINFO);
+        msg.attr_str(IFLA_INFO_KIND, "veth");
+        msg.end();
+        let data = msg.finish(7);
+        assert_eq!(data.len(), 16 + 4 + 12);
+        assert_eq!(&data[0..4], &[32, 0, 0, 0]);
+        assert_eq!(&data[8..12], &[7, 0, 0, 0]);
+        // nested attribute covers the inner one
+        assert_eq!(&data[16..20], &[16, 0, 18, 0x80]);
+        assert_eq!(&data[20..28], &[9, 0, 1, 0, b'v', b'e', b't', b'h']);
+    }
+
+    /// Writes `data` to a file (without allocations, used after fork)
+    unsafe fn write_file(path: &[u8], data: &[u8]) -> io::Result<()> {
+        let fd = open(path.as_ptr() as *const c_char, O_WRONLY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let rc = write(fd, data.as_ptr() as *const c_void, data.len());
+        close(fd);
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Runs `inner` test in a new (unprivileged) user and network namespace
+    #[test]
+    fn namespace() {
+        let uid_map = format!("0 {} 1", unsafe { getuid() });
+        let gid_map = format!("0 {} 1", unsafe { getgid() });
+        let mut cmd = Command::new(env::current_exe().unwrap());
+        cmd.arg("--exact").arg("netlink::test::inner").arg("--nocapture");
+        cmd.env(ENV, "1");
+        unsafe {
+            cmd.pre_exec(move || {
+                if unshare(CLONE_NEWUSER | CLONE_NEWNET) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // be root in the namespace to keep capabilities after exec
+                write_file(b"/proc/self/uid_map\0", uid_map.as_bytes())?;
+                write_file(b"/proc/self/setgroups\0", b"deny")?;
+                write_file(b"/proc/self/gid_map\0", gid_map.as_bytes())?;
+                Ok(())
+            });
+        }
+        match cmd.status() {
+            Ok(status) => assert!(status.success()),
+            Err(e) => {
+                // user namespaces may be disabled in this environment
+                println!("Skipping netlink test: {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn inner() {
+        if env::var_os(ENV).is_none() {
+            return;
+        }
+        let mut nl = Netlink::open().unwrap();
+        nl.set_up("lo").unwrap();
+        nl.add_veth("lt-host", "lt-cont").unwrap();
+        let err = nl.add_veth("lt-host", "lt-other").unwrap_err();
+        assert_eq!(err.errno(), Some(EEXIST));
+
+        let mut msg = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL);
+        msg.push(&super::IfInfoMsg {
+            family: 0, pad: 0, typ: 0, index: 0, flags: 0, change: 0,
+        });
+        msg.attr_str(IFLA_IFNAME, "lt-br");
+        msg.begin(IFLA_LINKINFO);
+        msg.attr_str(IFLA_INFO_KIND, "bridge");
+        msg.end();
+        nl.request(msg, "create bridge".into()).unwrap();
+        nl.set_master("lt-host", "lt-br").unwrap();
+
+        nl.set_up("lt-host").unwrap();
+        nl.set_up("lt-cont").unwrap();
+        nl.add_address("lt-cont", "10.1.0.2/24".parse().unwrap()).unwrap();
+        nl.add_address("lt-cont", "fd00::2/64".parse().unwrap()).unwrap();
+        nl.add_default_route("10.1.0.1".parse().unwrap()).unwrap();
+        match nl.set_up("lt-missing") {
+            Err(Error::NoInterface(ref name)) if name == "lt-missing" => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        nl.add_ifb("lt-ifb").unwrap();
+        nl.set_up("lt-ifb").unwrap();
+        nl.set_rate_limit("lt-host", 1_250_000, 32768).unwrap();
+        nl.set_rate_limit("lt-host", 6_000_000_000, 65536).unwrap();
+        nl.redirect_ingress("lt-host", "lt-ifb").unwrap();
+        nl.set_rate_limit("lt-ifb", 1_250_000, 32768).unwrap();
+        nl.delete_link("lt-ifb").unwrap();
+        nl.delete_link("lt-host").unwrap();
+        assert_eq!(nl.link_index("lt-cont").unwrap(), None);
+    }
+}
@@ -1,28 +1,40 @@
-use std::ptr::{null, copy};
-use std::ffi::{CString};
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind};
 use std::io::Result as IoResult;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, ToSocketAddrs};
+
 use libc::{c_int, size_t, c_char, EINVAL};
+use nix::ifaddrs::getifaddrs;
+use nix::net::if_::InterfaceFlags;
+use nix::sys::socket::SockAddr;
+
+use master_config::MasterConfig;
 
-#[repr(C)]
-struct hostent {
-    h_name: *const c_char,              /* official name of host */
-    h_aliases: *const *const c_char,    /* alias list */
-    h_addrtype: c_int,                  /* host address type */
-    h_length: c_int,                    /* length of address */
-    h_addr_list: *const *const c_char,  /* list of addresses */
-}
 
 extern {
     fn gethostname(name: *mut c_char, size: size_t) -> c_int;
-    fn gethostbyname(name: *const c_char) -> *const hostent;
 }
 
-pub fn get_host_ip() -> IoResult<String> {
-    let host = try!(get_host_name());
-    let addr = try!(get_host_address(&host[..]));
-    return Ok(addr);
+/// Returns hostname written into containers' `/etc/hosts`
+///
+/// This is `public-hostname` from master config if set, or the hostname
+/// of the machine.
+pub fn public_hostname(master: &MasterConfig) -> IoResult<String> {
+    match master.public_hostname {
+        Some(ref name) => Ok(name.clone()),
+        None => get_host_name(),
+    }
+}
+
+/// Returns addresses written into containers' `/etc/hosts`
+///
+/// This is `public-addresses` from master config if set, or addresses
+/// found by `get_host_addresses`.
+pub fn public_addresses(master: &MasterConfig) -> IoResult<Vec<IpAddr>> {
+    if master.public_addresses.len() > 0 {
+        Ok(master.public_addresses.clone())
+    } else {
+        get_host_addresses()
+    }
 }
 
 pub fn get_host_name() -> IoResult<String> {
@@ -42,19 +54,83 @@ pub fn get_host_name() -> IoResult<String> {
            .ok_or(IoError::from_raw_os_error(EINVAL));
 }
 
-pub fn get_host_address(val: &str) -> IoResult<String> {
-    let cval = CString::new(val).unwrap();
-    unsafe {
-        let hostent = gethostbyname(cval.as_ptr());
-        if hostent == null() {
-            return Err(IoError::last_os_error());
+fn is_usable(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_link_local()
+                          && !ip.is_unspecified(),
+        // link-local addresses are useless without an interface name
+        IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_unspecified()
+                          && ip.segments()[0] & 0xffc0 != 0xfe80,
+    }
+}
+
+fn push_unique(result: &mut Vec<IpAddr>, ip: IpAddr) {
+    if is_usable(&ip) && !result.contains(&ip) {
+        result.push(ip);
+    }
+}
+
+/// Resolves `name` using `getaddrinfo`
+pub fn resolve_host(name: &str) -> IoResult<Vec<IpAddr>> {
+    let mut result = Vec::new();
+    for addr in (name, 0).to_socket_addrs()? {
+        push_unique(&mut result, addr.ip());
+    }
+    Ok(result)
+}
+
+/// Returns addresses of all interfaces that are up, except loopback
+pub fn interface_addresses() -> IoResult<Vec<IpAddr>> {
+    let mut result = Vec::new();
+    let addrs = getifaddrs()
+        .map_err(|e| IoError::new(ErrorKind::Other, e))?;
+    for iface in addrs {
+        if !iface.flags.contains(InterfaceFlags::IFF_UP) ||
+            iface.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+        {
+            continue;
         }
-        if (*hostent).h_length == 0 {
-            return Err(IoError::from_raw_os_error(EINVAL));
+        if let Some(SockAddr::Inet(addr)) = iface.address {
+            push_unique(&mut result, addr.to_std().ip());
         }
-        let mut addr = [0u8; 4];
-        copy(*(*hostent).h_addr_list, addr.as_mut_ptr() as *mut i8, 4);
-        return Ok(format!("{}",
-                  Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3])));
+    }
+    // Interfaces are listed by address family, but let IPv4 go first
+    // regardless of the order
+    result.sort_by_key(|ip| ip.is_ipv6());
+    Ok(result)
+}
+
+/// Returns non-loopback addresses of this host
+///
+/// Hostname is resolved first, if it resolves only to loopback addresses
+/// (like `127.0.1.1` in default setup of some distributions) or doesn't
+/// resolve at all, addresses of network interfaces are returned.
+pub fn get_host_addresses() -> IoResult<Vec<IpAddr>> {
+    let resolved = get_host_name().and_then(|name| resolve_host(&name));
+    match resolved {
+        Ok(ref addrs) if addrs.len() > 0 => return Ok(addrs.clone()),
+        Ok(_) => {}
+        Err(ref e) => debug!("Can't resolve hostname: {}", e),
+    }
+    let addrs = interface_addresses()?;
+    if addrs.is_empty() {
+        return Err(IoError::new(ErrorKind::NotFound,
+            "no non-loopback address found for this host"));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_usable;
+
+    #[test]
+    fn usable() {
+        assert!(is_usable(&"10.1.2.3".parse().unwrap()));
+        assert!(is_usable(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_usable(&"127.0.1.1".parse().unwrap()));
+        assert!(!is_usable(&"169.254.1.1".parse().unwrap()));
+        assert!(!is_usable(&"::1".parse().unwrap()));
+        assert!(!is_usable(&"fe80::1".parse().unwrap()));
     }
 }
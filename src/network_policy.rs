@@ -0,0 +1,183 @@
+//! Egress filtering of containers in bridged network
+//!
+//! Policy of the sandbox is compiled into a chain of the `bridge lithos`
+//! table, named the same as the host side of container's veth interface.
+//! The chain is entered from the `prerouting` chain by a rule matching the
+//! interface, the rule is marked like described in `nftables` module.
+use ipnetwork::IpNetwork;
+use quire::validate::{Structure, Sequence, Scalar};
+
+use nftables::{COMMENT_PREFIX, nft, list_rules, delete_rules};
+use port_forward::Protocol;
+use range::Range;
+
+
+pub const TABLE: &'static str = "bridge lithos";
+const CHAIN: &'static str = "prerouting";
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all="lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PolicyRule {
+    #[serde(with="::serde_str")]
+    pub network: IpNetwork,
+    pub protocol: Option<Protocol>,
+    pub ports: Vec<Range>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NetworkPolicy {
+    pub default: Action,
+    pub allow: Vec<PolicyRule>,
+    pub deny: Vec<PolicyRule>,
+}
+
+impl PolicyRule {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("network", Scalar::new())
+        .member("protocol", Scalar::new().optional())
+        .member("ports", Sequence::new(Scalar::new()))
+    }
+    fn matches(&self) -> String {
+        let mut result = match self.network {
+            IpNetwork::V4(net) => {
+                format!("ip daddr {}/{}", net.network(), net.prefix())
+            }
+            IpNetwork::V6(net) => {
+                format!("ip6 daddr {}/{}", net.network(), net.prefix())
+            }
+        };
+        if self.ports.len() > 0 {
+            let ports = self.ports.iter().map(|r| if r.start == r.end {
+                r.start.to_string()
+            } else {
+                format!("{}-{}", r.start, r.end)
+            }).collect::<Vec<_>>().join(", ");
+            match self.protocol {
+                Some(proto) => {
+                    result.push_str(&format!(" {} dport {{ {} }}",
+                        proto, ports));
+                }
+                None => {
+                    result.push_str(&format!(
+                        " meta l4proto {{ tcp, udp }} th dport {{ {} }}",
+                        ports));
+                }
+            }
+        } else if let Some(proto) = self.protocol {
+            result.push_str(&format!(" meta l4proto {}", proto));
+        }
+        result
+    }
+}
+
+impl NetworkPolicy {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("default", Scalar::new().default("allow"))
+        .member("allow", Sequence::new(PolicyRule::validator()))
+        .member("deny", Sequence::new(PolicyRule::validator()))
+    }
+    /// Returns rules of the container's chain
+    ///
+    /// Replies to established connections and neighbor discovery are always
+    /// allowed. Then `deny` rules are checked, then `allow` rules.
+    pub fn rules(&self) -> Vec<String> {
+        let mut rules = vec![
+            "ct state established,related accept".to_string(),
+            "ether type arp accept".to_string(),
+            "icmpv6 type { nd-neighbor-solicit, nd-neighbor-advert, \
+                nd-router-solicit } accept".to_string(),
+        ];
+        for rule in &self.deny {
+            rules.push(format!("{} drop", rule.matches()));
+        }
+        for rule in &self.allow {
+            rules.push(format!("{} accept", rule.matches()));
+        }
+        rules.push(match self.default {
+            Action::Allow => "accept".to_string(),
+            Action::Deny => "drop".to_string(),
+        });
+        rules
+    }
+}
+
+/// Installs the policy for traffic coming from the `interface`
+///
+/// Rules installed by previous run of the same container are replaced.
+pub fn install(name: &str, interface: &str, policy: &NetworkPolicy)
+    -> Result<(), String>
+{
+    remove(name)?;
+    let mut script = format!("add table {}\n", TABLE);
+    script.push_str(&format!("add chain {} {} \
+        {{ type filter hook prerouting priority 0; }}\n", TABLE, CHAIN));
+    script.push_str(&format!("add chain {} {}\n", TABLE, interface));
+    script.push_str(&format!("flush chain {} {}\n", TABLE, interface));
+    for rule in policy.rules() {
+        script.push_str(&format!("add rule {} {} {}\n",
+            TABLE, interface, rule));
+    }
+    script.push_str(&format!("add rule {} {} iifname \"{}\" jump {} \
+        comment \"{}{}\"\n",
+        TABLE, CHAIN, interface, interface, COMMENT_PREFIX, name));
+    nft(&["-f", "-"], Some(&script))
+        .map_err(|e| format!("can't add network policy rules: {}", e))?;
+    Ok(())
+}
+
+/// Removes network policy of container `name`
+pub fn remove(name: &str) -> Result<(), String> {
+    let rules = list_rules(TABLE)?;
+    delete_rules(TABLE, rules.iter().filter(|r| r.name == name))
+}
+
+/// Removes policies of all containers for which `keep` returns false
+pub fn remove_stale<F: Fn(&str) -> bool>(keep: F) -> Result<(), String> {
+    let rules = list_rules(TABLE)?;
+    for rule in &rules {
+        if !keep(&rule.name) {
+            warn!("Removing stale network policy of {:?} (handle {})",
+                rule.name, rule.handle);
+        }
+    }
+    delete_rules(TABLE, rules.iter().filter(|r| !keep(&r.name)))
+}
+
+#[cfg(test)]
+mod test {
+    use quire::{parse_string, Options};
+    use super::NetworkPolicy;
+
+    #[test]
+    fn rules() {
+        let policy: NetworkPolicy = parse_string("<test>", r#"
+            default: deny
+            allow:
+            - network: 10.0.0.0/8
+              protocol: tcp
+              ports: [80, 8000-8999]
+            - network: 10.1.2.3
+              ports: [53]
+            - network: "fd00::/64"
+            deny:
+            - network: 169.254.169.254/32
+            "#,
+            &NetworkPolicy::validator(), &Options::default()).unwrap();
+        assert_eq!(&policy.rules()[3..], &[
+            "ip daddr 169.254.169.254/32 drop",
+            "ip daddr 10.0.0.0/8 tcp dport { 80, 8000-8999 } accept",
+            "ip daddr 10.1.2.3/32 meta l4proto { tcp, udp } \
+                th dport { 53 } accept",
+            "ip6 daddr fd00::/64 accept",
+            "drop",
+        ]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Helpers for running `nft` and finding rules installed by lithos
+//!
+//! Every rule installed for a container is marked with a
+//! `lithos:<sandbox>/<child>.<N>` comment, so rules of the single container
+//! can be removed and rules of dead containers can be found and cleaned up.
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+
+pub const COMMENT_PREFIX: &'static str = "lithos:";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub chain: String,
+    pub name: String,
+    pub handle: u64,
+    /// Target chain if rule is `jump <chain>`
+    pub jump: Option<String>,
+    /// Rule itself without the comment and the handle
+    pub text: String,
+}
+
+pub fn nft(args: &[&str], input: Option<&str>) -> Result<String, io::Error> {
+    let mut cmd = Command::new("nft");
+    cmd.args(args);
+    cmd.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() });
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    let out = child.wait_with_output()?;
+    if !out.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+            format!("nft {}: {}", out.status,
+                String::from_utf8_lossy(&out.stderr).trim())));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Finds rules marked by lithos in the output of `nft -a list table`
+pub fn parse_rules(listing: &str) -> Vec<Rule> {
+    let mut chain = None;
+    let mut rules = Vec::new();
+    for line in listing.lines() {
+        let line = line.trim();
+        if line.starts_with("chain ") {
+            chain = line.split_whitespace().nth(1).map(String::from);
+            continue;
+        }
+        let comment = match line.find(&format!("comment \"{}", COMMENT_PREFIX))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+        let start = comment + "comment \"".len() + COMMENT_PREFIX.len();
+        let name = match line[start..].find('"') {
+            Some(end) => &line[start..start+end],
+            None => continue,
+        };
+        let handle = line.rfind("# handle ")
+            .and_then(|x| line[x + "# handle ".len()..].trim().parse().ok());
+        let jump = line.find(" jump ")
+            .and_then(|x| line[x + " jump ".len()..].split_whitespace().next())
+            .map(String::from);
+        if let (Some(chain), Some(handle)) = (chain.as_ref(), handle) {
+            rules.push(Rule {
+                chain: chain.clone(),
+                name: name.to_string(),
+                handle,
+                jump,
+                text: line[..comment].trim().to_string(),
+            });
+        }
+    }
+    rules
+}
+
+/// Lists rules marked by lithos in the `table` (e.g. `inet lithos`)
+pub fn list_rules(table: &str) -> Result<Vec<Rule>, String> {
+    let mut args = vec!["-a", "list", "table"];
+    args.extend(table.split_whitespace());
+    match nft(&args, None) {
+        Ok(listing) => Ok(parse_rules(&listing)),
+        // No nft binary, so there can't be any rules
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        // The table is not created yet
+        Err(ref e) if e.to_string().contains("No such file or directory") => {
+            Ok(Vec::new())
+        }
+        Err(e) => Err(format!("can't list rules: {}", e)),
+    }
+}
+
+/// Deletes rules, and chains they jump to, in a single transaction
+pub fn delete_rules<'x, I>(table: &str, rules: I) -> Result<(), String>
+    where I: Iterator<Item=&'x Rule>
+{
+    let mut script = String::new();
+    let mut chains = Vec::new();
+    for rule in rules {
+        script.push_str(&format!("delete rule {} {} handle {}\n",
+            table, rule.chain, rule.handle));
+        match rule.jump {
+            Some(ref target) if !chains.contains(&target) => {
+                chains.push(target);
+            }
+            _ => {}
+        }
+    }
+    for chain in chains {
+        script.push_str(&format!("flush chain {} {}\n", table, chain));
+        script.push_str(&format!("delete chain {} {}\n", table, chain));
+    }
+    if script.is_empty() {
+        return Ok(());
+    }
+    nft(&["-f", "-"], Some(&script))
+        .map_err(|e| format!("can't delete rules: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rule, parse_rules};
+
+    #[test]
+    fn parse_listing() {
+        let listing = r#"table inet lithos { # handle 7
+	chain prerouting { # handle 1
+		type nat hook prerouting priority dstnat; policy accept;
+		meta nfproto ipv4 fib daddr type local tcp dport 80 dnat ip to 10.0.0.2:8080 comment "lithos:sb/web.0" # handle 3
+		iifname "li_0a1b2c_0002" jump li_0a1b2c_0002 comment "lithos:sb/db.0" # handle 6
+	}
+	chain output { # handle 2
+		type nat hook output priority -100; policy accept;
+		meta nfproto ipv4 fib daddr type local tcp dport 80 dnat ip to 10.0.0.2:8080 comment "lithos:sb/web.0" # handle 4
+		tcp dport 22 accept # handle 5
+	}
+}
+"#;
+        assert_eq!(parse_rules(listing), vec![
+            Rule { chain: "prerouting".into(), name: "sb/web.0".into(),
+                   handle: 3, jump: None,
+                   text: "meta nfproto ipv4 fib daddr type local \
+                          tcp dport 80 dnat ip to 10.0.0.2:8080".into() },
+            Rule { chain: "prerouting".into(), name: "sb/db.0".into(),
+                   handle: 6, jump: Some("li_0a1b2c_0002".into()),
+                   text: "iifname \"li_0a1b2c_0002\" \
+                          jump li_0a1b2c_0002".into() },
+            Rule { chain: "output".into(), name: "sb/web.0".into(),
+                   handle: 4, jump: None,
+                   text: "meta nfproto ipv4 fib daddr type local \
+                          tcp dport 80 dnat ip to 10.0.0.2:8080".into() },
+        ]);
+    }
+}
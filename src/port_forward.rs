@@ -0,0 +1,258 @@
+//! DNAT rules for ports published from host to bridged containers
+//!
+//! Rules are kept in nftables table `inet lithos`, see `nftables` module
+//! for how rules are marked.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::de::{Deserializer, Deserialize, Error};
+use serde::ser::{Serializer, Serialize};
+
+use nftables::{COMMENT_PREFIX, nft, list_rules, delete_rules};
+
+
+pub const TABLE: &'static str = "inet lithos";
+const CHAINS: &'static [&'static str] = &["prerouting", "output"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Instantiated published port
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PortForward {
+    pub protocol: Protocol,
+    pub host: IpAddr,
+    pub host_port: u16,
+    pub port: u16,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+    fn from_str(val: &str) -> Result<Protocol, String> {
+        match val {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(format!("unknown protocol {:?}", val)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Tcp => f.write_str("tcp"),
+            Protocol::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+impl<'a> Deserialize<'a> for Protocol {
+    fn deserialize<D: Deserializer<'a>>(d: D) -> Result<Protocol, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for Protocol {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(s)
+    }
+}
+
+/// Parses `PORT` or `PORT/PROTO` key of `published-ports`
+pub fn parse_key(key: &str) -> Result<(u16, Protocol), String> {
+    let mut pair = key.splitn(2, '/');
+    let port = pair.next().unwrap().parse()
+        .map_err(|e| format!("bad port {:?}: {}", key, e))?;
+    let proto = match pair.next() {
+        Some(proto) => proto.parse()?,
+        None => Protocol::Tcp,
+    };
+    Ok((port, proto))
+}
+
+/// Returns nftables rule (without the chain) for the forward
+///
+/// `addr` is the address of the container of the same family as
+/// `fwd.host`.
+pub fn rule(name: &str, fwd: &PortForward, addr: IpAddr) -> String {
+    let (family, daddr) = match fwd.host {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            ("ip", "meta nfproto ipv4".to_string())
+        }
+        IpAddr::V4(ip) => ("ip", format!("ip daddr {}", ip)),
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            ("ip6", "meta nfproto ipv6".to_string())
+        }
+        IpAddr::V6(ip) => ("ip6", format!("ip6 daddr {}", ip)),
+    };
+    let target = match addr {
+        IpAddr::V4(ip) => format!("{}:{}", ip, fwd.port),
+        IpAddr::V6(ip) => format!("[{}]:{}", ip, fwd.port),
+    };
+    format!("{} fib daddr type local {} dport {} dnat {} to {} \
+        comment \"{}{}\"",
+        daddr, fwd.protocol, fwd.host_port, family, target,
+        COMMENT_PREFIX, name)
+}
+
+/// Returns true if both addresses receive the same packets
+///
+/// I.e. addresses are of the same family and either equal or one of them is
+/// unspecified (`0.0.0.0` or `::`).
+pub fn overlaps(a: IpAddr, b: IpAddr) -> bool {
+    a.is_ipv4() == b.is_ipv4() &&
+        (a == b || a.is_unspecified() || b.is_unspecified())
+}
+
+/// Returns true if two forwards can't be installed at the same time
+pub fn conflicts(a: &PortForward, b: &PortForward) -> bool {
+    a.protocol == b.protocol && a.host_port == b.host_port &&
+        overlaps(a.host, b.host)
+}
+
+/// Parses protocol, host address and host port of the rule text
+///
+/// Only rules generated by `rule` are recognized (as listed by `nft`), the
+/// container port in the result is always zero.
+fn parse_rule(text: &str) -> Option<PortForward> {
+    let mut host = None;
+    let mut protocol = None;
+    let mut host_port = None;
+    let words = text.split_whitespace().collect::<Vec<_>>();
+    for (idx, pair) in words.windows(2).enumerate() {
+        match (pair[0], pair[1]) {
+            ("nfproto", "ipv4") => host = Some("0.0.0.0".parse().unwrap()),
+            ("nfproto", "ipv6") => host = Some("::".parse().unwrap()),
+            ("ip", "daddr") | ("ip6", "daddr") => {
+                host = words.get(idx+2).and_then(|x| x.parse().ok());
+            }
+            (proto, "dport") => {
+                protocol = proto.parse().ok();
+                host_port = words.get(idx+2).and_then(|x| x.parse().ok());
+            }
+            _ => {}
+        }
+    }
+    Some(PortForward {
+        protocol: protocol?,
+        host: host?,
+        host_port: host_port?,
+        port: 0,
+    })
+}
+
+/// Installs DNAT rules for all `forwards` of container `name`
+///
+/// Rules installed by previous run of the same container are replaced.
+/// Fails if any of the ports is already forwarded to another container.
+pub fn install(name: &str, forwards: &[PortForward],
+    ipv4: Option<IpAddr>, ipv6: Option<IpAddr>)
+    -> Result<(), String>
+{
+    remove(name)?;
+    for rule in list_rules(TABLE)?.iter().filter(|r| r.name != name) {
+        let other = match parse_rule(&rule.text) {
+            Some(other) => other,
+            None => continue,
+        };
+        if let Some(fwd) = forwards.iter().find(|f| conflicts(f, &other)) {
+            return Err(format!("port {}:{}/{} is already published \
+                by {:?}", fwd.host, fwd.host_port, fwd.protocol,
+                rule.name));
+        }
+    }
+    let mut script = format!("add table {}\n", TABLE);
+    for chain in CHAINS {
+        script.push_str(&format!("add chain {} {} \
+            {{ type nat hook {} priority -100; }}\n", TABLE, chain, chain));
+    }
+    for fwd in forwards {
+        let addr = if fwd.host.is_ipv4() { ipv4 } else { ipv6 };
+        let addr = addr.ok_or_else(|| format!("container has no address \
+            of the same family as {}", fwd.host))?;
+        let rule = rule(name, fwd, addr);
+        for chain in CHAINS {
+            script.push_str(&format!("add rule {} {} {}\n",
+                TABLE, chain, rule));
+        }
+    }
+    nft(&["-f", "-"], Some(&script))
+        .map_err(|e| format!("can't add port forwarding rules: {}", e))?;
+    Ok(())
+}
+
+/// Removes all DNAT rules of container `name`
+pub fn remove(name: &str) -> Result<(), String> {
+    let rules = list_rules(TABLE)?;
+    delete_rules(TABLE, rules.iter().filter(|r| r.name == name))
+}
+
+/// Removes rules of all containers for which `keep` returns false
+pub fn remove_stale<F: Fn(&str) -> bool>(keep: F) -> Result<(), String> {
+    let rules = list_rules(TABLE)?;
+    for rule in &rules {
+        if !keep(&rule.name) {
+            warn!("Removing stale port forwarding of {:?} (handle {})",
+                rule.name, rule.handle);
+        }
+    }
+    delete_rules(TABLE, rules.iter().filter(|r| !keep(&r.name)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PortForward, Protocol, rule, parse_key, parse_rule};
+    use super::conflicts;
+
+    #[test]
+    fn rules() {
+        let fwd = PortForward {
+            protocol: Protocol::Udp,
+            host: "0.0.0.0".parse().unwrap(),
+            host_port: 5353,
+            port: 53,
+        };
+        assert_eq!(rule("sb/dns.0", &fwd, "10.0.0.2".parse().unwrap()),
+            "meta nfproto ipv4 fib daddr type local udp dport 5353 \
+             dnat ip to 10.0.0.2:53 comment \"lithos:sb/dns.0\"");
+        let fwd = PortForward {
+            protocol: Protocol::Tcp,
+            host: "2001:db8::1".parse().unwrap(),
+            host_port: 80,
+            port: 8080,
+        };
+        assert_eq!(rule("sb/web.1", &fwd, "fd00::2".parse().unwrap()),
+            "ip6 daddr 2001:db8::1 fib daddr type local tcp dport 80 \
+             dnat ip6 to [fd00::2]:8080 comment \"lithos:sb/web.1\"");
+        assert_eq!(parse_key("53/udp").unwrap(), (53, Protocol::Udp));
+        assert_eq!(parse_key("80").unwrap(), (80, Protocol::Tcp));
+        assert!(parse_key("80/sctp").is_err());
+    }
+
+    #[test]
+    fn conflicting() {
+        let fwd = |host: &str, port| PortForward {
+            protocol: Protocol::Tcp,
+            host: host.parse().unwrap(),
+            host_port: port,
+            port: 0,
+        };
+        let listed = parse_rule("meta nfproto ipv4 fib daddr type local \
+            tcp dport 80 dnat ip to 10.0.0.2:8080").unwrap();
+        assert_eq!(listed, fwd("0.0.0.0", 80));
+        assert!(conflicts(&listed, &fwd("192.0.2.1", 80)));
+        assert!(!conflicts(&listed, &fwd("192.0.2.1", 81)));
+        assert!(!conflicts(&listed, &fwd("::", 80)));
+        let listed = parse_rule("ip6 daddr 2001:db8::1 fib daddr type local \
+            tcp dport 80 dnat ip6 to [fd00::2]:8080").unwrap();
+        assert_eq!(listed, fwd("2001:db8::1", 80));
+        assert!(conflicts(&listed, &fwd("::", 80)));
+        assert!(!conflicts(&listed, &fwd("2001:db8::2", 80)));
+        assert!(parse_rule("iifname \"li_0a1b2c_0002\" \
+            jump li_0a1b2c_0002").is_none());
+    }
+}
@@ -0,0 +1,522 @@
+use std::io;
+use std::mem;
+use std::fs::{File, OpenOptions, remove_file, read_link, read_dir};
+use std::io::{Read, Write, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+
+use libc::{c_int, c_ulong, ioctl, syscall, statvfs, SYS_quotactl};
+use nix::fcntl::{flock, FlockArg};
+use serde_json;
+
+use mount::MountRecord;
+use utils::{cpath, relative};
+
+
+/// Name of the file in the state directory that lists size-limited volumes
+pub const STATE_FILE: &'static str = "quotas.json";
+
+/// Project ids assigned to directories, in the format of `xfs_quota`
+pub const PROJECTS_FILE: &'static str = "/etc/projects";
+
+/// Images smaller than that have too little space left after formatting
+pub const MIN_IMAGE_SIZE: usize = 16 << 20;
+
+const FS_IOC_FSGETXATTR: c_ulong = 0x801c581f;
+const FS_IOC_FSSETXATTR: c_ulong = 0x401c5820;
+const FS_XFLAG_PROJINHERIT: u32 = 0x00000200;
+
+const Q_GETQUOTA: c_int = 0x800007;
+const Q_SETQUOTA: c_int = 0x800008;
+const PRJQUOTA: c_int = 2;
+const QIF_BLIMITS: u32 = 1;
+const QUOTA_BLOCK_SIZE: u64 = 1024;
+
+const LOOP_SET_FD: c_ulong = 0x4C00;
+const LOOP_SET_STATUS64: c_ulong = 0x4C04;
+const LOOP_CTL_GET_FREE: c_ulong = 0x4C82;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum QuotaMethod {
+    Project,
+    Loop,
+}
+
+/// Size-limited volume of the running container
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct VolumeQuota {
+    pub mount_point: String,
+    pub method: QuotaMethod,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub used: u64,
+    pub size: u64,
+}
+
+#[repr(C)]
+struct FsXAttr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+#[repr(C)]
+struct DqBlk {
+    bhardlimit: u64,
+    bsoftlimit: u64,
+    curspace: u64,
+    ihardlimit: u64,
+    isoftlimit: u64,
+    curinodes: u64,
+    btime: u64,
+    itime: u64,
+    valid: u32,
+}
+
+#[repr(C)]
+struct LoopInfo64 {
+    device: u64,
+    inode: u64,
+    rdevice: u64,
+    offset: u64,
+    sizelimit: u64,
+    number: u32,
+    encrypt_type: u32,
+    encrypt_key_size: u32,
+    flags: u32,
+    file_name: [u8; 64],
+    crypt_name: [u8; 64],
+    encrypt_key: [u8; 32],
+    init: [u64; 2],
+}
+
+fn qcmd(cmd: c_int, kind: c_int) -> c_int {
+    (cmd << 8) | (kind & 0x00ff)
+}
+
+/// Project id initially tried for the directory
+///
+/// It's derived from the host path, so usually the directory gets the same
+/// id regardless of which container uses it. Ids which are actually used
+/// are recorded in `PROJECTS_FILE`, see `assign_project_id`.
+pub fn project_id(path: &Path) -> u32 {
+    // FNV-1a
+    let mut hash: u32 = 0x811c9dc5;
+    for &b in path.as_os_str().as_bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    // project 0 is the default one for all files, so never use it
+    (hash & 0x7fffffff).max(1)
+}
+
+/// Parses the `id:path` lines of the `PROJECTS_FILE`
+pub fn parse_projects(data: &str) -> Vec<(u32, PathBuf)> {
+    data.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with("#"))
+        .filter_map(|line| {
+            let mut pair = line.splitn(2, ':');
+            match (pair.next().and_then(|x| x.parse().ok()), pair.next()) {
+                (Some(id), Some(path)) => Some((id, PathBuf::from(path))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Chooses project id for the `dir`
+///
+/// `current` is the project id the directory has now (zero if none).
+/// Returns the id and whether it needs to be added to the `projects`.
+/// Fails if the directory is marked with the id of another directory,
+/// because the quota would be shared between them.
+pub fn assign_project_id(projects: &[(u32, PathBuf)], dir: &Path,
+    current: u32)
+    -> Result<(u32, bool), String>
+{
+    if let Some(&(id, _)) = projects.iter().find(|&&(_, ref p)| p == dir) {
+        if current != 0 && current != id {
+            return Err(format!("{:?} has project id {}, but {} is \
+                recorded in {}", dir, current, id, PROJECTS_FILE));
+        }
+        return Ok((id, false));
+    }
+    let owner = |id| projects.iter().find(|&&(x, _)| x == id)
+        .map(|&(_, ref p)| p);
+    if current != 0 {
+        // directory was marked before ids were recorded, keep the id
+        return match owner(current) {
+            Some(other) => Err(format!("project id {} of {:?} belongs \
+                to {:?}", current, dir, other)),
+            None => Ok((current, true)),
+        };
+    }
+    let mut id = project_id(dir);
+    while owner(id).is_some() {
+        id = (id % 0x7fffffff) + 1;
+    }
+    Ok((id, true))
+}
+
+fn get_xattr(file: &File, dir: &Path) -> Result<FsXAttr, String> {
+    let mut attr: FsXAttr = unsafe { mem::zeroed() };
+    let rc = unsafe {
+        ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR as _, &mut attr)
+    };
+    if rc != 0 {
+        return Err(format!("can't get attributes of {:?}: {}",
+            dir, io::Error::last_os_error()));
+    }
+    Ok(attr)
+}
+
+/// Finds (or allocates and records) project id of the directory
+fn lock_project_id(dir: &Path, current: u32) -> Result<u32, String> {
+    let mut file = OpenOptions::new().read(true).append(true).create(true)
+        .open(PROJECTS_FILE)
+        .map_err(|e| format!("can't open {}: {}", PROJECTS_FILE, e))?;
+    // knots of different containers may allocate ids concurrently
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)
+        .map_err(|e| format!("can't lock {}: {}", PROJECTS_FILE, e))?;
+    let mut data = String::new();
+    file.read_to_string(&mut data)
+        .map_err(|e| format!("can't read {}: {}", PROJECTS_FILE, e))?;
+    let (id, new) = assign_project_id(&parse_projects(&data), dir, current)?;
+    if new {
+        let mut line = format!("{}:{}\n", id, dir.display());
+        if !data.is_empty() && !data.ends_with("\n") {
+            line.insert(0, '\n');
+        }
+        file.write_all(line.as_bytes())
+            .map_err(|e| format!("can't write {}: {}", PROJECTS_FILE, e))?;
+    }
+    Ok(id)
+}
+
+/// Finds the block device of the filesystem containing `path`
+fn find_device(path: &Path) -> Result<PathBuf, String> {
+    let path = path.canonicalize()
+        .map_err(|e| format!("can't resolve {:?}: {}", path, e))?;
+    let file = File::open("/proc/self/mountinfo")
+        .map_err(|e| format!("can't read mountinfo: {}", e))?;
+    let mut best: Option<(usize, PathBuf)> = None;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("can't read mountinfo: {}", e))?;
+        let rec = match MountRecord::from_str(&line) {
+            Ok(rec) => rec,
+            Err(()) => continue,
+        };
+        let mp = Path::new(rec.mount_point);
+        let len = rec.mount_point.len();
+        if path.starts_with(mp) && best.as_ref().map_or(true, |x| len >= x.0) {
+            best = Some((len, PathBuf::from(rec.mount_source)));
+        }
+    }
+    match best {
+        Some((_, ref dev)) if dev.is_absolute() => Ok(dev.clone()),
+        Some((_, dev)) => Err(format!("filesystem of {:?} has no block \
+            device ({:?}), project quotas are not supported", path, dev)),
+        None => Err(format!("can't find mount point of {:?}", path)),
+    }
+}
+
+fn quotactl(cmd: c_int, device: &Path, id: u32, blk: &mut DqBlk)
+    -> Result<(), io::Error>
+{
+    let dev = cpath(device);
+    let rc = unsafe {
+        syscall(SYS_quotactl, qcmd(cmd, PRJQUOTA), dev.as_ptr(), id,
+                blk as *mut DqBlk)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Marks directory with a project id and sets hard limit for the project
+///
+/// Only files created after the project id is set are accounted (new files
+/// inherit the id), so it's better to enable quota on a fresh directory.
+/// Filesystem must be mounted with `prjquota` option.
+pub fn set_project_quota(dir: &Path, size: u64) -> Result<u32, String> {
+    let file = File::open(dir)
+        .map_err(|e| format!("can't open {:?}: {}", dir, e))?;
+    let mut attr = get_xattr(&file, dir)?;
+    let id = lock_project_id(dir, attr.projid)?;
+    if attr.projid != id || attr.xflags & FS_XFLAG_PROJINHERIT == 0 {
+        attr.projid = id;
+        attr.xflags |= FS_XFLAG_PROJINHERIT;
+        let rc = unsafe {
+            ioctl(file.as_raw_fd(), FS_IOC_FSSETXATTR as _, &attr)
+        };
+        if rc != 0 {
+            return Err(format!("can't set project id of {:?}: {}",
+                dir, io::Error::last_os_error()));
+        }
+    }
+    let device = find_device(dir)?;
+    let blocks = (size + QUOTA_BLOCK_SIZE - 1) / QUOTA_BLOCK_SIZE;
+    let mut blk: DqBlk = unsafe { mem::zeroed() };
+    blk.bhardlimit = blocks;
+    blk.bsoftlimit = blocks;
+    blk.valid = QIF_BLIMITS;
+    quotactl(Q_SETQUOTA, &device, id, &mut blk)
+        .map_err(|e| format!("can't set quota for project {} on {:?}: {} \
+            (is filesystem mounted with prjquota?)", id, device, e))?;
+    Ok(id)
+}
+
+/// Returns usage of the project quota of the directory
+pub fn project_usage(dir: &Path) -> Result<Usage, String> {
+    let file = File::open(dir)
+        .map_err(|e| format!("can't open {:?}: {}", dir, e))?;
+    let id = get_xattr(&file, dir)?.projid;
+    if id == 0 {
+        return Err(format!("{:?} has no project quota", dir));
+    }
+    let device = find_device(dir)?;
+    let mut blk: DqBlk = unsafe { mem::zeroed() };
+    quotactl(Q_GETQUOTA, &device, id, &mut blk)
+        .map_err(|e| format!("can't get quota of {:?}: {}", dir, e))?;
+    Ok(Usage {
+        used: blk.curspace,
+        size: blk.bhardlimit * QUOTA_BLOCK_SIZE,
+    })
+}
+
+/// Creates and formats (ext4) an image file unless it already exists
+///
+/// Returns `true` if image has just been created. Size of existing images
+/// is never changed.
+pub fn prepare_image(path: &Path, size: u64) -> Result<bool, String> {
+    let file = match OpenOptions::new().write(true).create_new(true)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return Ok(false);
+        }
+        Err(e) => return Err(format!("can't create {:?}: {}", path, e)),
+    };
+    let result = file.set_len(size)
+        .map_err(|e| format!("can't resize {:?}: {}", path, e))
+        .and_then(|()| {
+            Command::new("mkfs.ext4")
+                .args(&["-q", "-F", "-m", "0"]).arg(path)
+                .status()
+                .map_err(|e| format!("can't run mkfs.ext4: {}", e))
+        })
+        .and_then(|status| if status.success() {
+            Ok(())
+        } else {
+            Err(format!("mkfs.ext4 {:?} failed: {}", path, status))
+        });
+    if let Err(e) = result {
+        // don't leave half-created image, so we retry next time
+        remove_file(path).ok();
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Finds a loop device which has `image` as a backing file
+pub fn find_loop(image: &Path) -> Result<Option<PathBuf>, String> {
+    let image = image.canonicalize()
+        .map_err(|e| format!("can't resolve {:?}: {}", image, e))?;
+    let dir = match read_dir("/sys/block") {
+        Ok(dir) => dir,
+        Err(e) => return Err(format!("can't list /sys/block: {}", e)),
+    };
+    for entry in dir {
+        let entry = entry
+            .map_err(|e| format!("can't list /sys/block: {}", e))?;
+        let name = entry.file_name();
+        if !name.as_bytes().starts_with(b"loop") {
+            continue;
+        }
+        // file only exists when device is attached
+        let mut backing = String::new();
+        match File::open(entry.path().join("loop/backing_file")) {
+            Ok(mut f) => {
+                if f.read_to_string(&mut backing).is_err() {
+                    continue;
+                }
+            }
+            Err(_) => continue,
+        }
+        if Path::new(backing.trim()) == image {
+            return Ok(Some(Path::new("/dev").join(name)));
+        }
+    }
+    Ok(None)
+}
+
+/// Attaches image to a free loop device
+///
+/// Image is locked (`flock`) so it can't be attached twice, and attaching
+/// fails if some loop device is already backed by the image (e.g. the
+/// filesystem of the previous run is still mounted). The returned file
+/// holds the lock and must be kept open while the image is mounted.
+///
+/// Device is detached automatically when the filesystem is unmounted
+pub fn attach_loop(image: &Path) -> Result<(PathBuf, File), String> {
+    let control = OpenOptions::new().read(true).write(true)
+        .open("/dev/loop-control")
+        .map_err(|e| format!("can't open /dev/loop-control: {}", e))?;
+    let file = OpenOptions::new().read(true).write(true).open(image)
+        .map_err(|e| format!("can't open {:?}: {}", image, e))?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock)
+        .map_err(|e| format!("can't lock {:?}, probably it's used by \
+            another container: {}", image, e))?;
+    if let Some(device) = find_loop(image)? {
+        return Err(format!("{:?} is already attached to {:?}",
+            image, device));
+    }
+    // somebody may grab device between GET_FREE and SET_FD, so retry
+    for _ in 0..5 {
+        let num = unsafe {
+            ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE as _)
+        };
+        if num < 0 {
+            return Err(format!("can't find free loop device: {}",
+                io::Error::last_os_error()));
+        }
+        let device = PathBuf::from(format!("/dev/loop{}", num));
+        let dev = OpenOptions::new().read(true).write(true).open(&device)
+            .map_err(|e| format!("can't open {:?}: {}", device, e))?;
+        let rc = unsafe {
+            ioctl(dev.as_raw_fd(), LOOP_SET_FD as _, file.as_raw_fd())
+        };
+        if rc != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(::libc::EBUSY) {
+                continue;
+            }
+            return Err(format!("can't attach {:?} to {:?}: {}",
+                image, device, err));
+        }
+        let mut info: LoopInfo64 = unsafe { mem::zeroed() };
+        info.flags = LO_FLAGS_AUTOCLEAR;
+        let name = image.as_os_str().as_bytes();
+        let len = name.len().min(info.file_name.len() - 1);
+        info.file_name[..len].copy_from_slice(&name[..len]);
+        let rc = unsafe {
+            ioctl(dev.as_raw_fd(), LOOP_SET_STATUS64 as _, &info)
+        };
+        if rc != 0 {
+            return Err(format!("can't set status of {:?}: {}",
+                device, io::Error::last_os_error()));
+        }
+        return Ok((device, file));
+    }
+    Err(format!("can't find free loop device: all are busy"))
+}
+
+/// Returns usage of the filesystem at path
+///
+/// For directories with project quota (on xfs and ext4) kernel reports
+/// the quota instead of the size of the whole filesystem.
+pub fn usage(path: &Path) -> Result<Usage, io::Error> {
+    let c_path = cpath(path);
+    let mut stat: statvfs = unsafe { mem::zeroed() };
+    let rc = unsafe { statvfs(c_path.as_ptr(), &mut stat) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let frsize = stat.f_frsize as u64;
+    Ok(Usage {
+        used: (stat.f_blocks as u64 - stat.f_bfree as u64) * frsize,
+        size: stat.f_blocks as u64 * frsize,
+    })
+}
+
+/// Returns usage of the volume mounted in the container of the knot process
+///
+/// Knot changes its root to the container's root, so the mount point is
+/// resolved relative to `/proc/<pid>/root`.
+pub fn volume_usage(knot_pid: i32, mount_point: &str)
+    -> Result<Usage, io::Error>
+{
+    let root = PathBuf::from(format!("/proc/{}/root", knot_pid));
+    if read_link(&root)? == Path::new("/") {
+        // otherwise we would report usage of the host filesystem
+        return Err(io::Error::new(io::ErrorKind::Other,
+            "container is not started yet"));
+    }
+    usage(&root.join(relative(Path::new(mount_point), Path::new("/"))))
+}
+
+pub fn write_state(state_dir: &Path, quotas: &[VolumeQuota])
+    -> Result<(), io::Error>
+{
+    let path = state_dir.join(STATE_FILE);
+    if quotas.is_empty() {
+        return match remove_file(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        };
+    }
+    let data = serde_json::to_vec(quotas)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    File::create(&path)?.write_all(&data)
+}
+
+pub fn read_state(state_dir: &Path) -> Result<Vec<VolumeQuota>, io::Error> {
+    let mut buf = Vec::new();
+    match File::open(state_dir.join(STATE_FILE)) {
+        Ok(mut f) => f.read_to_end(&mut buf)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use super::{project_id, parse_projects, assign_project_id};
+
+    #[test]
+    fn stable_project_id() {
+        let id = project_id(Path::new("/var/lib/db"));
+        assert_eq!(id, project_id(Path::new("/var/lib/db")));
+        assert!(id != project_id(Path::new("/var/lib/db2")));
+        assert!(id > 0 && id < 0x80000000);
+    }
+
+    #[test]
+    fn assign_ids() {
+        let projects = parse_projects("# comment\n10:/var/lib/a\n11:/b\n");
+        assert_eq!(projects, vec![
+            (10, PathBuf::from("/var/lib/a")),
+            (11, PathBuf::from("/b")),
+        ]);
+        let a = Path::new("/var/lib/a");
+        assert_eq!(assign_project_id(&projects, a, 0), Ok((10, false)));
+        assert_eq!(assign_project_id(&projects, a, 10), Ok((10, false)));
+        assert!(assign_project_id(&projects, a, 11).is_err());
+        let c = Path::new("/var/lib/c");
+        assert_eq!(assign_project_id(&projects, c, 12), Ok((12, true)));
+        assert!(assign_project_id(&projects, c, 11).is_err());
+        let id = project_id(c);
+        let taken = vec![(id, PathBuf::from("/other"))];
+        assert_eq!(assign_project_id(&taken, c, 0),
+            Ok((id % 0x7fffffff + 1, true)));
+    }
+}
@@ -0,0 +1,345 @@
+//! Generating `resolv.conf` for containers
+//!
+//! Nameservers, search domains and options are combined from the sandbox
+//! `dns` setting, container's `resolv-conf` and (optionally) the host's
+//! `resolv.conf`.
+use std::net::IpAddr;
+
+use quire::validate::{Structure, Sequence, Scalar};
+
+use container_config::{ResolvConf, replace_vars};
+use sandbox_config::SandboxConfig;
+use utils::instance_number;
+
+
+/// Variables that can be used in nameservers and search domains
+pub const VARIABLES: &'static [&'static str] = &[
+    "lithos:sandbox",
+    "lithos:child",
+    "lithos:instance",
+    "lithos:default_gateway",
+    "lithos:ipv6_default_gateway",
+];
+
+/// DNS settings of the sandbox
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Dns {
+    pub nameservers: Vec<String>,
+    pub search: Vec<String>,
+    pub options: Vec<String>,
+}
+
+/// Values of the variables for the specific container
+pub struct Vars<'a> {
+    /// Name of the process in the form of `sandbox/child.N`
+    pub name: &'a str,
+    /// Gateways of the bridged network (if container is in one)
+    pub default_gateway: Option<IpAddr>,
+    pub ipv6_default_gateway: Option<IpAddr>,
+    pub bridged: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Settings {
+    nameservers: Vec<String>,
+    search: Vec<String>,
+    options: Vec<String>,
+}
+
+impl Dns {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("nameservers", Sequence::new(Scalar::new()))
+        .member("search", Sequence::new(Scalar::new()))
+        .member("options", Sequence::new(Scalar::new()))
+    }
+}
+
+impl<'a> Vars<'a> {
+    pub fn new(name: &'a str, sandbox: &SandboxConfig) -> Vars<'a> {
+        let bridged = sandbox.bridged_network.as_ref();
+        Vars {
+            name: name,
+            default_gateway: bridged.and_then(|b| b.default_gateway),
+            ipv6_default_gateway: bridged.and_then(|b| b.ipv6_default_gateway),
+            bridged: bridged.is_some(),
+        }
+    }
+    fn get(&self, var: &str) -> Result<String, String> {
+        let mut pair = self.name.splitn(2, '/');
+        let sandbox = pair.next().unwrap_or("");
+        let child = pair.next().and_then(|x| x.rsplitn(2, '.').nth(1))
+            .unwrap_or("");
+        let gw = |gw: Option<IpAddr>| gw.map(|x| x.to_string())
+            .ok_or_else(|| format!("variable {:?} requires gateway \
+                in bridged-network", var));
+        match var {
+            "lithos:sandbox" => Ok(sandbox.to_string()),
+            "lithos:child" => Ok(child.to_string()),
+            "lithos:instance" => instance_number(self.name)
+                .map(|x| x.to_string())
+                .ok_or_else(|| format!("no instance number in {:?}",
+                    self.name)),
+            "lithos:default_gateway" => gw(self.default_gateway),
+            "lithos:ipv6_default_gateway" => gw(self.ipv6_default_gateway),
+            _ => Err(format!("unknown variable {:?}", var)),
+        }
+    }
+    fn substitute(&self, value: &str) -> Result<String, String> {
+        let mut error = None;
+        let result = replace_vars(value, |var| {
+            self.get(var).unwrap_or_else(|e| {
+                error = Some(e);
+                String::new()
+            })
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(result),
+        }
+    }
+}
+
+/// Returns true if `resolv.conf` must be generated rather than copied
+pub fn is_configured(dns: &Dns, local: &ResolvConf) -> bool {
+    dns.nameservers.len() > 0 || dns.search.len() > 0 ||
+        dns.options.len() > 0 ||
+        local.nameservers.len() > 0 || local.search.len() > 0 ||
+        local.options.len() > 0
+}
+
+/// Checks that the value is a single word that can't break the file
+fn check_word(kind: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() ||
+        value.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(format!("bad {} {:?}: must be a single word \
+            without control characters", kind, value));
+    }
+    Ok(())
+}
+
+/// Checks that the search domain is a valid host name
+fn check_domain(value: &str) -> Result<(), String> {
+    let name = if value.ends_with(".") {
+        &value[..value.len()-1]
+    } else {
+        value
+    };
+    let valid = name.len() > 0 && name.len() <= 253 &&
+        name.split('.').all(|label| {
+            label.len() > 0 && label.len() <= 63 &&
+            !label.starts_with("-") && !label.ends_with("-") &&
+            label.chars().all(|c| {
+                c.is_ascii_alphanumeric() || c == '-' || c == '_'
+            })
+        });
+    if !valid {
+        return Err(format!("bad search domain {:?}", value));
+    }
+    Ok(())
+}
+
+fn parse(data: &str) -> Settings {
+    let mut result = Settings::default();
+    for line in data.lines() {
+        let mut words = line.split_whitespace();
+        let target = match words.next() {
+            Some("nameserver") => &mut result.nameservers,
+            Some("search") | Some("domain") => &mut result.search,
+            Some("options") => &mut result.options,
+            _ => continue,
+        };
+        target.extend(words.map(String::from));
+    }
+    result
+}
+
+/// Renders `resolv.conf` for the container
+///
+/// Nameservers of the container override ones of the sandbox, which in
+/// turn override ones of the `host` file. Search domains and options are
+/// concatenated. Nameservers of the host which are on loopback are skipped
+/// for bridged containers as they are unreachable from there.
+pub fn render(host: Option<&str>, dns: &Dns, local: &ResolvConf,
+    vars: &Vars)
+    -> Result<String, String>
+{
+    let host = host.map(parse).unwrap_or_else(Settings::default);
+    let mut nameservers = Vec::new();
+    if local.nameservers.len() > 0 || dns.nameservers.len() > 0 {
+        let items = if local.nameservers.len() > 0 {
+            &local.nameservers
+        } else {
+            &dns.nameservers
+        };
+        for item in items {
+            let value = vars.substitute(item)?;
+            let ip: IpAddr = value.parse()
+                .map_err(|e| format!("bad nameserver {:?}: {}", value, e))?;
+            nameservers.push(ip);
+        }
+    } else {
+        for item in &host.nameservers {
+            match item.parse::<IpAddr>() {
+                Ok(ip) if vars.bridged && ip.is_loopback() => {
+                    warn!("Nameserver {} is unreachable from bridged \
+                        network, skipping", ip);
+                }
+                Ok(ip) => nameservers.push(ip),
+                Err(_) => warn!("Skipping bad nameserver {:?}", item),
+            }
+        }
+    }
+    let mut search = Vec::new();
+    for item in local.search.iter().chain(&dns.search) {
+        let value = vars.substitute(item)?;
+        check_domain(&value)?;
+        search.push(value);
+    }
+    if search.is_empty() {
+        search = host.search;
+    }
+    for item in dns.options.iter().chain(&local.options) {
+        check_word("option", item)?;
+    }
+    let options = host.options.iter()
+        .chain(&dns.options)
+        .chain(&local.options)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut result = String::from("# Generated by lithos\n");
+    for ip in nameservers {
+        result.push_str(&format!("nameserver {}\n", ip));
+    }
+    if search.len() > 0 {
+        result.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    if options.len() > 0 {
+        result.push_str(&format!("options {}\n", options.join(" ")));
+    }
+    Ok(result)
+}
+
+/// Checks variables, nameservers, search domains and options
+/// (for `lithos_check`)
+pub fn check(dns: &Dns, local: &ResolvConf, vars: &Vars)
+    -> Result<(), String>
+{
+    let nameservers = dns.nameservers.iter().chain(&local.nameservers);
+    for item in nameservers.chain(&dns.search).chain(&local.search) {
+        let mut error = None;
+        replace_vars(item, |var| {
+            if !VARIABLES.contains(&var) {
+                error = Some(format!("unknown variable {:?} in {:?}",
+                    var, item));
+            }
+            ""
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+    // sandbox nameservers may be overridden by container's ones in `render`
+    for item in &dns.nameservers {
+        let value = vars.substitute(item)?;
+        value.parse::<IpAddr>()
+            .map_err(|e| format!("bad nameserver {:?}: {}", value, e))?;
+    }
+    render(None, dns, local, vars).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use container_config::ResolvConf;
+    use super::{Dns, Vars, render, check};
+
+    fn vars() -> Vars<'static> {
+        Vars {
+            name: "sb/web.1",
+            default_gateway: Some("10.0.0.1".parse().unwrap()),
+            ipv6_default_gateway: None,
+            bridged: true,
+        }
+    }
+
+    #[test]
+    fn render_merged() {
+        let host = "# comment\nnameserver 127.0.0.53\nnameserver 8.8.8.8\n\
+                    search example.com\noptions edns0\n";
+        let dns = Dns {
+            nameservers: vec![],
+            search: vec!["@{lithos:sandbox}.svc".into()],
+            options: vec!["ndots:2".into()],
+        };
+        let local = ResolvConf {
+            mount: None,
+            copy_from_host: true,
+            nameservers: vec![],
+            search: vec!["@{lithos:child}.@{lithos:sandbox}.svc".into()],
+            options: vec!["timeout:1".into()],
+        };
+        assert_eq!(render(Some(host), &dns, &local, &vars()).unwrap(),
+            "# Generated by lithos\n\
+             nameserver 8.8.8.8\n\
+             search web.sb.svc sb.svc\n\
+             options edns0 ndots:2 timeout:1\n");
+    }
+
+    #[test]
+    fn render_gateway() {
+        let dns = Dns {
+            nameservers: vec!["@{lithos:default_gateway}".into()],
+            search: vec![],
+            options: vec![],
+        };
+        let mut local = ResolvConf {
+            mount: None,
+            copy_from_host: false,
+            nameservers: vec![],
+            search: vec![],
+            options: vec![],
+        };
+        assert_eq!(render(None, &dns, &local, &vars()).unwrap(),
+            "# Generated by lithos\nnameserver 10.0.0.1\n");
+        local.nameservers = vec!["@{lithos:ipv6_default_gateway}".into()];
+        assert!(render(None, &dns, &local, &vars()).is_err());
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.nameservers = vec!["@{lithos:default_gateway}".into()];
+        assert!(check(&dns, &local, &vars()).is_ok());
+        local.nameservers = vec!["@{lithos:pid}".into()];
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.nameservers = vec!["dns.local".into()];
+        assert!(check(&dns, &local, &vars()).is_err());
+    }
+
+    #[test]
+    fn no_injection() {
+        let dns = Dns::default();
+        let mut local = ResolvConf {
+            mount: None,
+            copy_from_host: false,
+            nameservers: vec![],
+            search: vec!["@{lithos:child}.svc".into()],
+            options: vec![],
+        };
+        assert!(check(&dns, &local, &vars()).is_ok());
+        local.search = vec!["svc\nnameserver 1.2.3.4".into()];
+        assert!(render(None, &dns, &local, &vars()).is_err());
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.search = vec!["a b".into()];
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.search = vec!["@{lithos:name}".into()];
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.search = vec!["-bad.svc".into()];
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.search = vec![];
+        local.options = vec!["ndots:2\nnameserver 1.2.3.4".into()];
+        assert!(render(None, &dns, &local, &vars()).is_err());
+        local.options = vec!["ndots:2 rotate".into()];
+        assert!(check(&dns, &local, &vars()).is_err());
+        local.options = vec!["ndots:2".into()];
+        assert!(check(&dns, &local, &vars()).is_ok());
+    }
+}
@@ -3,10 +3,18 @@ use std::net::IpAddr;
 use std::path::{PathBuf, Path, Component};
 
 use id_map::{IdMap, mapping_validator};
+use ipam::Pool;
+use bandwidth::Bandwidth;
+use network_policy::NetworkPolicy;
+use resolv_conf::Dns;
 use ipnetwork::IpNetwork;
+use serde::{Deserialize, Deserializer, de};
 use quire::validate::{Sequence, Mapping, Scalar, Numeric};
 use quire::validate::{Structure};
 use range::Range;
+use limits::Resource;
+use cpuset::CpuList;
+use container_config::MountOptions;
 
 
 #[derive(Deserialize, Clone)]
@@ -15,9 +23,24 @@ pub struct BridgedNetwork {
     #[serde(with="::serde_str")]
     pub network: IpNetwork,
     pub default_gateway: Option<IpAddr>,
+    #[serde(default, deserialize_with="deserialize_opt_network")]
+    pub ipv6_network: Option<IpNetwork>,
+    pub ipv6_default_gateway: Option<IpAddr>,
+    pub ip_pool: Option<Pool>,
+    pub bandwidth: Bandwidth,
     pub after_setup_command: Vec<String>,
 }
 
+fn deserialize_opt_network<'de, D>(d: D)
+    -> Result<Option<IpNetwork>, D::Error>
+    where D: Deserializer<'de>
+{
+    match Option::<String>::deserialize(d)? {
+        Some(s) => s.parse().map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct SandboxConfig {
     pub config_file: Option<PathBuf>,
@@ -33,15 +56,31 @@ pub struct SandboxConfig {
     pub allow_groups: Vec<Range>,
     pub default_group: Option<u32>,
     pub allow_tcp_ports: Vec<Range>,
+    pub allow_socket_dirs: Vec<PathBuf>,
     pub additional_hosts: BTreeMap<String, String>,
     pub uid_map: Vec<IdMap>,
     pub gid_map: Vec<IdMap>,
     pub auto_clean: bool,
     pub resolv_conf: PathBuf,
+    pub dns: Dns,
     pub hosts_file: PathBuf,
     pub bridged_network: Option<BridgedNetwork>,
+    pub network_policy: Option<NetworkPolicy>,
     pub secrets_private_key: Option<PathBuf>,
     pub secrets_namespaces: Vec<String>,
+    pub max_rlimits: BTreeMap<Resource, u64>,
+    pub core_dump_dir: Option<PathBuf>,
+    pub collect_core_dumps: bool,
+    pub max_core_dumps: usize,
+    pub cpu_pool: Option<CpuList>,
+    pub memory_nodes: Option<CpuList>,
+    pub min_nice: i32,
+    pub allow_realtime_ioprio: bool,
+    pub force_mount_options: MountOptions,
+    pub allow_devices: Vec<PathBuf>,
+    pub mask_paths: Vec<PathBuf>,
+    pub unmask_paths: Vec<PathBuf>,
+    pub image_public_keys: Vec<PathBuf>,
 }
 
 impl SandboxConfig {
@@ -55,6 +94,24 @@ impl SandboxConfig {
         }
         return num == self.image_dir_levels;
     }
+    /// Checks that unix socket may be created at `path` on the host
+    pub fn check_socket_path(&self, path: &Path) -> Result<(), String> {
+        if !path.is_absolute() {
+            return Err(format!("socket path {:?} must be absolute", path));
+        }
+        if path.components().any(|c| match c {
+            Component::Normal(_) | Component::RootDir => false,
+            _ => true,
+        }) {
+            return Err(format!("socket path {:?} must be normalized", path));
+        }
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        if !self.allow_socket_dirs.iter().any(|d| dir.starts_with(d)) {
+            return Err(format!("socket {:?} is not in allow-socket-dirs \
+                of the sandbox", path));
+        }
+        Ok(())
+    }
     pub fn validator<'x>() -> Structure<'x> {
         Structure::new()
         .member("config_file", Scalar::new().optional())
@@ -76,6 +133,7 @@ impl SandboxConfig {
         .member("allow_groups", Sequence::new(Scalar::new()))
         .member("default_group", Scalar::new().default(0))
         .member("allow_tcp_ports", Sequence::new(Scalar::new()))
+        .member("allow_socket_dirs", Sequence::new(Scalar::new()))
         .member("uid_map", mapping_validator())
         .member("gid_map", mapping_validator())
         .member("additional_hosts", Mapping::new(
@@ -84,13 +142,34 @@ impl SandboxConfig {
         .member("auto_clean", Scalar::new().default("true").optional())
         .member("hosts_file", Scalar::new().default("/etc/hosts"))
         .member("resolv_conf", Scalar::new().default("/etc/resolv.conf"))
+        .member("dns", Dns::validator())
         .member("bridged_network", Structure::new()
             .member("bridge", Scalar::new())
             .member("network", Scalar::new())
             .member("default_gateway", Scalar::new().optional())
+            .member("ipv6_network", Scalar::new().optional())
+            .member("ipv6_default_gateway", Scalar::new().optional())
+            .member("ip_pool", Scalar::new().optional())
+            .member("bandwidth", Bandwidth::validator())
             .member("after_setup_command", Sequence::new(Scalar::new()))
             .optional())
+        .member("network_policy", NetworkPolicy::validator().optional())
         .member("secrets_private_key", Scalar::new().optional())
         .member("secrets_namespaces", Sequence::new(Scalar::new()))
+        .member("max_rlimits", Mapping::new(
+            Scalar::new(),
+            Numeric::new().min(0)))
+        .member("core_dump_dir", Scalar::new().optional())
+        .member("collect_core_dumps", Scalar::new().default(false))
+        .member("max_core_dumps", Numeric::new().min(1).default(10))
+        .member("cpu_pool", Scalar::new().optional())
+        .member("memory_nodes", Scalar::new().optional())
+        .member("min_nice", Numeric::new().min(-20).max(19).default(0))
+        .member("allow_realtime_ioprio", Scalar::new().default(false))
+        .member("force_mount_options", MountOptions::validator())
+        .member("allow_devices", Sequence::new(Scalar::new()))
+        .member("mask_paths", Sequence::new(Scalar::new()))
+        .member("unmask_paths", Sequence::new(Scalar::new()))
+        .member("image_public_keys", Sequence::new(Scalar::new()))
     }
 }
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::io::Error as IoError;
+
+use libc::{c_int, pid_t, sched_param, sched_setscheduler, sched_getscheduler};
+use libc::{setpriority, syscall, PRIO_PROCESS, __priority_which_t};
+use libc::{SYS_ioprio_set, SYS_ioprio_get};
+use libc::{SCHED_OTHER, SCHED_BATCH, SCHED_IDLE};
+use quire::validate::{Structure, Scalar, Numeric};
+
+use sandbox_config::SandboxConfig;
+
+
+const IOPRIO_WHO_PROCESS: c_int = 1;
+const IOPRIO_CLASS_SHIFT: c_int = 13;
+
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all="kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct IoPriority {
+    pub class: IoClass,
+    pub level: u8,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all="lowercase")]
+pub enum SchedPolicy {
+    Other,
+    Batch,
+    Idle,
+}
+
+impl IoPriority {
+    pub fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("class", Scalar::new().default("best-effort"))
+        .member("level", Numeric::new().min(0).max(7).default(4))
+    }
+    fn to_raw(&self) -> c_int {
+        let class = match self.class {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        };
+        (class << IOPRIO_CLASS_SHIFT) | self.level as c_int
+    }
+    fn from_raw(value: c_int) -> Option<IoPriority> {
+        let class = match value >> IOPRIO_CLASS_SHIFT {
+            1 => IoClass::Realtime,
+            2 => IoClass::BestEffort,
+            3 => IoClass::Idle,
+            _ => return None,  // none, i.e. derived from nice value
+        };
+        let level = (value & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8;
+        Some(IoPriority { class, level })
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.class {
+            IoClass::Realtime => write!(f, "realtime/{}", self.level),
+            IoClass::BestEffort => write!(f, "best-effort/{}", self.level),
+            IoClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+impl SchedPolicy {
+    fn to_raw(&self) -> c_int {
+        match *self {
+            SchedPolicy::Other => SCHED_OTHER,
+            SchedPolicy::Batch => SCHED_BATCH,
+            SchedPolicy::Idle => SCHED_IDLE,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SchedPolicy::Other => "other",
+            SchedPolicy::Batch => "batch",
+            SchedPolicy::Idle => "idle",
+        }
+    }
+    pub fn from_raw(value: c_int) -> Option<SchedPolicy> {
+        match value {
+            SCHED_OTHER => Some(SchedPolicy::Other),
+            SCHED_BATCH => Some(SchedPolicy::Batch),
+            SCHED_IDLE => Some(SchedPolicy::Idle),
+            _ => None,
+        }
+    }
+}
+
+/// Checks scheduling settings of the container against sandbox's policy
+pub fn check(nice: i32, ioprio: Option<&IoPriority>, sandbox: &SandboxConfig)
+    -> Result<(), String>
+{
+    if nice < -20 || nice > 19 {
+        return Err(format!("nice value {} is out of range -20..19", nice));
+    }
+    if nice < sandbox.min_nice {
+        return Err(format!("nice value {} is lower than allowed by sandbox \
+            (min-nice: {})", nice, sandbox.min_nice));
+    }
+    if let Some(prio) = ioprio {
+        if prio.class == IoClass::Realtime && !sandbox.allow_realtime_ioprio {
+            return Err(format!("realtime ioprio is not allowed by sandbox \
+                (see allow-realtime-ioprio)"));
+        }
+    }
+    Ok(())
+}
+
+pub fn set_nice(nice: i32) -> Result<(), IoError> {
+    let rc = unsafe {
+        setpriority(PRIO_PROCESS as __priority_which_t, 0, nice)
+    };
+    if rc != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn set_ioprio(prio: &IoPriority) -> Result<(), IoError> {
+    let rc = unsafe {
+        syscall(SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio.to_raw())
+    };
+    if rc != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn get_ioprio(pid: pid_t) -> Result<Option<IoPriority>, IoError> {
+    let rc = unsafe { syscall(SYS_ioprio_get, IOPRIO_WHO_PROCESS, pid) };
+    if rc < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(IoPriority::from_raw(rc as c_int))
+}
+
+pub fn set_sched_policy(policy: SchedPolicy) -> Result<(), IoError> {
+    let param = sched_param { sched_priority: 0 };
+    let rc = unsafe { sched_setscheduler(0, policy.to_raw(), &param) };
+    if rc != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn get_sched_policy(pid: pid_t) -> Result<Option<SchedPolicy>, IoError> {
+    let rc = unsafe { sched_getscheduler(pid) };
+    if rc < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(SchedPolicy::from_raw(rc))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IoPriority, IoClass};
+
+    #[test]
+    fn ioprio_raw() {
+        let prio = IoPriority { class: IoClass::Idle, level: 0 };
+        assert_eq!(prio.to_raw(), 3 << 13);
+        let prio = IoPriority { class: IoClass::BestEffort, level: 7 };
+        assert_eq!(IoPriority::from_raw(prio.to_raw()), Some(prio));
+        assert_eq!(IoPriority::from_raw(0), None);
+    }
+}
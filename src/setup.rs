@@ -11,6 +11,8 @@ use humantime::format_rfc3339_seconds;
 use super::master_config::MasterConfig;
 use super::utils::{clean_dir};
 use super::cgroup;
+use super::port_forward;
+use super::network_policy;
 
 
 
@@ -20,6 +22,16 @@ pub fn clean_child(name: &str, master: &MasterConfig, temporary: bool) {
     clean_dir(&st_dir, true)
         .map_err(|e| error!("Error removing state dir for {}: {}", name, e))
         .ok();
+    // Removed here rather than in lithos_knot, because knot is already
+    // chrooted into the container when the process exits
+    port_forward::remove(name)
+        .map_err(|e| error!("Error removing published ports of {}: {}",
+            name, e))
+        .ok();
+    network_policy::remove(name)
+        .map_err(|e| error!("Error removing network policy of {}: {}",
+            name, e))
+        .ok();
     if !temporary {
         // If shutdown is temporary (i.e. process failed and we are going to
         // restart it shortly), we don't remove cgroups. Because removing
@@ -3,6 +3,7 @@ use std::io;
 use std::fs::{create_dir, remove_dir_all, read_dir, remove_file, remove_dir};
 use std::fs::{metadata};
 use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
 use std::path::Component::Normal;
 use std::io::Error as IoError;
 use std::io::ErrorKind::{AlreadyExists, NotFound};
@@ -233,6 +234,31 @@ pub fn cpath<P:AsRef<Path>>(path: P) -> CString {
     CString::new(path.as_ref().to_str().unwrap()).unwrap()
 }
 
+/// Maps path in container to the host path using `readonly-paths` or
+/// `writable-paths` of the sandbox
+///
+/// Path may point to a file, in this case it may also be exactly equal to
+/// the key in the mapping.
+pub fn map_dir(dir: &Path, dirs: &BTreeMap<PathBuf, PathBuf>)
+    -> Option<PathBuf>
+{
+    assert!(dir.is_absolute());
+    for (prefix, real_dir) in dirs.iter() {
+        if dir == prefix {
+            return Some(real_dir.clone());
+        }
+        if dir.starts_with(prefix) {
+            return Some(real_dir.join(relative(dir, prefix)));
+        }
+    }
+    return None;
+}
+
+/// Returns instance number from the process name `sandbox/child.N`
+pub fn instance_number(lithos_name: &str) -> Option<usize> {
+    lithos_name.rsplitn(2, '.').next().and_then(|x| x.parse().ok())
+}
+
 pub fn relative(child: &Path, base: &Path) -> PathBuf {
     assert!(child.starts_with(base));
     let mut res = PathBuf::new();